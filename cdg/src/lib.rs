@@ -18,11 +18,11 @@
 //! "border color" rather than drawn from the framebuffer.
 
 
-use std::io::Read;
+use std::io::{self, Read, Write};
 use std::fmt;
 
 /// A 6x12 tile, to be blitted to the display
-#[derive(Debug)]
+#[derive(Debug,Clone,Eq,PartialEq)]
 pub struct Tile {
     /// X and Y coordinates, in tiles. 0,0 is the top left corner
     pub pos: (u8,u8), // x, then y
@@ -55,6 +55,17 @@ impl Tile {
         }
     }
 
+    /// Convert a tile back into the data section of a Tile command
+    fn to_subchannel(&self) -> [u8; 16] {
+        let mut data = [0; 16];
+        data[0] = self.color.0 & 0x0F | (self.channel << 2) & 0x30;
+        data[1] = self.color.1 & 0x0F | (self.channel << 4) & 0x30;
+        data[2] = self.pos.1 & 0x1F;
+        data[3] = self.pos.0 & 0x3F;
+        iter_copy(data[4..16].iter_mut(), self.content.iter().map(|x| x & 0x3F));
+        data
+    }
+
     /// Return the CLUT index of the pixel at x,y
     pub fn get_pixel(&self, x: u8, y: u8) -> u8 {
         assert!(x < 6);
@@ -88,6 +99,14 @@ impl ScrollCommand {
             _ => ScrollCommand::Noop, // Invalid or NOOP
         }
     }
+
+    fn to_u8(self) -> u8 {
+        match self {
+            ScrollCommand::Noop => 0x00,
+            ScrollCommand::SE => 0x10,
+            ScrollCommand::NW => 0x20,
+        }
+    }
 }
 
 fn iter_copy<'a, T: Copy + 'a, DI: Iterator<Item=&'a mut T>, SI: Iterator<Item=T>>(dest: DI, src: SI) {
@@ -124,6 +143,12 @@ impl RgbColor {
         RgbColor((data0 as u16 & 0x3F) << 6 | (data1 as u16 & 0x3F))
     }
 
+    /// The inverse of `from_subchannel`; produces the pair of sixbits
+    /// that represent this color in a Load Palette command.
+    fn to_subchannel(self) -> (u8, u8) {
+        ((self.0 >> 6) as u8 & 0x3F, self.0 as u8 & 0x3F)
+    }
+
     /// Convert from an RGB triplet. The individual channels are each
     /// truncated to four bits.
     pub fn from_rgb(r: u8, g: u8, b: u8) -> RgbColor {
@@ -145,7 +170,7 @@ impl RgbColor {
 
 /// One drawing command
 #[allow(missing_docs)]
-#[derive(Debug)]
+#[derive(Debug,Clone,Eq,PartialEq)]
 pub enum Command {
     /// Clear the scren to `color`. This command will usually appear
     /// multiple times in a row with `repeat` incrementing each time,
@@ -234,6 +259,69 @@ pub fn decode_subchannel_cmd(block: &[u8]) -> Option<Command> {
     }
 }
 
+fn unparse_scroll(data: &mut [u8], color: Option<u8>, cmd: (ScrollCommand, ScrollCommand), offset: (u8, u8)) {
+    data[0] = color.unwrap_or(0) & 0xF;
+    data[1] = cmd.0.to_u8() | offset.0 & 0x07;
+    data[2] = cmd.1.to_u8() | offset.1 & 0x0F;
+}
+
+fn unparse_clut(data: &mut [u8], clut: &[RgbColor; 8]) {
+    for (chunk, color) in data.chunks_mut(2).zip(clut.iter()) {
+        let (data0, data1) = color.to_subchannel();
+        chunk[0] = data0;
+        chunk[1] = data1;
+    }
+}
+
+/// Encode a single command into a 24-byte subchannel pack. This is
+/// the inverse of `decode_subchannel_cmd`: decoding the result yields
+/// the original command, and re-encoding a decoded pack yields the
+/// original bytes, provided that the pack had its P and Q bits and
+/// parity bytes zeroed.
+///
+/// The parity bytes are left zeroed; nothing in the `.cdg` file
+/// world checks them.
+pub fn encode_subchannel_cmd(command: &Command) -> [u8; 24] {
+    let mut block = [0; 24];
+    block[0] = 9;
+    {
+        let data = &mut block[4..20];
+        let instruction = match *command {
+            Command::MemoryPreset{color, repeat} => {
+                data[0] = color & 0xF;
+                data[1] = repeat & 0xF;
+                1
+            },
+            Command::BorderPreset{color} => {
+                data[0] = color & 0xF;
+                2
+            },
+            Command::TileNormal{ref tile} => {
+                data.copy_from_slice(&tile.to_subchannel());
+                6
+            },
+            Command::TileXOR{ref tile} => {
+                data.copy_from_slice(&tile.to_subchannel());
+                38
+            },
+            Command::Scroll{color, cmd, offset} => {
+                unparse_scroll(data, color, cmd, offset);
+                if color.is_some() { 20 } else { 24 }
+            },
+            Command::SetTransparent{color} => {
+                data[0] = color & 0xF;
+                28
+            },
+            Command::LoadPalette{offset, ref clut} => {
+                unparse_clut(data, clut);
+                if offset < 8 { 30 } else { 31 }
+            },
+        };
+        block[1] = instruction;
+    }
+    block
+}
+
 /// Iterator over the blocks within a sector. This produces a stream
 /// of `Command` objects, skipping over invalid commands and only
 /// returning `None` when there are no more valid commands.
@@ -303,9 +391,186 @@ impl <R: Read> SubchannelStreamIter<R> {
     }
 }
 
+/// Writes a stream of sectors to a writer; the inverse of
+/// `SubchannelStreamIter`. Sectors with fewer than four commands are
+/// padded out with empty packs, which decoders skip.
+///
+/// # Examples
+///
+/// ```
+/// let mut writer = cdg::SubchannelStreamWriter::new(Vec::new());
+/// writer.write_sector(&[cdg::Command::MemoryPreset{color: 0, repeat: 0}]).unwrap();
+/// writer.write_sector(&[]).unwrap();
+/// assert_eq!(writer.into_inner().len(), 192);
+/// ```
+pub struct SubchannelStreamWriter<W: Write> {
+    writer: W,
+}
+
+impl <W: Write> SubchannelStreamWriter<W> {
+    /// Create a new subchannel stream writer around a Writer
+    pub fn new(writer: W) -> Self {
+        SubchannelStreamWriter{
+            writer: writer,
+        }
+    }
+
+    /// Write a single sector containing up to four commands. Fails
+    /// with `InvalidInput` if there are more commands than will fit
+    /// in a sector.
+    pub fn write_sector(&mut self, commands: &[Command]) -> io::Result<()> {
+        if commands.len() > 4 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "A sector holds at most four commands"));
+        }
+        let mut sector = [0; 96];
+        for (pack, cmd) in sector.chunks_mut(24).zip(commands) {
+            pack.copy_from_slice(&encode_subchannel_cmd(cmd));
+        }
+        self.writer.write_all(&sector)
+    }
+
+    /// Unwrap this writer, returning the underlying Writer
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
 #[cfg(test)]
 mod tests {
+    use super::*;
+
     #[test]
     fn it_works() {
     }
+
+    fn tile_pack(instruction: u8, channel: u8) -> [u8; 24] {
+        let mut pack = [0; 24];
+        pack[0] = 9;
+        pack[1] = instruction;
+        pack[4] = 0x03 | (channel << 2) & 0x30;
+        pack[5] = 0x0C | (channel << 4) & 0x30;
+        pack[6] = 17;
+        pack[7] = 49;
+        for (i, row) in pack[8..20].iter_mut().enumerate() {
+            *row = (i as u8 * 0x15) & 0x3F;
+        }
+        pack
+    }
+
+    fn corpus() -> Vec<[u8; 24]> {
+        let mut packs = Vec::new();
+        let mut pack = [0; 24];
+        pack[0] = 9;
+
+        // Memory preset, with repeats
+        for repeat in 0..16 {
+            pack[1] = 1;
+            pack[4] = 7;
+            pack[5] = repeat;
+            packs.push(pack);
+        }
+        // Border preset
+        pack = [0; 24];
+        pack[0] = 9;
+        pack[1] = 2;
+        pack[4] = 0xF;
+        packs.push(pack);
+
+        // Tiles, on every channel
+        for channel in 0..16 {
+            packs.push(tile_pack(6, channel));
+            packs.push(tile_pack(38, channel));
+        }
+
+        // Scroll preset and copy, in every direction and offset
+        for &instruction in &[20, 24] {
+            for hcmd in 0..3 {
+                for vcmd in 0..3 {
+                    for off in 0..16 {
+                        pack = [0; 24];
+                        pack[0] = 9;
+                        pack[1] = instruction;
+                        pack[4] = if instruction == 20 { off & 0xF } else { 0 };
+                        pack[5] = hcmd << 4 | off & 0x07;
+                        pack[6] = vcmd << 4 | off;
+                        packs.push(pack);
+                    }
+                }
+            }
+        }
+
+        // Transparent color
+        pack = [0; 24];
+        pack[0] = 9;
+        pack[1] = 28;
+        pack[4] = 5;
+        packs.push(pack);
+
+        // Both halves of the palette
+        for &instruction in &[30, 31] {
+            pack = [0; 24];
+            pack[0] = 9;
+            pack[1] = instruction;
+            for (i, b) in pack[4..20].iter_mut().enumerate() {
+                *b = (i as u8).wrapping_mul(0x1B).wrapping_add(instruction) & 0x3F;
+            }
+            packs.push(pack);
+        }
+        packs
+    }
+
+    #[test]
+    fn pack_round_trip() {
+        for pack in corpus() {
+            let cmd = decode_subchannel_cmd(&pack).expect("Corpus pack failed to decode");
+            assert_eq!(&encode_subchannel_cmd(&cmd)[..], &pack[..], "{:?}", cmd);
+        }
+    }
+
+    #[test]
+    fn command_round_trip() {
+        for pack in corpus() {
+            let cmd = decode_subchannel_cmd(&pack).unwrap();
+            assert_eq!(decode_subchannel_cmd(&encode_subchannel_cmd(&cmd)), Some(cmd));
+        }
+    }
+
+    #[test]
+    fn sector_round_trip() {
+        let packs = corpus();
+        let commands : Vec<Command> = packs.iter().filter_map(|p| decode_subchannel_cmd(p)).collect();
+        let mut writer = SubchannelStreamWriter::new(Vec::new());
+        // Use a ragged sector size to exercise the padding
+        for chunk in commands.chunks(3) {
+            writer.write_sector(chunk).unwrap();
+        }
+        let stream = writer.into_inner();
+        assert_eq!(stream.len(), commands.chunks(3).count() * 96);
+
+        let mut reader = SubchannelStreamIter::new(&stream[..]);
+        let mut decoded = Vec::new();
+        while let Some(sector) = reader.next() {
+            let sector: Vec<Command> = sector.collect();
+            assert!(sector.len() <= 3);
+            decoded.extend(sector);
+        }
+        assert_eq!(decoded, commands);
+
+        // Writing the decoded sectors back out must reproduce the stream exactly
+        let mut rewriter = SubchannelStreamWriter::new(Vec::new());
+        let mut reader = SubchannelStreamIter::new(&stream[..]);
+        while let Some(sector) = reader.next() {
+            let sector: Vec<Command> = sector.collect();
+            rewriter.write_sector(&sector).unwrap();
+        }
+        assert_eq!(rewriter.into_inner(), stream);
+    }
+
+    #[test]
+    fn oversized_sector() {
+        let cmd = Command::BorderPreset{color: 1};
+        let mut writer = SubchannelStreamWriter::new(Vec::new());
+        assert!(writer.write_sector(&vec![cmd; 5]).is_err());
+        assert!(writer.into_inner().is_empty());
+    }
 }
@@ -3,13 +3,28 @@ extern crate cdg_renderer;
 extern crate image;
 use image::{GenericImage};
 use std::fs::File;
+use std::time::Duration;
 
 const SECTORS_PER_FRAME : usize = 3;
+const USAGE : &'static str = "Usage: $0 filename destdir\n       $0 --terminal[=blocks|sixel] filename";
 
 fn main() {
-    let mut args = std::env::args().skip(1);
-    let filename = args.next().expect("Usage: $0 filename destdir");
-    let destdir = args.next().expect("Usage: $0 filename destdir");
+    let mut args = std::env::args().skip(1).peekable();
+    // With --terminal, play the disc in the terminal instead of
+    // writing out frames
+    let terminal = match args.peek().cloned() {
+        Some(ref arg) if arg.starts_with("--terminal") => {
+            args.next();
+            let mode = match arg.splitn(2, '=').nth(1) {
+                Some(mode) => mode.parse().expect(USAGE),
+                None => cdg_renderer::terminal::Mode::HalfBlock,
+            };
+            Some(cdg_renderer::terminal::TerminalRenderer::new(mode))
+        },
+        _ => None,
+    };
+    let filename = args.next().expect(USAGE);
+    let destdir = if terminal.is_none() { args.next().expect(USAGE) } else { String::new() };
 
     let infile = File::open(filename).unwrap();
     let mut scsi = cdg::SubchannelStreamIter::new(std::io::BufReader::with_capacity(16384, infile));
@@ -18,14 +33,21 @@ fn main() {
     let mut sector_no = 0;
     let mut res_image = image::RgbaImage::new(300,216);
     let mut interp = cdg_renderer::CdgInterpreter::new();
-    
+    let stdout = std::io::stdout();
+    let mut terminal = terminal.map(|renderer| (renderer, stdout.lock()));
+
     while let Some(sector) = scsi.next() {
         if sector_no != 0 && sector_no % SECTORS_PER_FRAME == 0 {
             // render a frame
-            res_image.copy_from(&interp, 0, 0);
-            // for now, don't dump; just benchmarking
-
-            res_image.save(format!("{}/frame_{:05}.png", destdir, frame_no)).unwrap();
+            if let Some((ref mut renderer, ref mut out)) = terminal {
+                renderer.render(&interp, out).unwrap();
+                interp.clear_dirty_region();
+                // 75 sectors a second
+                std::thread::sleep(Duration::from_millis(40));
+            } else {
+                res_image.copy_from(&interp, 0, 0);
+                res_image.save(format!("{}/frame_{:05}.png", destdir, frame_no)).unwrap();
+            }
             frame_no += 1;
         }
         for cmd in sector {
@@ -33,4 +55,7 @@ fn main() {
         }
         sector_no += 1;
     }
+    if let Some((ref mut renderer, ref mut out)) = terminal {
+        renderer.finish(out).unwrap();
+    }
 }
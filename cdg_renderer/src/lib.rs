@@ -1,8 +1,11 @@
 extern crate cdg;
+#[cfg(feature = "image")]
 extern crate image;
 
 use std::ops::{Index,IndexMut,Fn,Add};
 
+pub mod terminal;
+
 pub trait One {
     fn one() -> Self;
 }
@@ -14,10 +17,10 @@ impl One for u8 {
     fn one() -> Self { 1 }
 }
 
-#[derive(Clone,Copy,Debug)]
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
 pub struct Position<T> {
-    x: T,
-    y: T,
+    pub x: T,
+    pub y: T,
 }
 
 impl <T> Position<T> {
@@ -26,7 +29,7 @@ impl <T> Position<T> {
     }
 }
 
-#[derive(Copy,Clone,Debug)]
+#[derive(Copy,Clone,Debug,PartialEq,Eq)]
 pub struct Rectangle<T> {
     pub nw: Position<T>,
     pub se: Position<T>,
@@ -45,14 +48,44 @@ impl <T: Ord + Copy + Add<Output=T> + One> Rectangle<T> {
     }
 }
 
+/// The byte order of pixels produced by `CdgInterpreter::scanout`
+#[derive(Copy,Clone,Debug,PartialEq,Eq)]
+pub enum PixelFormat {
+    Rgba,
+    Bgra,
+}
+
+/// The complete displayable state of a `CdgInterpreter`, independent
+/// of the commands that produced it.
+#[derive(Clone,Debug,PartialEq)]
+pub struct Snapshot {
+    pub clut: [cdg::RgbColor; 16],
+    /// CLUT indices of the framebuffer, 300x216 in row-major order,
+    /// with whole-tile scrolling already applied.
+    pub content: Vec<u8>,
+    pub transparent: Option<u8>,
+    pub border: u8,
+    /// The sub-tile scroll offset, horizontal then vertical
+    pub offset: (u8, u8),
+}
+
 const TILE_ROWS: usize = 18;
 const TILE_COLS: usize = 50;
 
+const WIDTH: usize = 300;
+const HEIGHT: usize = 216;
+// The border covers the outermost half-tile on each side
+const BORDER_WIDTH: usize = 6;
+const BORDER_HEIGHT: usize = 12;
+
 pub struct CdgInterpreter {
     tile_shift: Position<u16>,
     pixel_shift: Position<u16>,
     clut: [cdg::RgbColor; 16],
     dirty: Option<Rectangle<u16>>, // in tiles
+    /// Set when the CLUT or the transparent color changes, which
+    /// recolors every pixel without changing its index
+    palette_dirty: bool,
     content: [[u8;300];216],
     border: u8,
     transparent: u8, // is 0..15 if a color is transparent, 0xff if not
@@ -130,17 +163,16 @@ impl CdgInterpreter {
                    RgbColor::from_rgb(255,255,255)],
             dirty: Some(Rectangle::new(Position::new(0,0),
                                        Position::new(50,18))),
+            palette_dirty: true,
             content: [[0;300];216],
             border: 0,
             transparent: 255,
         }
     }
-    #[allow(unused)]
     fn map_pxrow(&self, row: usize) -> usize {
         (row + self.tile_shift.y as usize * 12) % 216
     }
 
-    #[allow(unused)]
     fn map_pxcol(&self, row: usize) -> usize {
         (row + self.tile_shift.x as usize * 6) % 300
     }
@@ -149,38 +181,43 @@ impl CdgInterpreter {
         (row + self.tile_shift.y as usize) % TILE_ROWS
     }
 
+    /// The CLUT index of the pixel that is displayed at x,y. This
+    /// takes the sub-tile scroll offset into account, and paints the
+    /// border region in the border color.
+    #[cfg(any(feature = "image", test))]
+    fn display_index(&self, x: usize, y: usize) -> u8 {
+        if x < BORDER_WIDTH || x >= WIDTH - BORDER_WIDTH
+            || y < BORDER_HEIGHT || y >= HEIGHT - BORDER_HEIGHT {
+            self.border
+        } else {
+            self.content[self.map_pxrow(y + self.pixel_shift.y as usize)][self.map_pxcol(x + self.pixel_shift.x as usize)]
+        }
+    }
+
     fn map_tcol(&self, col: usize) -> usize {
         (col + self.tile_shift.x as usize) % TILE_COLS
     }
 
-    fn clear_col(&mut self, n: usize, color: Option<u8>) {
-        match color {
-            None => (),
-            Some(color) => {
-                let col = self.map_tcol(n) * 6;
-                for r in 0..216 {
-                    for c in col..col+6 {
-                        unsafe {*self.content.get_unchecked_mut(r).get_unchecked_mut(c) = color; }
-                    }
-                }
+    /// Fill the tile column `n` (in display coordinates) with `color`
+    fn fill_col(&mut self, n: usize, color: u8) {
+        let col = self.map_tcol(n) * 6;
+        for row in self.content.iter_mut() {
+            for px in row[col..col+6].iter_mut() {
+                *px = color;
             }
         }
     }
 
-    fn clear_row(&mut self, n: usize, color: Option<u8>) {
-        match color {
-            None => (),
-            Some(color) => {
-                let col = self.map_trow(n) * 12;
-                for c in col..col+12 {
-                    for r in 0..216 {
-                        unsafe {*self.content.get_unchecked_mut(r).get_unchecked_mut(c) = color; }
-                    }
-                }
+    /// Fill the tile row `n` (in display coordinates) with `color`
+    fn fill_row(&mut self, n: usize, color: u8) {
+        let row = self.map_trow(n) * 12;
+        for line in self.content[row..row+12].iter_mut() {
+            for px in line.iter_mut() {
+                *px = color;
             }
         }
-    }    
-    
+    }
+
     fn get_tile(&mut self, pos: (u8,u8)) -> TileView {
         let x = (pos.0 as u16 + self.tile_shift.x) as usize % TILE_COLS;
         let y = (pos.1 as u16 + self.tile_shift.y) as usize % TILE_ROWS;
@@ -192,8 +229,21 @@ impl CdgInterpreter {
         }
     }
 
+    /// The region of the display, in tiles, whose colors have
+    /// changed. A change to the palette dirties the whole display.
     pub fn dirty(&self) -> Option<Rectangle<u16>> {
-        self.dirty
+        if self.palette_dirty {
+            Some(Rectangle::new(Position::new(0,0),
+                                Position::new(50,18)))
+        } else {
+            self.dirty
+        }
+    }
+
+    /// True if the CLUT or the transparent color has changed since
+    /// the dirty region was last cleared
+    pub fn palette_dirty(&self) -> bool {
+        self.palette_dirty
     }
 
     fn invalidate_tile(&mut self, pos: (u8, u8)) {
@@ -209,24 +259,168 @@ impl CdgInterpreter {
                                          Position::new(50,18)));
     }
 
+    /// Capture the current state of the display
+    pub fn snapshot(&self) -> Snapshot {
+        let mut content = Vec::with_capacity(WIDTH * HEIGHT);
+        for y in 0..HEIGHT {
+            let row = &self.content[self.map_pxrow(y)];
+            content.extend((0..WIDTH).map(|x| row[self.map_pxcol(x)]));
+        }
+        Snapshot{
+            clut: self.clut,
+            content: content,
+            transparent: if self.transparent < 16 { Some(self.transparent) } else { None },
+            border: self.border,
+            offset: (self.pixel_shift.x as u8, self.pixel_shift.y as u8),
+        }
+    }
+
+    /// Replace the state of the display with a snapshot, such as one
+    /// taken by `snapshot`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the snapshot's content is not exactly 300x216 pixels
+    pub fn restore(&mut self, snapshot: &Snapshot) {
+        assert_eq!(snapshot.content.len(), WIDTH * HEIGHT);
+        for (row, src) in self.content.iter_mut().zip(snapshot.content.chunks(WIDTH)) {
+            row.copy_from_slice(src);
+        }
+        self.tile_shift = Position::new(0, 0);
+        self.pixel_shift = Position::new(snapshot.offset.0 as u16 % 6, snapshot.offset.1 as u16 % 12);
+        self.clut = snapshot.clut;
+        self.transparent = snapshot.transparent.unwrap_or(255);
+        self.border = snapshot.border;
+        self.invalidate_all();
+        self.palette_dirty = true;
+    }
+
     /// Mark the entire region clean
     pub fn clear_dirty_region(&mut self) {
         self.dirty = None;
+        self.palette_dirty = false;
+    }
+
+    /// The CLUT, as used by `scanout_indices`
+    pub fn clut(&self) -> &[cdg::RgbColor; 16] {
+        &self.clut
+    }
+
+    /// The CLUT index that is drawn transparent, if any
+    pub fn transparent(&self) -> Option<u8> {
+        if self.transparent < 16 { Some(self.transparent) } else { None }
+    }
+
+    fn tiles_to_pixels(&self, tiles: Rectangle<u16>) -> Rectangle<u16> {
+        use std::cmp::min;
+        let px = self.pixel_shift.x;
+        let py = self.pixel_shift.y;
+        Rectangle{
+            nw: Position::new((tiles.nw.x * 6).saturating_sub(px),
+                              (tiles.nw.y * 12).saturating_sub(py)),
+            se: Position::new(min(tiles.se.x * 6, WIDTH as u16),
+                              min(tiles.se.y * 12, HEIGHT as u16)),
+        }
+    }
+
+    /// The region of the display, in pixels, that has changed since
+    /// the dirty region was last cleared. This accounts for the
+    /// scroll offset, so it is the region that needs to be passed to
+    /// `scanout`.
+    pub fn dirty_pixels(&self) -> Option<Rectangle<u16>> {
+        self.dirty().map(|tiles| self.tiles_to_pixels(tiles))
+    }
+
+    /// Like `dirty_pixels`, but leaving out palette changes: the
+    /// region that needs to be passed to `scanout_indices` when the
+    /// palette is applied elsewhere.
+    pub fn dirty_index_pixels(&self) -> Option<Rectangle<u16>> {
+        self.dirty.map(|tiles| self.tiles_to_pixels(tiles))
+    }
+
+    fn palette_lut(&self, format: PixelFormat) -> [[u8; 4]; 16] {
+        let mut lut = [[0; 4]; 16];
+        for (entry, color) in lut.iter_mut().zip(self.clut.iter()) {
+            *entry = match format {
+                PixelFormat::Rgba => [color.r(), color.g(), color.b(), 255],
+                PixelFormat::Bgra => [color.b(), color.g(), color.r(), 255],
+            };
+        }
+        if (self.transparent as usize) < lut.len() {
+            lut[self.transparent as usize] = [0, 0, 0, 0];
+        }
+        lut
     }
 
-    /*
-    // For performance reasons, always produces an RGBA image
-    fn scanout<Image>(&self, image: &mut Image, region: Rectangle<u16>) {
-        for y in region.nw.y as usize..region.se.y as usize {
-            for x in region.nw.x as usize..region.se.x as usize {
-                pos = (y * stride + x) * 4;
-                buffer[y * stride + x] = 
+    /// Render `region` (in pixels) of the display into `buffer`,
+    /// which receives 4 bytes per pixel in the given format. The
+    /// top-left pixel of the region is written to the start of the
+    /// buffer, and each row begins `stride` bytes after the previous
+    /// one; to render into a full-sized image instead, pass the
+    /// slice of that image starting at the region's origin.
+    ///
+    /// Pixels outside the region are not touched. Typically, the
+    /// region is the one returned by `dirty_pixels`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the region extends past the edge of the display, or
+    /// if the buffer is too small to hold it.
+    pub fn scanout(&self, region: Rectangle<u16>, buffer: &mut [u8], stride: usize, format: PixelFormat) {
+        let lut = self.palette_lut(format);
+        self.scan(region, buffer, stride, 4, |px, index| px.copy_from_slice(&lut[index as usize]));
+    }
+
+    /// Like `scanout`, but writes the CLUT index of each pixel as a
+    /// single byte, for the palette to be applied elsewhere (such as
+    /// on the GPU). Transparency is left to whoever applies it.
+    pub fn scanout_indices(&self, region: Rectangle<u16>, buffer: &mut [u8], stride: usize) {
+        self.scan(region, buffer, stride, 1, |px, index| px[0] = index);
+    }
+
+    /// Walk `region` of the display, calling `put` with each pixel's
+    /// `bytes` bytes of `buffer` and its CLUT index
+    fn scan<F: Fn(&mut [u8], u8)>(&self, region: Rectangle<u16>, buffer: &mut [u8], stride: usize, bytes: usize, put: F) {
+        let x0 = region.nw.x as usize;
+        let y0 = region.nw.y as usize;
+        let x1 = region.se.x as usize;
+        let y1 = region.se.y as usize;
+        assert!(x1 <= WIDTH && y1 <= HEIGHT);
+        if x0 >= x1 || y0 >= y1 {
+            return;
+        }
+        assert!(stride >= (x1 - x0) * bytes);
+        assert!(buffer.len() >= (y1 - y0 - 1) * stride + (x1 - x0) * bytes);
+
+        let border = self.border & 0xF;
+        let start_col = self.map_pxcol(x0 + self.pixel_shift.x as usize);
+        for y in y0..y1 {
+            let line = &mut buffer[(y - y0) * stride..(y - y0) * stride + (x1 - x0) * bytes];
+            if y < BORDER_HEIGHT || y >= HEIGHT - BORDER_HEIGHT {
+                for px in line.chunks_mut(bytes) {
+                    put(px, border);
+                }
+                continue;
+            }
+
+            let src = &self.content[self.map_pxrow(y + self.pixel_shift.y as usize)];
+            let mut col = start_col;
+            for (x, px) in (x0..x1).zip(line.chunks_mut(bytes)) {
+                if x < BORDER_WIDTH || x >= WIDTH - BORDER_WIDTH {
+                    put(px, border);
+                } else {
+                    put(px, src[col] & 0xF);
+                }
+                col += 1;
+                if col == WIDTH {
+                    col = 0;
+                }
             }
         }
     }
-     */
 }
 
+#[cfg(feature = "image")]
 impl image::GenericImage for CdgInterpreter {
     type Pixel = image::Rgba<u8>;
 
@@ -240,7 +434,7 @@ impl image::GenericImage for CdgInterpreter {
 
     fn get_pixel(&self, x: u32, y: u32) -> Self::Pixel {
         use image::Pixel;
-        let cindex = self.content[self.map_pxrow(y as usize)][self.map_pxcol(x as usize)];
+        let cindex = self.display_index(x as usize, y as usize);
         if self.transparent == cindex {
             image::Rgba::from_channels(0,0,0,0)
         } else {
@@ -284,37 +478,48 @@ impl CdgInterpreter {
             TileXOR{tile} => { self.get_tile(tile.pos).draw_xor(&tile); self.invalidate_tile(tile.pos); },
             Scroll{color, cmd: (xc, yc), offset: (xo,yo)} => {
                 use cdg::ScrollCommand::{NW,SE,Noop};
-                // Handle horizontal scrolling first
+                // Scrolling rotates the framebuffer by a whole tile;
+                // tiles that move off one edge reappear at the other,
+                // which is exactly a scroll copy. For a scroll preset,
+                // the tiles that wrapped around are then filled in.
                 match xc {
                     NW => {
-                        self.clear_col(0, color);
                         self.tile_shift.x = (self.tile_shift.x + 1) % TILE_COLS as u16;
+                        if let Some(color) = color {
+                            self.fill_col(TILE_COLS - 1, color);
+                        }
                     }
                     SE => {
                         self.tile_shift.x = (self.tile_shift.x + TILE_COLS as u16 - 1) % TILE_COLS as u16;
-                        self.clear_col(0, color);
+                        if let Some(color) = color {
+                            self.fill_col(0, color);
+                        }
                     }
                     Noop => (),
                 }
                 match yc {
                     NW => {
-                        self.clear_row(0, color);
                         self.tile_shift.y = (self.tile_shift.y + 1) % TILE_ROWS as u16;
+                        if let Some(color) = color {
+                            self.fill_row(TILE_ROWS - 1, color);
+                        }
                     }
                     SE => {
                         self.tile_shift.y = (self.tile_shift.y + TILE_ROWS as u16 - 1) % TILE_ROWS as u16;
-                        self.clear_row(0, color);
+                        if let Some(color) = color {
+                            self.fill_row(0, color);
+                        }
                     }
                     Noop => (),
                 }
                 self.pixel_shift = Position::new(xo as u16 % 6, yo as u16 % 12);
                 self.invalidate_all();
             },
-            SetTransparent{color} => {self.transparent = color; self.invalidate_all(); },
+            SetTransparent{color} => {self.transparent = color; self.palette_dirty = true; },
             LoadPalette{offset, clut} => {
                 let off = offset as usize;
                 self.clut[off..off+8].copy_from_slice(&clut);
-                self.invalidate_all();
+                self.palette_dirty = true;
             }
             
         }
@@ -329,7 +534,423 @@ impl Default for CdgInterpreter {
 
 #[cfg(test)]
 mod tests {
+    use super::{CdgInterpreter, Position, Rectangle};
+    use cdg;
+
     #[test]
     fn it_works() {
     }
+
+    // A tile whose content is a function of its position, so that
+    // any misplaced pixel shows up in the output.
+    fn pattern_tile(x: u8, y: u8) -> cdg::Tile {
+        let mut content = [0; 12];
+        for (row, px) in content.iter_mut().enumerate() {
+            *px = (row as u8 * 7 + x * 3 + y) & 0x3F;
+        }
+        cdg::Tile{
+            pos: (x, y),
+            color: ((x + y) % 16, (x * 3 + y * 5 + 1) % 16),
+            content: content,
+            channel: 0,
+        }
+    }
+
+    fn pattern_pixel(x: usize, y: usize) -> u8 {
+        pattern_tile((x / 6) as u8, (y / 12) as u8).get_pixel((x % 6) as u8, (y % 12) as u8)
+    }
+
+    fn scroll(h: (cdg::ScrollCommand, u8), v: (cdg::ScrollCommand, u8)) -> cdg::Command {
+        cdg::Command::Scroll{color: None, cmd: (h.0, v.0), offset: (h.1, v.1)}
+    }
+
+    const PATTERN_SECTORS: usize = 1 + 50 * 18;
+
+    /// Build a test disc: a cleared screen with a colored border and
+    /// the test pattern, followed by the given commands, one per sector.
+    fn test_disc(border: u8, commands: &[cdg::Command]) -> Vec<u8> {
+        let mut writer = cdg::SubchannelStreamWriter::new(Vec::new());
+        writer.write_sector(&[cdg::Command::MemoryPreset{color: 0, repeat: 0},
+                              cdg::Command::BorderPreset{color: border}]).unwrap();
+        for y in 0..18 {
+            for x in 0..50 {
+                writer.write_sector(&[cdg::Command::TileNormal{tile: pattern_tile(x, y)}]).unwrap();
+            }
+        }
+        for cmd in commands {
+            writer.write_sector(&[cmd.clone()]).unwrap();
+        }
+        writer.into_inner()
+    }
+
+    /// Play a disc, producing the displayed CLUT indices after each
+    /// of the sectors following the test pattern
+    fn play(disc: &[u8]) -> Vec<Vec<u8>> {
+        let mut interp = CdgInterpreter::new();
+        let mut frames = Vec::new();
+        let mut sectors = cdg::SubchannelStreamIter::new(disc);
+        let mut sector_no = 0;
+        while let Some(sector) = sectors.next() {
+            for cmd in sector {
+                interp.handle_cmd(cmd);
+            }
+            sector_no += 1;
+            if sector_no > PATTERN_SECTORS {
+                frames.push(frame_of(&interp));
+            }
+        }
+        frames
+    }
+
+    fn frame_of(interp: &CdgInterpreter) -> Vec<u8> {
+        let mut frame = Vec::with_capacity(300 * 216);
+        for y in 0..216 {
+            for x in 0..300 {
+                frame.push(interp.display_index(x, y));
+            }
+        }
+        frame
+    }
+
+    /// The frame that should be visible when the test pattern has been
+    /// scrolled by (dx, dy) pixels in total
+    fn golden_frame(border: u8, dx: usize, dy: usize) -> Vec<u8> {
+        let mut frame = Vec::with_capacity(300 * 216);
+        for y in 0..216 {
+            for x in 0..300 {
+                frame.push(if x < 6 || x >= 294 || y < 12 || y >= 204 {
+                    border
+                } else {
+                    pattern_pixel((x + dx) % 300, (y + dy) % 216)
+                });
+            }
+        }
+        frame
+    }
+
+    fn assert_frame_eq(actual: &[u8], expected: &[u8], what: &str) {
+        if let Some(pos) = actual.iter().zip(expected).position(|(a, e)| a != e) {
+            panic!("{}: first mismatch at ({}, {}): got {}, expected {}",
+                   what, pos % 300, pos / 300, actual[pos], expected[pos]);
+        }
+    }
+
+    #[test]
+    fn border_is_painted() {
+        let frames = play(&test_disc(9, &[cdg::Command::BorderPreset{color: 9}]));
+        assert_frame_eq(&frames[0], &golden_frame(9, 0, 0), "unscrolled");
+    }
+
+    #[cfg(feature = "image")]
+    #[test]
+    fn border_get_pixel() {
+        use image::{GenericImage, Pixel};
+        let mut interp = CdgInterpreter::new();
+        interp.handle_cmd(cdg::Command::BorderPreset{color: 4});
+        let expected = interp.clut[4];
+        for &(x, y) in &[(0, 0), (5, 100), (294, 100), (150, 11), (150, 204), (299, 215)] {
+            let px = interp.get_pixel(x, y);
+            assert_eq!(px.channels(), &[expected.r(), expected.g(), expected.b(), 255][..]);
+        }
+    }
+
+    #[test]
+    fn pixel_offsets() {
+        use cdg::ScrollCommand::Noop;
+        let mut offsets = Vec::new();
+        for dy in 0..12 {
+            for dx in 0..6 {
+                offsets.push((dx, dy));
+            }
+        }
+        let commands: Vec<_> = offsets.iter().map(|&(dx, dy)| scroll((Noop, dx), (Noop, dy))).collect();
+        let frames = play(&test_disc(3, &commands));
+        for (frame, &(dx, dy)) in frames.iter().zip(&offsets) {
+            assert_frame_eq(frame, &golden_frame(3, dx as usize, dy as usize),
+                            &format!("offset {},{}", dx, dy));
+        }
+    }
+
+    #[test]
+    fn smooth_vertical_scroll() {
+        use cdg::ScrollCommand::{Noop, NW};
+        // Scroll up by two and a half tiles a pixel at a time, the way
+        // karaoke discs move lyrics.
+        let mut commands = Vec::new();
+        for step in 1..31 {
+            if step % 12 == 0 {
+                commands.push(scroll((Noop, 0), (NW, 0)));
+            } else {
+                commands.push(scroll((Noop, 0), (Noop, step % 12)));
+            }
+        }
+        let frames = play(&test_disc(1, &commands));
+        for (step, frame) in frames.iter().enumerate() {
+            assert_frame_eq(frame, &golden_frame(1, 0, step + 1), &format!("step {}", step + 1));
+        }
+    }
+
+    #[test]
+    fn smooth_horizontal_scroll() {
+        use cdg::ScrollCommand::{Noop, NW};
+        let mut commands = Vec::new();
+        for step in 1..20 {
+            if step % 6 == 0 {
+                commands.push(scroll((NW, 0), (Noop, 0)));
+            } else {
+                commands.push(scroll((Noop, step % 6), (Noop, 0)));
+            }
+        }
+        let frames = play(&test_disc(2, &commands));
+        for (step, frame) in frames.iter().enumerate() {
+            assert_frame_eq(frame, &golden_frame(2, step + 1, 0), &format!("step {}", step + 1));
+        }
+    }
+
+    /// The framebuffer as seen through the tile shift, ignoring the
+    /// pixel offset and the border
+    fn framebuffer_of(interp: &CdgInterpreter) -> Vec<u8> {
+        let mut frame = Vec::with_capacity(300 * 216);
+        for y in 0..216 {
+            for x in 0..300 {
+                frame.push(interp.content[interp.map_pxrow(y)][interp.map_pxcol(x)]);
+            }
+        }
+        frame
+    }
+
+    fn direction(cmd: cdg::ScrollCommand) -> isize {
+        match cmd {
+            cdg::ScrollCommand::NW => 1,
+            cdg::ScrollCommand::SE => -1,
+            cdg::ScrollCommand::Noop => 0,
+        }
+    }
+
+    /// The framebuffer expected after scrolling the test pattern once
+    fn scrolled_framebuffer(color: Option<u8>, cmd: (cdg::ScrollCommand, cdg::ScrollCommand)) -> Vec<u8> {
+        let dx = direction(cmd.0) * 6;
+        let dy = direction(cmd.1) * 12;
+        let mut frame = Vec::with_capacity(300 * 216);
+        for y in 0..216 {
+            for x in 0..300 {
+                let sx = x as isize + dx;
+                let sy = y as isize + dy;
+                let wrapped = sx < 0 || sx >= 300 || sy < 0 || sy >= 216;
+                frame.push(match color {
+                    Some(color) if wrapped => color,
+                    _ => pattern_pixel(((sx + 300) % 300) as usize, ((sy + 216) % 216) as usize),
+                });
+            }
+        }
+        frame
+    }
+
+    fn all_directions() -> Vec<(cdg::ScrollCommand, cdg::ScrollCommand)> {
+        use cdg::ScrollCommand::{NW, SE, Noop};
+        let mut directions = Vec::new();
+        for &h in &[NW, SE, Noop] {
+            for &v in &[NW, SE, Noop] {
+                if (h, v) != (Noop, Noop) {
+                    directions.push((h, v));
+                }
+            }
+        }
+        directions
+    }
+
+    fn pattern_interp() -> CdgInterpreter {
+        let mut interp = CdgInterpreter::new();
+        for y in 0..18 {
+            for x in 0..50 {
+                interp.handle_cmd(cdg::Command::TileNormal{tile: pattern_tile(x, y)});
+            }
+        }
+        interp
+    }
+
+    #[test]
+    fn scroll_copy() {
+        for cmd in all_directions() {
+            let mut interp = pattern_interp();
+            interp.handle_cmd(cdg::Command::Scroll{color: None, cmd: cmd, offset: (0, 0)});
+            assert_frame_eq(&framebuffer_of(&interp), &scrolled_framebuffer(None, cmd),
+                            &format!("copy {:?}", cmd));
+        }
+    }
+
+    #[test]
+    fn scroll_preset() {
+        for cmd in all_directions() {
+            let mut interp = pattern_interp();
+            interp.handle_cmd(cdg::Command::Scroll{color: Some(15), cmd: cmd, offset: (0, 0)});
+            assert_frame_eq(&framebuffer_of(&interp), &scrolled_framebuffer(Some(15), cmd),
+                            &format!("preset {:?}", cmd));
+        }
+    }
+
+    #[test]
+    fn scroll_copy_wraps_around() {
+        use cdg::ScrollCommand::{NW, SE};
+        // A full rotation on both axes (lcm(50, 18) = 450 tiles)
+        // brings back the original picture
+        for &cmd in &[(NW, NW), (SE, SE), (NW, SE)] {
+            let mut interp = pattern_interp();
+            let original = framebuffer_of(&interp);
+            for _ in 0..450 {
+                interp.handle_cmd(cdg::Command::Scroll{color: None, cmd: cmd, offset: (0, 0)});
+            }
+            assert_frame_eq(&framebuffer_of(&interp), &original, &format!("{:?}", cmd));
+        }
+    }
+
+    #[test]
+    fn tiles_after_scroll() {
+        use cdg::ScrollCommand::{NW, SE};
+        // Tiles are positioned relative to the display, not the
+        // underlying memory, so drawing after a scroll lands where
+        // it would on an unscrolled screen.
+        let mut interp = CdgInterpreter::new();
+        interp.handle_cmd(cdg::Command::Scroll{color: Some(0), cmd: (NW, SE), offset: (0, 0)});
+        for y in 0..18 {
+            for x in 0..50 {
+                interp.handle_cmd(cdg::Command::TileNormal{tile: pattern_tile(x, y)});
+            }
+        }
+        assert_frame_eq(&framebuffer_of(&interp), &framebuffer_of(&pattern_interp()), "redrawn");
+    }
+
+    fn full_screen() -> Rectangle<u16> {
+        Rectangle::new(Position::new(0, 0), Position::new(300, 216))
+    }
+
+    #[cfg(feature = "image")]
+    #[test]
+    fn scanout_matches_get_pixel() {
+        use cdg::ScrollCommand::{Noop, SE};
+        use image::{GenericImage, Pixel};
+        use super::PixelFormat;
+        let mut interp = pattern_interp();
+        interp.handle_cmd(cdg::Command::BorderPreset{color: 6});
+        interp.handle_cmd(cdg::Command::SetTransparent{color: 2});
+        interp.handle_cmd(cdg::Command::Scroll{color: None, cmd: (SE, Noop), offset: (4, 7)});
+
+        let mut rgba = vec![0; 300 * 216 * 4];
+        interp.scanout(full_screen(), &mut rgba, 300 * 4, PixelFormat::Rgba);
+        let mut bgra = vec![0; 300 * 216 * 4];
+        interp.scanout(full_screen(), &mut bgra, 300 * 4, PixelFormat::Bgra);
+        for y in 0..216 {
+            for x in 0..300 {
+                let off = (y * 300 + x) * 4;
+                let expected = interp.get_pixel(x as u32, y as u32);
+                assert_eq!(&rgba[off..off+4], expected.channels(), "at {},{}", x, y);
+                assert_eq!([bgra[off+2], bgra[off+1], bgra[off], bgra[off+3]], &rgba[off..off+4]);
+            }
+        }
+
+        // A sub-region lands at the start of the buffer, with the
+        // given stride, and nothing else is touched.
+        let region = Rectangle::new(Position::new(3, 5), Position::new(45, 40));
+        let mut partial = vec![0xAA; 64 * 35 * 4];
+        interp.scanout(region, &mut partial, 64 * 4, PixelFormat::Rgba);
+        for y in 0..35 {
+            for x in 0..64 {
+                let off = (y * 64 + x) * 4;
+                if x < 42 {
+                    let full_off = ((y + 5) * 300 + x + 3) * 4;
+                    assert_eq!(&partial[off..off+4], &rgba[full_off..full_off+4]);
+                } else {
+                    assert_eq!(&partial[off..off+4], &[0xAA; 4]);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn dirty_pixels() {
+        let mut interp = CdgInterpreter::new();
+        assert_eq!(interp.dirty_pixels(), Some(full_screen()));
+        interp.clear_dirty_region();
+        assert_eq!(interp.dirty_pixels(), None);
+
+        interp.handle_cmd(cdg::Command::TileNormal{tile: pattern_tile(3, 4)});
+        interp.handle_cmd(cdg::Command::TileXOR{tile: pattern_tile(7, 2)});
+        assert_eq!(interp.dirty_pixels(),
+                   Some(Rectangle::new(Position::new(18, 24), Position::new(48, 60))));
+    }
+
+    #[test]
+    fn palette_changes() {
+        let mut interp = CdgInterpreter::new();
+        assert!(interp.palette_dirty());
+        interp.clear_dirty_region();
+        assert!(!interp.palette_dirty());
+
+        // Recoloring dirties every pixel, but no index
+        interp.handle_cmd(cdg::Command::LoadPalette{offset: 8, clut: [cdg::RgbColor::from_rgb(255, 0, 0); 8]});
+        assert!(interp.palette_dirty());
+        assert_eq!(interp.dirty_pixels(), Some(full_screen()));
+        assert_eq!(interp.dirty_index_pixels(), None);
+        assert_eq!(interp.clut()[8], cdg::RgbColor::from_rgb(255, 0, 0));
+
+        interp.clear_dirty_region();
+        interp.handle_cmd(cdg::Command::SetTransparent{color: 4});
+        assert!(interp.palette_dirty());
+        assert_eq!(interp.transparent(), Some(4));
+        interp.handle_cmd(cdg::Command::TileNormal{tile: pattern_tile(3, 4)});
+        assert_eq!(interp.dirty_index_pixels(),
+                   Some(Rectangle::new(Position::new(18, 48), Position::new(24, 60))));
+    }
+
+    #[test]
+    fn scanout_indices() {
+        use cdg::ScrollCommand::{NW, Noop};
+        let mut interp = pattern_interp();
+        interp.handle_cmd(cdg::Command::BorderPreset{color: 9});
+        interp.handle_cmd(cdg::Command::Scroll{color: None, cmd: (Noop, NW), offset: (3, 5)});
+        let mut indices = vec![0; 300 * 216];
+        interp.scanout_indices(full_screen(), &mut indices, 300);
+        assert_frame_eq(&indices, &frame_of(&interp), "indices");
+    }
+
+    #[test]
+    fn snapshot() {
+        use cdg::ScrollCommand::{NW, SE};
+        let mut interp = pattern_interp();
+        interp.handle_cmd(cdg::Command::BorderPreset{color: 5});
+        interp.handle_cmd(cdg::Command::Scroll{color: None, cmd: (SE, NW), offset: (2, 9)});
+        let snapshot = interp.snapshot();
+        assert_eq!(snapshot.content, framebuffer_of(&interp));
+        assert_eq!(snapshot.offset, (2, 9));
+        assert_eq!(snapshot.border, 5);
+        assert_eq!(snapshot.transparent, None);
+        assert_eq!(&snapshot.clut[..], &interp.clut[..]);
+
+        interp.handle_cmd(cdg::Command::SetTransparent{color: 12});
+        assert_eq!(interp.snapshot().transparent, Some(12));
+    }
+
+    #[test]
+    fn restore() {
+        use cdg::ScrollCommand::{NW, SE};
+        let mut interp = pattern_interp();
+        interp.handle_cmd(cdg::Command::BorderPreset{color: 5});
+        interp.handle_cmd(cdg::Command::SetTransparent{color: 3});
+        interp.handle_cmd(cdg::Command::Scroll{color: Some(1), cmd: (NW, SE), offset: (5, 11)});
+        let snapshot = interp.snapshot();
+
+        let mut restored = CdgInterpreter::new();
+        restored.clear_dirty_region();
+        restored.restore(&snapshot);
+        assert_eq!(restored.dirty_pixels(), Some(full_screen()));
+        assert_eq!(restored.snapshot(), snapshot);
+        assert_frame_eq(&frame_of(&restored), &frame_of(&interp), "restored");
+
+        // Drawing on top of the restored state matches drawing on the original
+        for interp in [&mut interp, &mut restored].iter_mut() {
+            interp.handle_cmd(cdg::Command::TileXOR{tile: pattern_tile(20, 10)});
+            interp.handle_cmd(cdg::Command::Scroll{color: None, cmd: (SE, NW), offset: (0, 0)});
+        }
+        assert_frame_eq(&frame_of(&restored), &frame_of(&interp), "after drawing");
+    }
 }
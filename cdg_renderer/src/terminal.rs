@@ -0,0 +1,325 @@
+//! Drawing the display on a terminal, for watching a disc over ssh or
+//! on a box with no screen attached.
+//!
+//! `Mode::HalfBlock` works on any terminal with 24-bit color: each
+//! character cell is an upper half block, with the foreground color
+//! painting the upper pixel and the background color the lower one,
+//! and only the cells that have changed are redrawn. `Mode::Sixel`
+//! sends real pixels to terminals that understand sixel graphics.
+
+use std::cmp::min;
+use std::io::{self, Write};
+use std::str::FromStr;
+
+use super::{CdgInterpreter, PixelFormat, Position, Rectangle, HEIGHT, WIDTH};
+
+#[derive(Copy,Clone,Debug,PartialEq,Eq)]
+pub enum Mode {
+    /// Two pixels per character cell, in ANSI truecolor
+    HalfBlock,
+    /// A sixel image. Sixel images can't be placed at arbitrary
+    /// pixel offsets, so any change redraws the whole image.
+    Sixel,
+}
+
+pub const MODES: [&'static str; 2] = ["blocks", "sixel"];
+
+impl FromStr for Mode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "blocks" => Ok(Mode::HalfBlock),
+            "sixel" => Ok(Mode::Sixel),
+            _ => Err(format!("Unknown terminal mode {:?}; expected one of {}", s, MODES.join(", "))),
+        }
+    }
+}
+
+pub struct TerminalRenderer {
+    mode: Mode,
+    scale: usize,
+    /// The number of text rows drawn so far, or None before the
+    /// screen has been cleared
+    rows: Option<usize>,
+    /// The display, as RGBA, for `render`
+    frame: Vec<u8>,
+    /// Output is built up here and written in one go, so that the
+    /// terminal never shows a half-drawn frame
+    buffer: Vec<u8>,
+}
+
+impl TerminalRenderer {
+    pub fn new(mode: Mode) -> Self {
+        TerminalRenderer{
+            mode: mode,
+            scale: 1,
+            rows: None,
+            frame: Vec::new(),
+            buffer: Vec::new(),
+        }
+    }
+
+    /// Draw only every `scale`th pixel in each direction. At full
+    /// size, half blocks take 300 columns and 108 rows.
+    pub fn set_scale(&mut self, scale: usize) {
+        self.scale = if scale == 0 { 1 } else { scale };
+        // Everything has to be drawn again at the new size
+        self.rows = None;
+    }
+
+    /// Draw the part of the interpreter's display within `dirty()`.
+    /// The dirty region is left for the caller to clear.
+    pub fn render<W: Write>(&mut self, interp: &CdgInterpreter, out: &mut W) -> io::Result<()> {
+        if self.frame.is_empty() {
+            self.frame = vec![0; WIDTH * HEIGHT * 4];
+        }
+        let region = if self.rows.is_none() {
+            // The first frame is drawn in full, whatever is dirty
+            Rectangle::new(Position::new(0, 0), Position::new(WIDTH as u16, HEIGHT as u16))
+        } else {
+            match interp.dirty_pixels() {
+                Some(region) => region,
+                None => return Ok(()),
+            }
+        };
+        let offset = (region.nw.y as usize * WIDTH + region.nw.x as usize) * 4;
+        interp.scanout(region, &mut self.frame[offset..], WIDTH * 4, PixelFormat::Rgba);
+
+        let frame = ::std::mem::replace(&mut self.frame, Vec::new());
+        let result = self.draw(&frame, WIDTH, HEIGHT, region, out);
+        self.frame = frame;
+        result
+    }
+
+    /// Draw `region` (in pixels) of an RGBA image of `width` by
+    /// `height`. The first call clears the terminal and draws the
+    /// whole image.
+    pub fn draw<W: Write>(&mut self, rgba: &[u8], width: usize, height: usize,
+                          region: Rectangle<u16>, out: &mut W) -> io::Result<()> {
+        assert!(rgba.len() >= width * height * 4);
+        self.buffer.clear();
+        let region = if self.rows.is_none() {
+            // Clear the screen and hide the cursor
+            self.buffer.extend_from_slice(b"\x1b[2J\x1b[?25l");
+            Rectangle::new(Position::new(0, 0), Position::new(width as u16, height as u16))
+        } else {
+            region
+        };
+        let rows = match self.mode {
+            Mode::HalfBlock => try!(half_blocks(rgba, width, height, region, self.scale, &mut self.buffer)),
+            Mode::Sixel => try!(sixel(rgba, width, height, self.scale, &mut self.buffer)),
+        };
+        self.rows = Some(rows);
+        try!(out.write_all(&self.buffer));
+        out.flush()
+    }
+
+    /// Put the terminal back the way it was, with the cursor below
+    /// the image
+    pub fn finish<W: Write>(&mut self, out: &mut W) -> io::Result<()> {
+        if let Some(rows) = self.rows.take() {
+            try!(write!(out, "\x1b[0m\x1b[{};1H\x1b[?25h", rows + 1));
+        }
+        out.flush()
+    }
+}
+
+fn pixel(rgba: &[u8], width: usize, height: usize, x: usize, y: usize) -> [u8; 3] {
+    if x >= width || y >= height {
+        return [0; 3];
+    }
+    let offset = (y * width + x) * 4;
+    [rgba[offset], rgba[offset + 1], rgba[offset + 2]]
+}
+
+/// Returns the number of rows of cells in the whole image
+fn half_blocks<W: Write>(rgba: &[u8], width: usize, height: usize, region: Rectangle<u16>,
+                         scale: usize, out: &mut W) -> io::Result<usize> {
+    let cell_height = scale * 2;
+    let cols = (width + scale - 1) / scale;
+    let rows = (height + cell_height - 1) / cell_height;
+    let x0 = region.nw.x as usize / scale;
+    let x1 = min((region.se.x as usize + scale - 1) / scale, cols);
+    let y0 = region.nw.y as usize / cell_height;
+    let y1 = min((region.se.y as usize + cell_height - 1) / cell_height, rows);
+
+    for row in y0..y1 {
+        try!(write!(out, "\x1b[{};{}H", row + 1, x0 + 1));
+        // Colors carry over from one cell to the next, so they're
+        // only sent when they change
+        let mut fg = None;
+        let mut bg = None;
+        for col in x0..x1 {
+            let top = pixel(rgba, width, height, col * scale, row * cell_height);
+            let bottom = pixel(rgba, width, height, col * scale, row * cell_height + scale);
+            if bg != Some(bottom) {
+                try!(write!(out, "\x1b[48;2;{};{};{}m", bottom[0], bottom[1], bottom[2]));
+                bg = Some(bottom);
+            }
+            if top == bottom {
+                try!(out.write_all(b" "));
+                continue;
+            }
+            if fg != Some(top) {
+                try!(write!(out, "\x1b[38;2;{};{};{}m", top[0], top[1], top[2]));
+                fg = Some(top);
+            }
+            try!(out.write_all("\u{2580}".as_bytes()));
+        }
+    }
+    try!(out.write_all(b"\x1b[0m"));
+    Ok(rows)
+}
+
+fn distance(a: [u8; 3], b: [u8; 3]) -> u32 {
+    a.iter().zip(b.iter()).map(|(&a, &b)| {
+        let d = a as i32 - b as i32;
+        (d * d) as u32
+    }).sum()
+}
+
+/// Write `count` copies of a sixel, run-length encoded
+fn sixel_run<W: Write>(out: &mut W, sixel: u8, count: usize) -> io::Result<()> {
+    if count > 3 {
+        write!(out, "!{}{}", count, sixel as char)
+    } else {
+        for _ in 0..count {
+            try!(out.write_all(&[sixel]));
+        }
+        Ok(())
+    }
+}
+
+/// Draw the whole image at the top left of the terminal. Returns the
+/// number of rows of cells it covers, assuming cells 12 pixels high.
+fn sixel<W: Write>(rgba: &[u8], width: usize, height: usize, scale: usize, out: &mut W) -> io::Result<usize> {
+    let w = (width + scale - 1) / scale;
+    let h = (height + scale - 1) / scale;
+
+    // CD+G never shows more than 16 colors, but other images might
+    // not fit in the palette
+    let mut palette: Vec<[u8; 3]> = Vec::new();
+    let mut indices = Vec::with_capacity(w * h);
+    for y in 0..h {
+        for x in 0..w {
+            let color = pixel(rgba, width, height, x * scale, y * scale);
+            let index = match palette.iter().position(|&c| c == color) {
+                Some(index) => index,
+                None if palette.len() < 256 => {
+                    palette.push(color);
+                    palette.len() - 1
+                },
+                None => (0..palette.len()).min_by_key(|&i| distance(palette[i], color)).unwrap(),
+            };
+            indices.push(index as u8);
+        }
+    }
+
+    try!(write!(out, "\x1b[H\x1bP0;1;0q\"1;1;{};{}", w, h));
+    for (i, color) in palette.iter().enumerate() {
+        try!(write!(out, "#{};2;{};{};{}", i,
+                    color[0] as u32 * 100 / 255, color[1] as u32 * 100 / 255, color[2] as u32 * 100 / 255));
+    }
+    for band in 0..(h + 5) / 6 {
+        let top = band * 6;
+        let bottom = min(top + 6, h);
+        let mut first = true;
+        for color in 0..palette.len() {
+            let color = color as u8;
+            if !indices[top * w..bottom * w].contains(&color) {
+                continue;
+            }
+            if !first {
+                // Back to the start of the band
+                try!(out.write_all(b"$"));
+            }
+            first = false;
+            try!(write!(out, "#{}", color));
+            let mut run = (0, 0);
+            for x in 0..w {
+                let mut bits = 0;
+                for y in top..bottom {
+                    if indices[y * w + x] == color {
+                        bits |= 1 << (y - top);
+                    }
+                }
+                let sixel = b'?' + bits;
+                if sixel == run.0 {
+                    run.1 += 1;
+                } else {
+                    try!(sixel_run(out, run.0, run.1));
+                    run = (sixel, 1);
+                }
+            }
+            // Empty sixels at the end of the line needn't be sent
+            if run.0 != b'?' {
+                try!(sixel_run(out, run.0, run.1));
+            }
+        }
+        try!(out.write_all(b"-"));
+    }
+    try!(out.write_all(b"\x1b\\"));
+    Ok((h + 11) / 12)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::{Position, Rectangle};
+
+    fn image(pixels: &[[u8; 3]]) -> Vec<u8> {
+        pixels.iter().flat_map(|px| vec![px[0], px[1], px[2], 255]).collect()
+    }
+
+    const RED: [u8; 3] = [255, 0, 0];
+    const BLUE: [u8; 3] = [0, 0, 255];
+
+    #[test]
+    fn half_blocks() {
+        // 2x4: a red-over-blue column next to a solid blue one, then
+        // a row of each
+        let rgba = image(&[RED, BLUE,
+                           BLUE, BLUE,
+                           RED, RED,
+                           BLUE, BLUE]);
+        let everything = Rectangle::new(Position::new(0, 0), Position::new(2, 4));
+        let mut renderer = TerminalRenderer::new(Mode::HalfBlock);
+        let mut out = Vec::new();
+        renderer.draw(&rgba, 2, 4, everything, &mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(),
+                   "\x1b[2J\x1b[?25l\
+                    \x1b[1;1H\x1b[48;2;0;0;255m\x1b[38;2;255;0;0m\u{2580} \
+                    \x1b[2;1H\x1b[48;2;0;0;255m\x1b[38;2;255;0;0m\u{2580}\u{2580}\
+                    \x1b[0m");
+
+        // Only the cells within the region are drawn again
+        let mut out = Vec::new();
+        renderer.draw(&rgba, 2, 4, Rectangle::new(Position::new(1, 3), Position::new(2, 4)), &mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(),
+                   "\x1b[2;2H\x1b[48;2;0;0;255m\x1b[38;2;255;0;0m\u{2580}\x1b[0m");
+
+        let mut out = Vec::new();
+        renderer.finish(&mut out).unwrap();
+        assert_eq!(out, b"\x1b[0m\x1b[3;1H\x1b[?25h");
+    }
+
+    #[test]
+    fn sixel() {
+        let mut rgba = image(&[RED; 8]);
+        rgba[4..8].copy_from_slice(&[0, 0, 255, 255]);
+        let mut out = Vec::new();
+        super::sixel(&rgba, 1, 8, 1, &mut out).unwrap();
+        // Pixel 1 is blue, the rest red; the second band is only two
+        // pixels high
+        assert_eq!(String::from_utf8(out).unwrap(),
+                   "\x1b[H\x1bP0;1;0q\"1;1;1;8#0;2;100;0;0#1;2;0;0;100\
+                    #0|$#1A-#0B-\x1b\\");
+
+        let everything = Rectangle::new(Position::new(0, 0), Position::new(8, 1));
+        let mut out = Vec::new();
+        let mut renderer = TerminalRenderer::new(Mode::Sixel);
+        renderer.draw(&image(&[RED; 8]), 8, 1, everything, &mut out).unwrap();
+        assert!(String::from_utf8(out).unwrap().contains("#0!8@-"));
+    }
+}
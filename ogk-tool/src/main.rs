@@ -21,7 +21,11 @@ fn main() {
                          .long("mp3")
                          .multiple(true)
                          .number_of_values(1)
-                         .value_name("FILE")))
+                         .value_name("FILE"))
+                    .arg(Arg::with_name("keyframe-interval")
+                         .long("keyframe-interval")
+                         .help("Seconds between CDG keyframes; 0 disables them")
+                         .value_name("SECONDS")))
         .get_matches();
     match matches.subcommand() {
         ("mux", Some(matches)) => {
@@ -38,10 +42,23 @@ fn main() {
                     }
                 }
             }
+            let keyframe_interval = match matches.value_of("keyframe-interval").map(str::parse::<u64>) {
+                None => None,
+                Some(Ok(seconds)) => Some(seconds * 75),
+                Some(Err(e)) => {
+                    println!("Invalid keyframe interval: {}", e);
+                    std::process::exit(1);
+                },
+            };
             if let Some(values) = matches.values_of_os("cdg") {
                 use ogk::cdg::OggCdgCoder;
                 for file in values {
-                    match fs::File::open(file).map(BufReader::new).map(OggCdgCoder::new).map(Box::new) {
+                    let coder = fs::File::open(file).map(BufReader::new).map(OggCdgCoder::new);
+                    let coder = match keyframe_interval {
+                        Some(interval) => coder.map(|c| c.with_keyframe_interval(interval)),
+                        None => coder,
+                    };
+                    match coder.map(Box::new) {
                         Err(e) => {
                             println!("Failed to open CDG file {:?}: {}", file, e);
                             std::process::exit(1);
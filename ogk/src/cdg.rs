@@ -3,15 +3,42 @@ use std::io;
 use std::borrow::Cow;
 use std::cmp::min;
 
+use cdg_parser;
+use cdg_renderer;
 use lz4;
 use ogg;
 
+/// The size of a decompressed keyframe packet
+pub const KEYFRAME_SIZE: usize = 32 + 300 * 216 / 2 + 4;
+
+/// The size of a keyframe from a version 0.0 stream, which lacks the
+/// border color and scroll offset
+const KEYFRAME_SIZE_V0: usize = KEYFRAME_SIZE - 3;
+
+/// The number of low bits of a granule position that hold the sector
+/// number of the last keyframe
+const KEYFRAME_BITS: u64 = 20;
+const KEYFRAME_MASK: u64 = (1 << KEYFRAME_BITS) - 1;
+
+/// The time in µs of the last keyframe before the packet with granule
+/// position `granule`. This is 0 if there hasn't been one, as the
+/// stream starts from a blank screen.
+pub fn keyframe_time(granule: u64) -> u64 {
+    (granule & KEYFRAME_MASK) * 1000_000 / 75
+}
+
 pub struct OggCdgCoder<R> {
     reader: R,
     packetsize: u8,
-    // TODO: add keyframe support
     cur_frame: u64,
     last_keyframe: u64,
+    /// Sectors between keyframes, or 0 to disable them
+    keyframe_interval: u64,
+    /// Tracks the state of the display so that it can be written out as keyframes
+    interp: cdg_renderer::CdgInterpreter,
+    /// Sectors that have been read but not yet encoded, because a
+    /// keyframe had to be sent first
+    pending: Option<Vec<u8>>,
 }
 
 impl <R: Read> OggCdgCoder<R> {
@@ -21,8 +48,129 @@ impl <R: Read> OggCdgCoder<R> {
             packetsize: 75,
             cur_frame: 0,
             last_keyframe: 0,
+            keyframe_interval: 75 * 60, // Once a minute
+            interp: cdg_renderer::CdgInterpreter::new(),
+            pending: None,
+        }
+    }
+
+    /// Set the number of sectors between keyframes; 0 disables
+    /// keyframes entirely. Keyframes are only emitted between
+    /// packets, so the actual interval is rounded up to a whole
+    /// number of packets.
+    pub fn with_keyframe_interval(mut self, sectors: u64) -> Self {
+        self.keyframe_interval = sectors;
+        self
+    }
+
+    fn keyframe_due(&self) -> bool {
+        self.keyframe_interval != 0
+            && self.cur_frame != self.last_keyframe
+            && self.cur_frame - self.last_keyframe >= self.keyframe_interval
+    }
+
+    fn granule(&self) -> u64 {
+        self.cur_frame << KEYFRAME_BITS | self.last_keyframe & KEYFRAME_MASK
+    }
+
+    fn next_keyframe(&mut self) -> io::Result<ogg::Packet> {
+        let content = try!(compress_packet(PacketType::Keyframe, 0, &encode_keyframe(&self.interp.snapshot())));
+        self.last_keyframe = self.cur_frame;
+        Ok(ogg::Packet{
+            content: content,
+            timestamp: self.granule(),
+        })
+    }
+}
+
+fn compress_packet(typ: PacketType, aux: u8, input: &[u8]) -> io::Result<Vec<u8>> {
+    let mut output = Vec::new();
+    output.push(typ.to_u8());
+    output.push(aux);
+
+    let mut encoder = try!(lz4::EncoderBuilder::new()
+                           .level(9)
+                           .checksum(lz4::ContentChecksum::NoChecksum)
+                           .build(output));
+
+    try!(encoder.write_all(input));
+    let (output, result) = encoder.finish();
+    try!(result);
+    Ok(output)
+}
+
+/// Encode the state of a CDG display as the contents of a keyframe
+/// packet, before compression.
+pub fn encode_keyframe(state: &cdg_renderer::Snapshot) -> Vec<u8> {
+    let mut keyframe = Vec::with_capacity(KEYFRAME_SIZE);
+    // The palette is stored as the data sections of the two Load
+    // Palette commands that would load it
+    for offset in [0, 8].iter() {
+        let mut clut = [cdg_parser::RgbColor::from_rgb(0, 0, 0); 8];
+        clut.copy_from_slice(&state.clut[*offset..*offset + 8]);
+        let pack = cdg_parser::encode_subchannel_cmd(&cdg_parser::Command::LoadPalette{offset: *offset as u8, clut: clut});
+        keyframe.extend_from_slice(&pack[4..20]);
+    }
+    keyframe.extend(state.content.chunks(2).map(|px| px[0] << 4 | px[1] & 0xF));
+    keyframe.push(state.transparent.unwrap_or(0xFF));
+    keyframe.push(state.border);
+    keyframe.push(state.offset.0);
+    keyframe.push(state.offset.1);
+    keyframe
+}
+
+/// Decode the contents of a keyframe packet, after decompression.
+/// Returns None if the keyframe does not match the layout in the
+/// specification.
+pub fn decode_keyframe(buf: &[u8]) -> Option<cdg_renderer::Snapshot> {
+    if buf.len() != KEYFRAME_SIZE && buf.len() != KEYFRAME_SIZE_V0 {
+        return None;
+    }
+
+    let mut clut = [cdg_parser::RgbColor::from_rgb(0, 0, 0); 16];
+    for (i, data) in buf[..32].chunks(16).enumerate() {
+        if data.iter().any(|b| b & 0xC0 != 0) {
+            return None;
+        }
+        let mut pack = [0; 24];
+        pack[0] = 9;
+        pack[1] = 30 + i as u8;
+        pack[4..20].copy_from_slice(data);
+        match cdg_parser::decode_subchannel_cmd(&pack) {
+            Some(cdg_parser::Command::LoadPalette{offset, clut: half}) => {
+                let offset = offset as usize;
+                clut[offset..offset + 8].copy_from_slice(&half);
+            },
+            _ => unreachable!(),
         }
     }
+
+    let mut content = Vec::with_capacity(300 * 216);
+    for px in &buf[32..32432] {
+        content.push(px >> 4);
+        content.push(px & 0xF);
+    }
+
+    let transparent = match buf[32432] {
+        color @ 0..=15 => Some(color),
+        _ => None,
+    };
+    let (border, offset) = if buf.len() == KEYFRAME_SIZE {
+        (buf[32433], (buf[32434], buf[32435]))
+    } else {
+        (0, (0, 0))
+    };
+    if border > 15 || offset.0 > 5 || offset.1 > 11 {
+        return None;
+    }
+
+    Some(cdg_renderer::Snapshot{
+        clut: clut,
+        content: content,
+        transparent: transparent,
+        border: border,
+        offset: offset,
+    })
 }
 
 impl <R: Read> ogg::BitstreamCoder for OggCdgCoder<R> {
@@ -34,47 +182,59 @@ impl <R: Read> ogg::BitstreamCoder for OggCdgCoder<R> {
 
         header.extend_from_slice(b"OggCDG\0\0");
         header.push(0); // Major
-        header.push(0); // Minor
+        header.push(1); // Minor
         header.push(Compression::LZ4 as u8); // LZ4
         header.push(self.packetsize-1);
         vec![header]
     }
 
     fn next_frame(&mut self) -> io::Result<Option<ogg::Packet>> {
-        let mut input = Vec::with_capacity(self.packetsize as usize * 96);
-        let mut output = Vec::new();
-        let size = try!(self.reader.by_ref().take(self.packetsize as u64 * 96).read_to_end(&mut input));
-        //let size = try!(self.reader.read(&mut input));
-        if size % 96 != 0 {
-            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Incomplete sector read"));
-        }
+        let input = match self.pending.take() {
+            Some(input) => input,
+            None => {
+                let mut input = Vec::with_capacity(self.packetsize as usize * 96);
+                let size = try!(self.reader.by_ref().take(self.packetsize as u64 * 96).read_to_end(&mut input));
+                //let size = try!(self.reader.read(&mut input));
+                if size % 96 != 0 {
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Incomplete sector read"));
+                }
 
-        if size == 0 {
-            return Ok(None);
+                if size == 0 {
+                    return Ok(None);
+                }
+
+                // A keyframe is only useful if something follows it,
+                // so it is emitted once the next packet's input is in
+                // hand. That input is then held for the next call.
+                if self.keyframe_due() {
+                    let keyframe = try!(self.next_keyframe());
+                    self.pending = Some(input);
+                    return Ok(Some(keyframe));
+                }
+                input
+            }
+        };
+        let sectors = input.len() / 96;
+
+        let output = try!(compress_packet(PacketType::Command, sectors as u8, &input));
+        for sector in input.chunks(96) {
+            for cmd in cdg_parser::SectorIter::new(sector) {
+                self.interp.handle_cmd(cmd);
+            }
         }
-        
-        output.push(0);
-        output.push((size / 96) as u8);
+        // Nobody looks at the dirty region here
+        self.interp.clear_dirty_region();
 
-        let mut encoder = try!(lz4::EncoderBuilder::new()
-                               .level(9)
-                               .checksum(lz4::ContentChecksum::NoChecksum)
-                               .build(output));
-        
-        try!(encoder.write_all(&input));
-        let (output, result) = encoder.finish();
-        try!(result);
-        
-        self.cur_frame += size as u64 / 96;
+        self.cur_frame += sectors as u64;
 
         Ok(Some(ogg::Packet{
             content: output,
-            timestamp: self.cur_frame << 32 | self.last_keyframe,
+            timestamp: self.granule(),
         }))
     }
 
     fn map_granule(&self, granule: u64) -> u64 {
-        (granule >> 32) * 1000_000 / 75
+        (granule >> KEYFRAME_BITS) * 1000_000 / 75
     }
 }
 #[derive(Copy,Clone,PartialEq,Debug)]
@@ -127,7 +287,7 @@ impl CdgHeader {
         let spp = min(self.sectors_per_packet - 1, 255) as u8;
         vec![
             b'O', b'g', b'g', b'C', b'D', b'G', 0, 0,
-            0, 0, self.compression as u8, spp,
+            0, 1, self.compression as u8, spp,
         ]
     }
 
@@ -168,3 +328,222 @@ impl CdgHeader {
         self.decompress_packet(&buf[2..]).ok().map(|pkt| (typ, pkt))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cdg_parser;
+    use cdg_renderer;
+    use ogg::{self, BitstreamCoder};
+    use std::cell::RefCell;
+    use std::io::Cursor;
+    use std::rc::Rc;
+
+    /// A stream that draws something different in every sector
+    fn test_stream(sectors: usize) -> Vec<u8> {
+        let mut writer = cdg_parser::SubchannelStreamWriter::new(Vec::new());
+        for i in 0..sectors {
+            let tile = cdg_parser::Tile{
+                pos: ((i % 50) as u8, (i / 50 % 18) as u8),
+                color: ((i % 16) as u8, (i / 16 % 16) as u8),
+                content: [(i % 64) as u8; 12],
+                channel: 0,
+            };
+            let mut cmds = vec![cdg_parser::Command::TileXOR{tile: tile}];
+            if i % 40 == 0 {
+                cmds.push(cdg_parser::Command::BorderPreset{color: (i % 16) as u8});
+            }
+            writer.write_sector(&cmds).unwrap();
+        }
+        writer.into_inner()
+    }
+
+    fn keyframe_at(stream: &[u8], sector: usize) -> Vec<u8> {
+        let mut interp = cdg_renderer::CdgInterpreter::new();
+        for sector in stream[..sector * 96].chunks(96) {
+            for cmd in cdg_parser::SectorIter::new(sector) {
+                interp.handle_cmd(cmd);
+            }
+        }
+        encode_keyframe(&interp.snapshot())
+    }
+
+    #[test]
+    fn keyframe_layout() {
+        let stream = test_stream(10);
+        let keyframe = keyframe_at(&stream, 0);
+        assert_eq!(keyframe.len(), KEYFRAME_SIZE);
+        // Nothing has been drawn and there's no transparency
+        assert!(keyframe[32..32432].iter().all(|&b| b == 0));
+        assert_eq!(&keyframe[32432..], &[0xFF, 0, 0, 0]);
+
+        let keyframe = keyframe_at(&stream, 10);
+        // Sector 9 XORed color 9 into the top left pixels of tile (9, 0)
+        assert_eq!(keyframe[32 + 9 * 3], 0x99);
+    }
+
+    #[test]
+    fn keyframe_round_trip() {
+        use cdg_parser::ScrollCommand::{NW, SE};
+        let stream = test_stream(1000);
+        let mut interp = cdg_renderer::CdgInterpreter::new();
+        for sector in stream.chunks(96) {
+            for cmd in cdg_parser::SectorIter::new(sector) {
+                interp.handle_cmd(cmd);
+            }
+        }
+        interp.handle_cmd(cdg_parser::Command::Scroll{color: None, cmd: (NW, SE), offset: (3, 10)});
+        interp.handle_cmd(cdg_parser::Command::SetTransparent{color: 7});
+        let mut clut = [cdg_parser::RgbColor::from_rgb(0, 0, 0); 8];
+        for (i, color) in clut.iter_mut().enumerate() {
+            *color = cdg_parser::RgbColor::from_rgb(i as u8 * 32, 255 - i as u8 * 16, 0x50);
+        }
+        interp.handle_cmd(cdg_parser::Command::LoadPalette{offset: 8, clut: clut});
+
+        let snapshot = interp.snapshot();
+        assert_eq!(decode_keyframe(&encode_keyframe(&snapshot)), Some(snapshot));
+    }
+
+    #[test]
+    fn invalid_keyframes() {
+        let keyframe = keyframe_at(&test_stream(100), 100);
+        assert!(decode_keyframe(&keyframe).is_some());
+        assert!(decode_keyframe(&keyframe[..1000]).is_none());
+        assert!(decode_keyframe(&[&keyframe[..], &[0]].concat()).is_none());
+
+        // Old keyframes lack the border and scroll offset
+        let old = decode_keyframe(&keyframe[..KEYFRAME_SIZE_V0]).unwrap();
+        assert_eq!((old.border, old.offset), (0, (0, 0)));
+
+        for &(pos, value) in &[(0, 0x40), (32433, 16), (32434, 6), (32435, 12)] {
+            let mut bad = keyframe.clone();
+            bad[pos] = value;
+            assert!(decode_keyframe(&bad).is_none(), "byte {} = {}", pos, value);
+        }
+    }
+
+    #[test]
+    fn keyframes_are_generated() {
+        let stream = test_stream(750);
+        let mut coder = OggCdgCoder::new(&stream[..]).with_keyframe_interval(150);
+        let header = CdgHeader::from_bytes(&coder.headers()[0]).unwrap();
+
+        let mut sector = 0;
+        let mut last_keyframe = 0;
+        let mut keyframes = Vec::new();
+        while let Some(packet) = coder.next_frame().unwrap() {
+            let (typ, content) = header.decode_packet(&packet.content).unwrap();
+            match typ {
+                PacketType::Command => {
+                    sector += packet.content[1] as u64;
+                    assert_eq!(content.len() as u64, packet.content[1] as u64 * 96);
+                },
+                PacketType::Keyframe => {
+                    last_keyframe = sector;
+                    keyframes.push(sector);
+                    assert_eq!(&content[..], &keyframe_at(&stream, sector as usize)[..]);
+                },
+                PacketType::Other(n) => panic!("Unexpected packet type {}", n),
+            }
+            assert_eq!(packet.timestamp, sector << 20 | last_keyframe);
+            assert_eq!(coder.map_granule(packet.timestamp), sector * 1000_000 / 75);
+        }
+        assert_eq!(sector, 750);
+        // No keyframe at the very end; nothing would use it
+        assert_eq!(keyframes, vec![150, 300, 450, 600]);
+    }
+
+    /// Logs the first sector and type of each packet it is given
+    struct LoggingDecoder {
+        log: Rc<RefCell<Vec<(u64, PacketType)>>>,
+    }
+
+    impl ogg::BitstreamDecoder for LoggingDecoder {
+        fn map_granule(&self, granule: u64) -> u64 { (granule >> KEYFRAME_BITS) * 1000_000 / 75 }
+        fn num_headers(&self) -> usize { 1 }
+        fn process_header(&mut self, _: &[u8]) { }
+        fn process_packet(&mut self, packet: &[u8], last_granule: u64) -> u64 {
+            let sector = last_granule >> KEYFRAME_BITS;
+            let typ = PacketType::from_u8(packet[0]);
+            self.log.borrow_mut().push((sector, typ));
+            match typ {
+                PacketType::Keyframe => sector << KEYFRAME_BITS | sector,
+                _ => (sector + packet[1] as u64) << KEYFRAME_BITS | last_granule & KEYFRAME_MASK,
+            }
+        }
+        fn restart_time(&self, granule: u64) -> Option<u64> { Some(keyframe_time(granule)) }
+        fn notice_gap(&mut self) { }
+        fn finish(&mut self) { }
+    }
+
+    /// Mux `sectors` of CDG with keyframes every `interval` sectors,
+    /// seek to each of `targets`, in sectors, and demux the rest of the
+    /// file. Returns the sector that playback resumed from and the
+    /// packets that were delivered after each seek.
+    fn seek_log(sectors: usize, interval: u64, targets: &[u64]) -> Vec<(u64, Vec<(u64, PacketType)>)> {
+        let mut mux = ogg::OgkMux::new();
+        mux.add_stream(Box::new(OggCdgCoder::new(Cursor::new(test_stream(sectors))).with_keyframe_interval(interval)));
+        let mut file = Vec::new();
+        mux.write_to(&mut file).unwrap();
+
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let decoder_log = log.clone();
+        let mut demux = ogg::OggDemux::new(Cursor::new(file), move |header| {
+            CdgHeader::from_bytes(header).map(|_| {
+                (Box::new(LoggingDecoder{log: decoder_log.clone()}) as Box<ogg::BitstreamDecoder>, ())
+            })
+        }).unwrap();
+        targets.iter().map(|&target| {
+            log.borrow_mut().clear();
+            let resumed = demux.seek_to(target * 1000_000 / 75).unwrap();
+            while !demux.is_eof() {
+                demux.pump_page().unwrap();
+            }
+            (resumed * 75 / 1000_000, log.borrow().clone())
+        }).collect()
+    }
+
+    #[test]
+    fn seeks_start_from_a_keyframe() {
+        // The file has to span a few pages for there to be anywhere to
+        // seek to
+        for (resumed, log) in seek_log(18000, 1500, &[15000, 9000, 1510, 17999, 100]) {
+            assert_eq!(log.last(), Some(&(17925, PacketType::Command)));
+            if resumed < 1500 {
+                // There is nothing before the first keyframe to go
+                // back to but the start
+                assert_eq!(log[0], (0, PacketType::Command));
+                continue;
+            }
+            // The keyframe that the resume point refers back to is
+            // delivered, without starting over
+            assert!(log[0].0 > 0);
+            assert!(log.iter().any(|&(sector, typ)| {
+                typ == PacketType::Keyframe && sector <= resumed && resumed - sector <= 1500
+            }), "no keyframe for playback from {}", resumed);
+            // Anything before the first keyframe delivered is from
+            // before it, and can be dropped
+            let first_keyframe = log.iter().position(|&(_, typ)| typ == PacketType::Keyframe).unwrap();
+            assert!(log[..first_keyframe].iter().all(|&(sector, _)| sector < log[first_keyframe].0));
+        }
+    }
+
+    #[test]
+    fn seeks_without_keyframes_start_over() {
+        for (resumed, log) in seek_log(18000, 0, &[15000, 9000]) {
+            assert!(resumed > 0);
+            assert_eq!(log[0], (0, PacketType::Command));
+            assert_eq!(log.len(), 240);
+        }
+    }
+
+    #[test]
+    fn keyframes_disabled() {
+        let stream = test_stream(750);
+        let mut coder = OggCdgCoder::new(&stream[..]).with_keyframe_interval(0);
+        while let Some(packet) = coder.next_frame().unwrap() {
+            assert_eq!(packet.content[0], PacketType::Command.to_u8());
+            assert_eq!(packet.timestamp & KEYFRAME_MASK, 0);
+        }
+    }
+}
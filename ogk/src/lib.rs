@@ -4,6 +4,7 @@
 extern crate byteorder;
 extern crate lz4;
 extern crate cdg as cdg_parser;
+extern crate cdg_renderer;
 extern crate rand;
 
 pub mod mp3;
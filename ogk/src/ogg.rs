@@ -1,6 +1,6 @@
 use std::error::Error;
 use std::fmt::{self, Display};
-use std::io::{self,Write,Read};
+use std::io::{self,Write,Read,Seek,SeekFrom};
 use std::collections;
 use rand;
 
@@ -535,6 +535,15 @@ pub trait BitstreamDecoder {
     /// Called for each packet in the stream. Returns the granule position of this packet
     fn process_packet(&mut self, packet: &[u8], last_granule: u64) -> u64;
 
+    /// For streams whose packets build on the ones before them, the
+    /// time in µs that decoding has to start from to get the right
+    /// result at `granule`, such as the time of the last keyframe.
+    /// Seeks go back far enough to pick the stream up from there.
+    /// None if each packet stands on its own.
+    fn restart_time(&self, _granule: u64) -> Option<u64> {
+        None
+    }
+
     /// There was a gap in the underlying page stream. May be called
     /// multiple times in a row; should therefore be idempotent.
     // This will happen when we regain sync on a page that contains a
@@ -558,11 +567,18 @@ struct StreamState<Desc> {
     /// The last page sequence number seen
     last_page_seq: u32,
 
+    /// The sequence number of the BOS page
+    first_page_seq: u32,
+
     /// Number of remaining header packets
     headers_remaining: usize,
 
     /// EOS packet seen
     finished: bool,
+
+    /// Set after a seek to the granule position of the page that the
+    /// stream is to be picked up from, until that page turns up
+    resync: Option<u64>,
     
     /// This holds user data associated with the stream, such as decode buffers
     user_data: Desc,
@@ -570,6 +586,15 @@ struct StreamState<Desc> {
 
 impl <Desc> StreamState<Desc> {
     pub fn process_page(&mut self, page: RefPage) -> Result<(), StreamError> {
+        if let Some(granule) = self.resync {
+            self.resync_page(page, granule);
+            return Ok(());
+        }
+        if self.finished {
+            // Only a seek sends us pages after the end
+            return Ok(());
+        }
+
         let had_gap = page.page_sequence != self.last_page_seq + 1;
         if had_gap {
             if page.segment_table.iter().filter(|x| **x != 255).count() == 0 {
@@ -598,6 +623,8 @@ impl <Desc> StreamState<Desc> {
             } else {
                 // Get a reference to the packet body
                 let packet_ref = if packet_continued {
+                    // The last piece is on this page
+                    self.partial.extend_from_slice(packet);
                     &self.partial
                 } else {
                     packet
@@ -606,7 +633,7 @@ impl <Desc> StreamState<Desc> {
                 // process it
                 if self.headers_remaining > 0 {
                     self.headers_remaining -= 1;
-                    self.decoder.process_header(packet);
+                    self.decoder.process_header(packet_ref);
                 } else {
                     self.hwm = self.decoder.process_packet(packet_ref, self.hwm);
                 }
@@ -620,6 +647,32 @@ impl <Desc> StreamState<Desc> {
 
         Ok(())
     }
+
+    /// Handle a page after a seek. Pages before the one with granule
+    /// position `granule` are skipped. The packets that complete on
+    /// that page are dropped, as we have no way to know their
+    /// timestamps; the page's granule position is used to pick up the
+    /// stream from the next packet.
+    fn resync_page(&mut self, page: RefPage, granule: u64) {
+        if page.granule_position == !0 || page.granule_position < granule {
+            // No packet finishes on this page, or it is too early
+            return;
+        }
+        self.resync = None;
+        self.last_page_seq = page.page_sequence;
+        self.hwm = page.granule_position;
+        self.partial.clear();
+        // As some packet finished on this page, an incomplete last
+        // packet must have started here as well.
+        if let Some((packet, false)) = page.packets().last() {
+            self.partial.extend_from_slice(packet);
+        }
+
+        if page.flags.intersects(PAGE_EOS) {
+            self.decoder.finish();
+            self.finished = true;
+        }
+    }
 }
 
 
@@ -628,6 +681,8 @@ pub struct OggPageSource<R> {
     reader: R,
     dead_bytes: usize,
     eof: bool,
+    /// Offset in the underlying stream of the last page returned
+    page_offset: u64,
 }
 
 // TODO: When nonlexical lifetimes land, kill this with fire.
@@ -640,24 +695,26 @@ impl <R: Read> OggPageSource<R> {
         loop {
             self.buffer.consume(self.dead_bytes);
             self.dead_bytes = 0;
-            if try!(self.buffer.fill_max(&mut self.reader)) == 0 {
+            try!(self.buffer.fill_max(&mut self.reader));
+            if self.buffer.is_empty() {
                 self.eof = true;
                 return Ok(None);
             }
-            for i in 0..self.buffer.len() - 5 {
+            // The buffer is either full or holds everything up to the
+            // end of the stream, and every page fits in the buffer, so
+            // parsing at i == 0 can't run out of data unless the stream
+            // has been truncated.
+            for i in 0..self.buffer.len() {
                 // Try to parse...  The unsafe unalias simply divorces
                 // the borrow of buffer from the lifetime of this
                 // function, so that the loop still works.
                 match RefPage::parse(unsafe{unalias(&self.buffer[i..])}) {
-                    ParseResult::No => { println!("Skipping"); continue; },
+                    ParseResult::No => continue,
                     ParseResult::InsufficientNoms(_) => {
                         if i == 0 {
-                            if self.buffer.is_empty() {
-                                self.eof = true;
-                                return Ok(None);
-                            }
                             //println!("EOF at offset {}; need {} had {} (contents {})", self.buffer.offset(), n, self.buffer.len(), &self.buffer[..].iter().map(|x| format!("{:02x}", x)).collect::<Vec<String>>().join(""));
                             // We'll never be able to complete this page
+                            self.eof = true;
                             return Err(StreamError::Io(io::Error::new(io::ErrorKind::UnexpectedEof, "Incomplete page")));
                         }
                         self.dead_bytes = i;
@@ -666,6 +723,7 @@ impl <R: Read> OggPageSource<R> {
                     ParseResult::Yay(n, page_res) => {
                         // handle the page
                         self.dead_bytes = i + n;
+                        self.page_offset = (self.buffer.offset() - self.buffer.len() + i) as u64;
                         return Ok(Some(page_res));
                     }
                 }
@@ -673,11 +731,29 @@ impl <R: Read> OggPageSource<R> {
         }
     }
 
+    /// The offset in the underlying stream at which the page most
+    /// recently returned by `next_page` starts
+    pub fn page_offset(&self) -> u64 {
+        self.page_offset
+    }
+
     pub fn is_eof(&self) -> bool {
         self.eof
     }
 }
 
+impl <R: Read + Seek> OggPageSource<R> {
+    /// Continue reading pages from `offset`, which need not be at a
+    /// page boundary.
+    pub fn seek(&mut self, offset: u64) -> Result<(), StreamError> {
+        try!(self.reader.seek(SeekFrom::Start(offset)));
+        self.buffer.reset(offset as usize);
+        self.dead_bytes = 0;
+        self.eof = false;
+        Ok(())
+    }
+}
+
 pub type StreamInitFn<StreamDesc> = Fn(&[u8]) -> Option<(Box<BitstreamDecoder>, StreamDesc)>;
 
 struct StreamMapper<StreamDesc>{
@@ -730,9 +806,11 @@ impl<StreamDesc> StreamMapper<StreamDesc> {
                             partial: Vec::new(),
                             hwm: 0,
                             last_page_seq: page.page_sequence,
+                            first_page_seq: page.page_sequence,
                             user_data: desc,
                             headers_remaining: num_headers - 1,
                             finished: page.flags.intersects(PAGE_EOS),
+                            resync: None,
                         });
                         return Ok(());
                     } else {
@@ -769,8 +847,24 @@ impl<StreamDesc> StreamMapper<StreamDesc> {
 pub struct OggDemux<R, StreamDesc> {
     source: OggPageSource<R>,
     mapper: StreamMapper<StreamDesc>,
+    /// Offset of the first page after the BOS pages
+    data_start: u64,
+}
+
+/// A page considered as a place to resume demuxing after a seek
+struct SeekPoint {
+    /// Offset of the start of the page
+    offset: u64,
+    /// Time at which the page ends, in µs
+    time: u64,
+    granule: u64,
+    eos: bool,
 }
 
+/// Once a seek has narrowed the search down to this many bytes, the
+/// remaining pages are simply scanned in order.
+const SEEK_SCAN_SIZE: u64 = 65536;
+
 impl <R: Read, StreamDesc> OggDemux<R, StreamDesc> {
     pub fn new<F>(reader: R, stream_mapper: F) -> Result<Self, StreamError>
         where F: 'static + Fn(&[u8]) -> Option<(Box<BitstreamDecoder>, StreamDesc)>
@@ -781,6 +875,7 @@ impl <R: Read, StreamDesc> OggDemux<R, StreamDesc> {
                 reader: reader,
                 dead_bytes: 0,
                 eof: false,
+                page_offset: 0,
             },
             mapper: StreamMapper{
                 streams: collections::HashMap::new(),
@@ -789,6 +884,7 @@ impl <R: Read, StreamDesc> OggDemux<R, StreamDesc> {
                 hwm: 0,
                 stream_init: Box::new(stream_mapper),
             },
+            data_start: 0,
         };
 
         //while !demux.mapper.headers_read && !demux.source.is_eof() {
@@ -796,6 +892,7 @@ impl <R: Read, StreamDesc> OggDemux<R, StreamDesc> {
         //}
 
         try!(demux.internal_pump_until(|ogg| ogg.mapper.headers_read));
+        demux.data_start = demux.source.page_offset();
 
         Ok(demux)
     }
@@ -837,6 +934,157 @@ impl <R: Read, StreamDesc> OggDemux<R, StreamDesc> {
     }
 }
 
+impl <R: Read + Seek, StreamDesc> OggDemux<R, StreamDesc> {
+    /// Find the first page of stream `serial` at or after `offset`
+    /// that has a granule position.
+    fn probe_from(&mut self, serial: u32, offset: u64) -> Result<Option<SeekPoint>, StreamError> {
+        try!(self.source.seek(offset));
+        self.next_probe(serial)
+    }
+
+    fn next_probe(&mut self, serial: u32) -> Result<Option<SeekPoint>, StreamError> {
+        loop {
+            let (page_serial, granule, eos) = match try!(self.source.next_page()) {
+                Some(page) => (page.stream_serial, page.granule_position, page.flags.intersects(PAGE_EOS)),
+                None => return Ok(None),
+            };
+            if page_serial != serial || granule == !0 {
+                continue;
+            }
+            return Ok(Some(SeekPoint{
+                offset: self.source.page_offset(),
+                time: self.mapper.streams[&serial].decoder.map_granule(granule),
+                granule: granule,
+                eos: eos,
+            }));
+        }
+    }
+
+    /// Find the last page of stream `serial` that ends before `time`
+    fn find_seek_point(&mut self, serial: u32, time: u64) -> Result<Option<SeekPoint>, StreamError> {
+        // Pages at or after hi all end at or after time. The page at
+        // lo ends before time, unless lo is the start of the data.
+        let mut lo = self.data_start;
+        let mut hi = try!(self.source.reader.seek(SeekFrom::End(0)));
+        let mut found = None;
+
+        while hi > lo + SEEK_SCAN_SIZE {
+            let mid = lo + (hi - lo) / 2;
+            match try!(self.probe_from(serial, mid)) {
+                Some(point) if point.time < time => {
+                    lo = point.offset;
+                    found = Some(point);
+                },
+                _ => hi = mid,
+            }
+        }
+
+        // Finish with a linear scan
+        try!(self.source.seek(lo));
+        while let Some(point) = try!(self.next_probe(serial)) {
+            if point.time >= time {
+                break;
+            }
+            found = Some(point);
+        }
+        Ok(found)
+    }
+
+    /// Seek to a time in µs. Each stream is picked up from its last
+    /// page that ends before `time`, or for a stream with a
+    /// `restart_time`, from its last page that ends before then; a
+    /// stream with no such page starts over from the beginning.
+    /// Demuxing resumes from the earliest of these pages. The first
+    /// page of each stream is used to recover the stream's position,
+    /// and decoding continues with the packet after it. Seeking to a
+    /// time before some stream's first page ends restarts all of the
+    /// streams from the beginning, and seeking past the end of every
+    /// stream leaves the demuxer at the end of the file, as of the time
+    /// the last stream ended.
+    ///
+    /// Every stream's decoder is notified of the discontinuity with
+    /// `notice_gap`. Returns the time that playback resumes from,
+    /// which is at or before `time`. Streams picked up from their
+    /// restart time have packets from before then as well.
+    pub fn seek_to(&mut self, time: u64) -> Result<u64, StreamError> {
+        use std::cmp::{max, min};
+        let serials : Vec<u32> = self.mapper.streams.keys().cloned().collect();
+        let mut offset = !0;
+        let mut resume_time = Some(!0);
+        let mut ended = collections::HashMap::new();
+        // When the streams that have ended did
+        let mut end_time = 0;
+        // The granule position of the page that each stream is picked
+        // up from. Streams that aren't here or in ended start over.
+        let mut starts = collections::HashMap::new();
+        for serial in serials {
+            let point = match try!(self.find_seek_point(serial, time)) {
+                // This stream finished before time, so there is
+                // nothing left of it to resume
+                Some(ref point) if point.eos => {
+                    ended.insert(serial, point.granule);
+                    end_time = max(end_time, point.time);
+                    continue;
+                },
+                Some(point) => point,
+                None => {
+                    resume_time = None;
+                    continue;
+                },
+            };
+            resume_time = resume_time.map(|resume_time| min(resume_time, point.time));
+            let restart = self.mapper.streams[&serial].decoder.restart_time(point.granule);
+            let start = match restart {
+                Some(restart) => try!(self.find_seek_point(serial, restart)),
+                None => Some(point),
+            };
+            match start {
+                Some(start) => {
+                    offset = min(offset, start.offset);
+                    starts.insert(serial, start.granule);
+                },
+                None => offset = self.data_start,
+            }
+        }
+
+        let (offset, resume_time) = match resume_time {
+            Some(resume_time) if offset != !0 => (offset, resume_time),
+            // Every stream ended before time, so there is nothing to
+            // pick up
+            Some(_) if !ended.is_empty() => (try!(self.source.reader.seek(SeekFrom::End(0))), end_time),
+            _ => {
+                ended.clear();
+                starts.clear();
+                (self.data_start, 0)
+            },
+        };
+        try!(self.source.seek(offset));
+
+        for (serial, stream) in &mut self.mapper.streams {
+            stream.partial.clear();
+            stream.decoder.notice_gap();
+            if let Some(&granule) = ended.get(serial) {
+                stream.hwm = granule;
+                stream.finished = true;
+                stream.resync = None;
+                continue;
+            }
+            stream.hwm = 0;
+            stream.finished = false;
+            match starts.get(serial) {
+                Some(&granule) => stream.resync = Some(granule),
+                None => {
+                    stream.last_page_seq = stream.first_page_seq;
+                    stream.headers_remaining = stream.decoder.num_headers() - 1;
+                    stream.resync = None;
+                },
+            }
+        }
+        self.mapper.hwm = resume_time;
+        Ok(resume_time)
+    }
+}
+
 pub struct DemuxStreams<'a, Desc: 'a>(collections::hash_map::IterMut<'a, u32, StreamState<Desc>>);
 
 impl <'a, Desc> Iterator for DemuxStreams<'a, Desc> {
@@ -848,3 +1096,159 @@ impl <'a, Desc> Iterator for DemuxStreams<'a, Desc> {
 }
 
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::io::Cursor;
+    use std::rc::Rc;
+    use byteorder::{LittleEndian,ByteOrder};
+
+    /// Produces `count` packets of `size` bytes, each `step` µs long
+    /// and starting with its own granule position.
+    struct CountingCoder {
+        tag: u8,
+        step: u64,
+        size: usize,
+        count: u64,
+        next: u64,
+    }
+
+    impl BitstreamCoder for CountingCoder {
+        fn headers(&self) -> Vec<Vec<u8>> {
+            vec![vec![self.tag]]
+        }
+
+        fn next_frame(&mut self) -> io::Result<Option<Packet>> {
+            if self.next == self.count {
+                return Ok(None);
+            }
+            self.next += 1;
+            let mut content = vec![self.tag; self.size];
+            LittleEndian::write_u64(&mut content[..8], self.next);
+            Ok(Some(Packet{content: content, timestamp: self.next}))
+        }
+
+        fn map_granule(&self, granule: u64) -> u64 { granule * self.step }
+    }
+
+    #[derive(Default)]
+    struct Log {
+        packets: Vec<u64>,
+        gaps: usize,
+    }
+
+    struct CountingDecoder {
+        step: u64,
+        size: usize,
+        log: Rc<RefCell<Log>>,
+    }
+
+    impl BitstreamDecoder for CountingDecoder {
+        fn map_granule(&self, granule: u64) -> u64 { granule * self.step }
+        fn num_headers(&self) -> usize { 1 }
+        fn process_header(&mut self, _: &[u8]) { }
+        fn process_packet(&mut self, packet: &[u8], last_granule: u64) -> u64 {
+            let granule = last_granule + 1;
+            assert_eq!(packet.len(), self.size);
+            assert_eq!(LittleEndian::read_u64(&packet[..8]), granule);
+            self.log.borrow_mut().packets.push(granule);
+            granule
+        }
+        fn notice_gap(&mut self) {
+            self.log.borrow_mut().gaps += 1;
+        }
+        fn finish(&mut self) { }
+    }
+
+    // Stream 0 has 1ms packets and lasts 5s; stream 1 has 10ms
+    // packets and lasts 4s
+    const STEPS: [u64; 2] = [1000, 10_000];
+    const COUNTS: [u64; 2] = [5000, 400];
+    const SIZES: [usize; 2] = [100, 700];
+
+    fn test_file() -> Vec<u8> {
+        let mut mux = OgkMux::new();
+        mux.add_stream(Box::new(CountingCoder{tag: 0, step: STEPS[0], size: SIZES[0], count: COUNTS[0], next: 0}));
+        mux.add_stream(Box::new(CountingCoder{tag: 1, step: STEPS[1], size: SIZES[1], count: COUNTS[1], next: 0}));
+        let mut buf = Vec::new();
+        mux.write_to(&mut buf).unwrap();
+        buf
+    }
+
+    fn open(file: Vec<u8>) -> (OggDemux<Cursor<Vec<u8>>, usize>, [Rc<RefCell<Log>>; 2]) {
+        let logs = [Rc::new(RefCell::new(Log::default())), Rc::new(RefCell::new(Log::default()))];
+        let init_logs = logs.clone();
+        let demux = OggDemux::new(Cursor::new(file), move |header| {
+            let tag = header[0] as usize;
+            Some((Box::new(CountingDecoder{
+                step: STEPS[tag],
+                size: SIZES[tag],
+                log: init_logs[tag].clone(),
+            }) as Box<BitstreamDecoder>, tag))
+        }).unwrap();
+        (demux, logs)
+    }
+
+    fn run_to_end(demux: &mut OggDemux<Cursor<Vec<u8>>, usize>) {
+        while !demux.is_eof() {
+            demux.pump_page().unwrap();
+        }
+    }
+
+    #[test]
+    fn demux_whole_file() {
+        let file = test_file();
+        assert!(file.len() > 4 * 65536);
+        let (mut demux, logs) = open(file);
+        run_to_end(&mut demux);
+        for (log, &count) in logs.iter().zip(COUNTS.iter()) {
+            let log = log.borrow();
+            assert_eq!(log.packets, (1..count + 1).collect::<Vec<_>>());
+            assert_eq!(log.gaps, 0);
+        }
+    }
+
+    #[test]
+    fn seek() {
+        let (mut demux, logs) = open(test_file());
+        for &target in &[2_500_000, 4_999_000, 1_000_000, 0, 6_000_000, 3_333_333, 4_500_000, 10_000] {
+            for log in &logs {
+                *log.borrow_mut() = Log::default();
+            }
+            let resumed = demux.seek_to(target).unwrap();
+            assert!(resumed <= target);
+            run_to_end(&mut demux);
+
+            for (i, log) in logs.iter().enumerate() {
+                let log = log.borrow();
+                assert_eq!(log.gaps, 1);
+                if target >= COUNTS[i] * STEPS[i] {
+                    assert_eq!(log.packets, vec![]);
+                    continue;
+                }
+                let first = log.packets[0];
+                // The packet containing the target time must be decoded
+                assert!((first - 1) * STEPS[i] <= target,
+                        "stream {} resumed at packet {} for target {}", i, first, target);
+                assert!(first * STEPS[i] >= resumed);
+                assert_eq!(log.packets, (first..COUNTS[i] + 1).collect::<Vec<_>>());
+            }
+        }
+    }
+
+    #[test]
+    fn seek_past_the_end() {
+        let (mut demux, logs) = open(test_file());
+        for log in &logs {
+            *log.borrow_mut() = Log::default();
+        }
+        assert_eq!(demux.seek_to(6_000_000).unwrap(), COUNTS[0] * STEPS[0]);
+        demux.pump_page().unwrap();
+        assert!(demux.is_eof());
+        for log in &logs {
+            assert_eq!(log.borrow().packets, vec![]);
+        }
+    }
+}
@@ -99,6 +99,14 @@ impl ShiftBuffer {
     pub fn offset(&self) -> usize {
         self.passed_data
     }
+
+    /// Discard the content of the buffer. The next byte read into the
+    /// buffer is taken to be at `offset` in the underlying stream.
+    pub fn reset(&mut self, offset: usize) {
+        self.rptr = 0;
+        self.wptr = 0;
+        self.passed_data = offset;
+    }
 }
 
 
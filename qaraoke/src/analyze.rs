@@ -0,0 +1,178 @@
+//! Loudness analysis. Each song is decoded in full, as fast as it
+//! will go, and its integrated loudness and true peak are written to
+//! a sidecar file next to it. The player reads that back to bring
+//! every song to the same loudness.
+
+use std::error::Error;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use clap;
+use glium;
+
+use codec;
+use dsp::loudness::LoudnessMeter;
+use rt;
+use types::SAMPLE_RATE;
+use KaraokeSource;
+
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub struct Analysis {
+    /// In LUFS
+    pub integrated: f64,
+    /// In dBTP
+    pub true_peak: f64,
+}
+
+/// Where the analysis of `song` is kept: alongside it, with
+/// ".loudness" added to the name
+pub fn sidecar_path(song: &Path) -> PathBuf {
+    let mut name = song.as_os_str().to_owned();
+    name.push(".loudness");
+    PathBuf::from(name)
+}
+
+impl Analysis {
+    /// Read the analysis of `song`, if it has been analyzed
+    pub fn load(song: &Path) -> io::Result<Option<Self>> {
+        let mut text = String::new();
+        match fs::File::open(sidecar_path(song)) {
+            Ok(mut file) => try!(file.read_to_string(&mut text)),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        match Self::parse(&text) {
+            Some(analysis) => Ok(Some(analysis)),
+            None => Err(io::Error::new(io::ErrorKind::InvalidData, "Malformed loudness analysis")),
+        }
+    }
+
+    pub fn save(&self, song: &Path) -> io::Result<()> {
+        let mut file = try!(fs::File::create(sidecar_path(song)));
+        file.write_all(self.serialize().as_bytes())
+    }
+
+    /// The sidecar format is one "NAME VALUE" pair per line
+    fn parse(text: &str) -> Option<Self> {
+        let (mut integrated, mut true_peak) = (None, None);
+        for line in text.lines() {
+            let mut fields = line.split_whitespace();
+            let field = match fields.next() {
+                Some("integrated") => &mut integrated,
+                Some("true-peak") => &mut true_peak,
+                _ => continue,
+            };
+            *field = fields.next().and_then(|value| value.parse().ok());
+        }
+        match (integrated, true_peak) {
+            (Some(integrated), Some(true_peak)) => Some(Analysis{
+                integrated: integrated,
+                true_peak: true_peak,
+            }),
+            _ => None,
+        }
+    }
+
+    fn serialize(&self) -> String {
+        format!("integrated {:.2}\ntrue-peak {:.2}\n", self.integrated, self.true_peak)
+    }
+
+    /// The linear gain that brings the song to `target` LUFS. Peaks
+    /// that this pushes too high are left to the limiter.
+    pub fn gain(&self, target: f64) -> f32 {
+        10f64.powf((target - self.integrated) / 20.) as f32
+    }
+}
+
+pub fn analyze<R: Read>(input: R, options: codec::Options) -> Result<Analysis, Box<Error>> {
+    let mut source : KaraokeSource<R, glium::Frame> = try!(KaraokeSource::from_stream(input, options));
+    let mut acodec = match source.audio.take() {
+        Some(acodec) => acodec,
+        None => return Err(From::from("The file has no usable audio stream")),
+    };
+    let (mut audio_rd, audio_wr) = rt::ringbuffer::new(SAMPLE_RATE as usize);
+    acodec.set_ringbuffer(audio_wr);
+
+    let mut meter = LoudnessMeter::new();
+    let mut samples = Vec::with_capacity(SAMPLE_RATE as usize);
+    let mut time = 0;
+    loop {
+        // A second at a time, so that the decoded audio fits in the
+        // ring buffer
+        time += 1_000_000;
+        try!(source.demux.pump_until(time));
+        loop {
+            acodec.do_needful();
+            samples.clear();
+            samples.extend(audio_rd.iter());
+            if samples.is_empty() {
+                break;
+            }
+            meter.add(&samples);
+        }
+        if source.demux.is_eof() {
+            break;
+        }
+    }
+
+    match meter.integrated() {
+        Some(integrated) => Ok(Analysis{
+            integrated: integrated,
+            true_peak: meter.true_peak(),
+        }),
+        None => Err(From::from("The audio is too short or too quiet to measure")),
+    }
+}
+
+pub fn subcommand() -> clap::App<'static, 'static> {
+    use clap::{Arg, SubCommand};
+    SubCommand::with_name("analyze")
+        .about("Measures the loudness of songs, so that the player can normalize them")
+        .arg(Arg::with_name("FILES")
+             .help("The songs to analyze")
+             .required(true)
+             .multiple(true))
+}
+
+pub fn main(matches: &clap::ArgMatches, options: codec::Options) -> Result<(), Box<Error>> {
+    // Measure the song as recorded
+    let options = codec::Options{
+        vocal_reduction: false,
+        ..options
+    };
+    for filename in matches.values_of("FILES").unwrap() {
+        let path = Path::new(filename);
+        let analysis = try!(fs::File::open(path)
+                            .map_err(From::from)
+                            .and_then(|file| analyze(io::BufReader::new(file), options))
+                            .map_err(|e| format!("{}: {}", filename, e)));
+        if let Err(e) = analysis.save(path) {
+            return Err(From::from(format!("{}: {}", sidecar_path(path).display(), e)));
+        }
+        println!("{}: {:.1} LUFS, {:.1} dBTP", filename, analysis.integrated, analysis.true_peak);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sidecar() {
+        let analysis = Analysis{integrated: -14.25, true_peak: 0.5};
+        assert_eq!(Analysis::parse(&analysis.serialize()), Some(analysis));
+        assert_eq!(Analysis::parse("true-peak -1\n# comment\nintegrated -20\n"),
+                   Some(Analysis{integrated: -20., true_peak: -1.}));
+        assert_eq!(Analysis::parse("integrated -20\n"), None);
+        assert_eq!(Analysis::parse("integrated loud\ntrue-peak 0\n"), None);
+    }
+
+    #[test]
+    fn gain() {
+        let analysis = Analysis{integrated: -12., true_peak: 0.};
+        assert!((analysis.gain(-18.) - 0.501).abs() < 0.001);
+        assert!((analysis.gain(-12.) - 1.).abs() < 1e-6);
+    }
+}
@@ -17,8 +17,11 @@ Drivers are guaranteed to
 */
 
 
-use types;
+use dsp::effects;
+use types::{self, SAMPLE_RATE};
 use std::error::Error;
+use std::path::PathBuf;
+use std::str::FromStr;
 use std::sync::atomic;
 use std::sync::Arc;
 use rt::AtomicOption;
@@ -27,15 +30,65 @@ use rt;
 
 pub type Stream = Option<rt::ringbuffer::Reader<types::Sample>>;
 
+/// Samples of microphone input that can be waiting for the backend.
+/// This bounds how far the mics can lag; anything more is dropped.
+const MIC_BUFFER: usize = 2048;
+
+/// Each kind of deferred command has a slot in `DriverBackend`
+const DEFERRED_SLOTS: usize = 4;
+/// The kinds of IMMEDIATE command that carry settings, not counting
+/// Commit, Abort and Nop
+const IMMEDIATE_KINDS: usize = 4;
+/// The command queue has room for two rounds of commands between
+/// buffer periods, each a full set of deferred commands, one of each
+/// immediate setting and a Commit
+const COMMAND_QUEUE: usize = 2 * (DEFERRED_SLOTS + IMMEDIATE_KINDS + 1);
+
 #[derive(Debug)]
 enum DriverCommand {
     /// Change to a new stream. If the argument is None, plays silence
     /// at the last played sample value.
     ChangeStream(Stream),
 
+    /// Change to a new stream, fading it in over the given number of
+    /// seconds while the current stream fades out. Replaces any
+    /// ChangeStream or FadeOut in the same commit, and vice versa.
+    CrossfadeStream(Stream, f64),
+
+    /// Fade the current stream out to silence over the given number
+    /// of seconds. This is a CrossfadeStream to None.
+    FadeOut(f64),
+
     /// Resets the time counter to 0
     ZeroTime,
 
+    /// Sets the time counter to the given value, in seconds. Replaces
+    /// any ZeroTime in the same commit, and vice versa.
+    SetTimeBase(f64),
+
+    /// Stops playback, leaving the current stream where it is. The
+    /// time counter stops as well.
+    Pause,
+
+    /// Continues playback after a Pause. Replaces any Pause in the
+    /// same commit, and vice versa.
+    Resume,
+
+    /// Starts copying the output to the given ring buffer, replacing
+    /// any previous one. If the argument is None, stops recording.
+    Record(Option<rt::ringbuffer::Writer<types::Sample>>),
+
+    /// (IMMEDIATE) Sets the gain applied to the output, as a linear
+    /// factor.
+    SetVolume(f32),
+
+    /// (IMMEDIATE) Sets the gain of a microphone, given by its input
+    /// channel, as a linear factor
+    SetMicGain(usize, f32),
+
+    /// (IMMEDIATE) Changes the echo and reverb on the microphones
+    SetMicEffects(effects::EffectSettings),
+
     /// (IMMEDIATE) Commits any outstanding changes. The argument is the new value
     /// for the command ID in the time counter.
     Commit(u16),
@@ -57,23 +110,83 @@ pub struct AoStatus {
     pub last_command: u16,
     // Time, in seconds
     pub timestamp: f64,
+    /// If playback is paused, the time counter at which it stopped
+    pub paused: Option<f64>,
+}
+
+/// A linear gain ramp between two streams, counted in samples
+#[derive(Copy,Clone,Debug)]
+struct Fade {
+    position: u32,
+    length: u32,
+    /// The gain of the outgoing stream when the fade started. This is
+    /// less than 1 if it was itself still fading in.
+    outgoing_gain: f32,
+}
+
+impl Fade {
+    /// The current gain of the incoming stream
+    fn gain(&self) -> f32 {
+        self.position as f32 / self.length as f32
+    }
+
+    fn is_done(&self) -> bool {
+        self.position >= self.length
+    }
+
+    /// Returns the gains of the incoming and outgoing streams for the
+    /// next sample
+    fn step(&mut self) -> (f32, f32) {
+        let gain = self.gain();
+        if !self.is_done() {
+            self.position += 1;
+        }
+        (gain, (1. - gain) * self.outgoing_gain)
+    }
 }
 
 pub struct DriverBackend {
     shared: Arc<AtomicOption<AoStatus>>,
-    /// The command queue is guaranteed to be able to hold exactly one
-    /// of each type of deferred command. Later instances of a command replace earlier ones.
-    deferred_commands: [DriverCommand; 2],
+    /// One slot for each kind of deferred command: the stream, the
+    /// time counter, pausing and recording. Later instances of a kind
+    /// replace earlier ones in the same commit.
+    deferred_commands: [DriverCommand; DEFERRED_SLOTS],
 
     /// The time as of the last ZeroTime command processed
     time_base: f64,
 
+    /// The time counter at which playback was paused, if it is
+    paused: Option<f64>,
+
+    volume: f32,
+
     /// The ID of the last Commit command
     command_id: u16,
     
     command_queue: rt::ringbuffer::Reader<DriverCommand>,
 
     current_stream: Stream,
+
+    /// The stream being faded out, while a fade is running
+    outgoing_stream: Stream,
+
+    fade: Option<Fade>,
+
+    /// Microphones, if an input was opened
+    mics: Option<Mics>,
+
+    /// Where the output is copied to while recording
+    recorder: Option<rt::ringbuffer::Writer<types::Sample>>,
+
+    /// Samples that didn't fit in the recorder, for the frontend to
+    /// report
+    recording_overruns: Arc<atomic::AtomicUsize>,
+}
+
+/// Microphone input on its way into the output
+struct Mics {
+    input: rt::ringbuffer::Reader<types::Sample>,
+    mixer: effects::MicMixer,
 }
 
 pub struct DriverFrontend {
@@ -81,18 +194,112 @@ pub struct DriverFrontend {
     cached_state: AoStatus,
     command_queue: rt::ringbuffer::Writer<DriverCommand>,
     last_cmd_sent: u16,
-    driver: self::pa::Driver,
+    recording_overruns: Arc<atomic::AtomicUsize>,
+    driver: Box<Output>,
+    mic_input: Option<Box<Input>>,
+}
+
+/// Something that plays the samples produced by a `DriverBackend`.
+/// Outputs own the backend, and call `handle_commands` followed by
+/// `signal` once per buffer period.
+pub trait Output {
+    /// Time, in seconds since some arbitrary epoch. This must be the
+    /// clock that is passed to `DriverBackend::handle_commands`.
+    fn time(&self) -> f64;
+
+    fn start(&mut self) -> Result<(), Box<Error>>;
+
+    /// Note that this is an absolute minimum; codecs should
+    /// probably set their buffer sizes to be at least two video
+    /// frames long
+    fn min_buffer_size(&self) -> u32;
+}
+
+/// Something that captures microphone input into a ring buffer, for
+/// the backend to mix into the output. One mic can be plugged into
+/// each input channel.
+pub trait Input {
+    fn start(&mut self) -> Result<(), Box<Error>>;
+}
+
+/// Selects an `Input` at runtime
+#[derive(Clone,Debug,PartialEq)]
+pub enum InputKind {
+    /// A PortAudio device, given by name or index. If None, the
+    /// default device is used.
+    PortAudio(Option<String>),
+    /// Reads samples from a WAV file, as fast as the backend takes
+    /// them
+    Wav(PathBuf),
+}
+
+impl FromStr for InputKind {
+    type Err = String;
+
+    /// Parses "portaudio", "portaudio:DEVICE" or "wav:FILENAME"
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "portaudio" => Ok(InputKind::PortAudio(None)),
+            _ if s.starts_with("portaudio:") && s.len() > 10 => Ok(InputKind::PortAudio(Some(s[10..].to_owned()))),
+            _ if s.starts_with("wav:") && s.len() > 4 => Ok(InputKind::Wav(PathBuf::from(&s[4..]))),
+            _ => Err(format!("Unknown audio input {:?}; expected portaudio or wav:FILENAME", s)),
+        }
+    }
+}
+
+/// Selects an `Output` at runtime
+#[derive(Clone,Debug,PartialEq)]
+pub enum OutputKind {
+    /// A PortAudio device, given by name or index. If None, the
+    /// default device is used.
+    PortAudio(Option<String>),
+    /// Discards samples, consuming them in real time
+    Null,
+    /// Writes samples to a WAV file, consuming them in real time
+    Wav(PathBuf),
+}
+
+impl Default for OutputKind {
+    fn default() -> Self {
+        OutputKind::PortAudio(None)
+    }
+}
+
+impl FromStr for OutputKind {
+    type Err = String;
+
+    /// Parses "portaudio", "portaudio:DEVICE", "null" or "wav:FILENAME"
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "portaudio" => Ok(OutputKind::PortAudio(None)),
+            _ if s.starts_with("portaudio:") && s.len() > 10 => Ok(OutputKind::PortAudio(Some(s[10..].to_owned()))),
+            "null" => Ok(OutputKind::Null),
+            _ if s.starts_with("wav:") && s.len() > 4 => Ok(OutputKind::Wav(PathBuf::from(&s[4..]))),
+            _ => Err(format!("Unknown audio output {:?}; expected portaudio, null or wav:FILENAME", s)),
+        }
+    }
 }
 
 impl DriverBackend {
-    fn new(shared: Arc<AtomicOption<AoStatus>>, queue: rt::ringbuffer::Reader<DriverCommand>) -> Self {
+    fn new(shared: Arc<AtomicOption<AoStatus>>, queue: rt::ringbuffer::Reader<DriverCommand>,
+           mic_input: Option<rt::ringbuffer::Reader<types::Sample>>) -> Self {
         let mut backend = DriverBackend{
             shared: shared,
             deferred_commands: Self::default_deferred_commands(),
             time_base: 0.,
+            paused: None,
+            volume: 1.,
             command_id: 0,
             command_queue: queue,
             current_stream: None,
+            outgoing_stream: None,
+            fade: None,
+            mics: mic_input.map(|input| Mics{
+                input: input,
+                mixer: effects::MicMixer::new(),
+            }),
+            recorder: None,
+            recording_overruns: Arc::new(atomic::AtomicUsize::new(0)),
         };
         backend.receive_command(DriverCommand::ZeroTime, 0.);
         backend
@@ -100,6 +307,12 @@ impl DriverBackend {
 
     /// This time value is in seconds since some arbitrary epoch.
     fn handle_commands(&mut self, time: f64) {
+        // Drop the outgoing stream once it has faded out
+        if self.fade.map_or(false, |fade| fade.is_done()) {
+            self.fade = None;
+            self.outgoing_stream = None;
+        }
+
         // Handle comands
 
         // TODO: Do this with less synchronization; each pop costs
@@ -112,8 +325,12 @@ impl DriverBackend {
     fn receive_command(&mut self, command: DriverCommand, time: f64) {
         use std::mem::replace;
         match command {
-            DriverCommand::ChangeStream(_) => self.deferred_commands[0] = command,
-            DriverCommand::ZeroTime => self.deferred_commands[1] = command,
+            DriverCommand::ChangeStream(_) |
+            DriverCommand::CrossfadeStream(..) |
+            DriverCommand::FadeOut(_) => self.deferred_commands[0] = command,
+            DriverCommand::ZeroTime | DriverCommand::SetTimeBase(_) => self.deferred_commands[1] = command,
+            DriverCommand::Pause | DriverCommand::Resume => self.deferred_commands[2] = command,
+            DriverCommand::Record(_) => self.deferred_commands[3] = command,
             DriverCommand::Commit(v) => {
                 println!("Committing");
                 let mut cmdlist = replace(&mut self.deferred_commands, Self::default_deferred_commands());
@@ -125,47 +342,168 @@ impl DriverBackend {
                 self.shared.swap(AoStatus{
                     last_command: v,
                     timestamp: self.time_base,
+                    paused: self.paused,
                 }, atomic::Ordering::Release);
             },
+            DriverCommand::SetVolume(volume) => self.volume = volume,
+            DriverCommand::SetMicGain(mic, gain) => if let Some(ref mut mics) = self.mics {
+                if mic < mics.mixer.gains.len() {
+                    mics.mixer.gains[mic] = gain;
+                }
+            },
+            DriverCommand::SetMicEffects(settings) => if let Some(ref mut mics) = self.mics {
+                mics.mixer.effects.configure(settings);
+            },
             DriverCommand::Abort => self.deferred_commands = Self::default_deferred_commands(),
             DriverCommand::Nop => (),
         }
     }
 
-    fn default_deferred_commands() -> [DriverCommand; 2] {
+    fn default_deferred_commands() -> [DriverCommand; DEFERRED_SLOTS] {
         [DriverCommand::Nop,
          DriverCommand::Nop,
+         DriverCommand::Nop,
+         DriverCommand::Nop,
         ]
     }
 
+    /// Deferred commands are processed in the order of their slots,
+    /// so a time change in the same commit as a Pause or Resume
+    /// applies to the stream position before it is frozen or thawed.
     fn process_command(&mut self, command: DriverCommand, time: f64) {
         match command {
-            DriverCommand::ChangeStream(stream) => self.current_stream = stream,
-            DriverCommand::ZeroTime => self.time_base = time,
+            DriverCommand::ChangeStream(stream) => {
+                self.current_stream = stream;
+                self.outgoing_stream = None;
+                self.fade = None;
+            },
+            DriverCommand::CrossfadeStream(stream, duration) => self.start_fade(stream, duration),
+            DriverCommand::FadeOut(duration) => self.start_fade(None, duration),
+            DriverCommand::ZeroTime => self.set_time(0., time),
+            DriverCommand::SetTimeBase(base) => self.set_time(base, time),
+            DriverCommand::Pause => if self.paused.is_none() {
+                self.paused = Some(time - self.time_base);
+            },
+            DriverCommand::Resume => if let Some(stream_time) = self.paused.take() {
+                self.time_base = time - stream_time;
+            },
+            DriverCommand::Record(recorder) => self.recorder = recorder,
             _ => (),
         }
     }
 
+    /// Fade from the current stream to `stream` over `duration`
+    /// seconds. If a fade is already running, the stream that was
+    /// fading out is cut off, and the one that was fading in fades
+    /// out from wherever it had got to.
+    fn start_fade(&mut self, stream: Stream, duration: f64) {
+        use std::mem::replace;
+        let length = (duration * SAMPLE_RATE as f64) as u32;
+        let outgoing_gain = self.fade.map_or(1., |fade| fade.gain());
+        let outgoing = replace(&mut self.current_stream, stream);
+        if length > 0 {
+            self.outgoing_stream = outgoing;
+            self.fade = Some(Fade{
+                position: 0,
+                length: length,
+                outgoing_gain: outgoing_gain,
+            });
+        } else {
+            // Nothing to fade, so this is a cut
+            self.outgoing_stream = None;
+            self.fade = None;
+        }
+    }
+
+    /// Make the time counter read `stream_time` as of `time`
+    fn set_time(&mut self, stream_time: f64, time: f64) {
+        self.time_base = time - stream_time;
+        if self.paused.is_some() {
+            self.paused = Some(stream_time);
+        }
+    }
+
     fn signal(&mut self) -> DriverSignal {
+        let mics = self.mics.as_mut().map(|mics| (mics.input.iter(), &mut mics.mixer));
+        // While paused, play silence without touching the streams.
+        // The mics stay live, but the break is left out of the
+        // recording.
+        if self.paused.is_some() {
+            return DriverSignal{
+                iter: None,
+                outgoing: None,
+                fade: None,
+                mics: mics,
+                recorder: None,
+                volume: self.volume,
+                underrun_count: 0,
+                overrun_count: 0,
+                recording_overruns: &self.recording_overruns,
+            };
+        }
+        let fade = self.fade.as_mut();
         DriverSignal{
             iter: self.current_stream.as_mut().map(|s| s.iter()),
+            outgoing: if fade.is_some() { self.outgoing_stream.as_mut().map(|s| s.iter()) } else { None },
+            fade: fade,
+            mics: mics,
+            recorder: self.recorder.as_mut().map(|r| r.extender()),
+            volume: self.volume,
             underrun_count: 0,
+            overrun_count: 0,
+            recording_overruns: &self.recording_overruns,
         }
     }
 }
 
 struct DriverSignal<'a> {
     iter: Option<rt::ringbuffer::ReadIter<'a, types::Sample>>,
+    outgoing: Option<rt::ringbuffer::ReadIter<'a, types::Sample>>,
+    fade: Option<&'a mut Fade>,
+    mics: Option<(rt::ringbuffer::ReadIter<'a, types::Sample>, &'a mut effects::MicMixer)>,
+    recorder: Option<rt::ringbuffer::WriteIter<'a, types::Sample>>,
+    /// Applies to the song, not the mics
+    volume: f32,
     underrun_count: usize,
+    /// Samples that didn't fit in the recorder
+    overrun_count: usize,
+    /// Where `overrun_count` is added up, since printing here would
+    /// hold up the audio callback
+    recording_overruns: &'a atomic::AtomicUsize,
 }
 
 impl <'a> Iterator for DriverSignal<'a> {
     type Item = types::Sample;
     fn next(&mut self) -> Option<types::Sample> {
-        Some(self.iter.as_mut().and_then(|i| i.next()).unwrap_or_else(|| {
+        let volume = self.volume;
+        let [l, r] = self.iter.as_mut().and_then(|i| i.next()).unwrap_or_else(|| {
             self.underrun_count += 1;
             [0.0,0.0]
-        }))
+        });
+        let [l, r] = match self.fade {
+            Some(ref mut fade) => {
+                let (gain_in, gain_out) = fade.step();
+                let [ol, or] = self.outgoing.as_mut().and_then(|i| i.next()).unwrap_or([0.0, 0.0]);
+                [(l * gain_in + ol * gain_out) * volume,
+                 (r * gain_in + or * gain_out) * volume]
+            },
+            None => [l * volume, r * volume],
+        };
+        let output = match self.mics {
+            Some((ref mut input, ref mut mixer)) => {
+                // Keep the effects running through gaps in the input,
+                // so that their tails ring out
+                let [ml, mr] = mixer.process(input.next().unwrap_or([0.0, 0.0]));
+                [l + ml, r + mr]
+            },
+            None => [l, r],
+        };
+        if let Some(ref mut recorder) = self.recorder {
+            if recorder.push(output).is_err() {
+                self.overrun_count += 1;
+            }
+        }
+        Some(output)
     }
 }
 
@@ -174,20 +512,27 @@ impl <'a> Drop for DriverSignal<'a> {
         if self.underrun_count != 0 && self.iter.is_some() {
             println!("Dropped {} samples", self.underrun_count);
         }
+        if self.overrun_count != 0 {
+            self.recording_overruns.fetch_add(self.overrun_count, atomic::Ordering::Relaxed);
+        }
     }
 }
 
 impl DriverFrontend {
-    fn new(shared: Arc<AtomicOption<AoStatus>>, queue: rt::ringbuffer::Writer<DriverCommand>, hw: pa::Driver) -> Self {
+    fn new(shared: Arc<AtomicOption<AoStatus>>, queue: rt::ringbuffer::Writer<DriverCommand>,
+           recording_overruns: Arc<atomic::AtomicUsize>, hw: Box<Output>, mic_input: Option<Box<Input>>) -> Self {
         DriverFrontend {
             shared: shared,
             cached_state: AoStatus{
                 last_command: 0,
                 timestamp: 0.,
+                paused: None,
             },
             command_queue: queue,
             last_cmd_sent: 0,
+            recording_overruns: recording_overruns,
             driver: hw,
+            mic_input: mic_input,
         }
     }
 
@@ -200,10 +545,63 @@ impl DriverFrontend {
         })
     }
 
+    /// Starts copying everything that is played, mics included, to
+    /// `recorder`, or stops if that is None. This is deferred, so that
+    /// a recording can start along with a new song.
+    pub fn record(&mut self, recorder: Option<rt::ringbuffer::Writer<types::Sample>>) -> Result<(), ()> {
+        self.command_queue.push(DriverCommand::Record(recorder)).map_err(|_|())
+    }
+
+    /// Like `change_stream`, but fades from the current stream to the
+    /// new one over `duration` seconds
+    pub fn crossfade_stream(&mut self, stream: Stream, duration: f64) -> Result<(), Stream> {
+        self.command_queue.push(DriverCommand::CrossfadeStream(stream, duration)).map_err(|cmd| match cmd {
+            DriverCommand::CrossfadeStream(stream, _) => stream,
+            _ => unreachable!(),
+        })
+    }
+
+    /// Fades the current stream out to silence over `duration` seconds
+    pub fn fade_out(&mut self, duration: f64) -> Result<(), ()> {
+        self.command_queue.push(DriverCommand::FadeOut(duration)).map_err(|_|())
+    }
+
+    /// Sets the gain of the mic on input channel `mic`, as a linear
+    /// factor. This takes effect immediately. Does nothing if no mic
+    /// input was opened.
+    pub fn set_mic_gain(&mut self, mic: usize, gain: f32) -> Result<(), ()> {
+        self.command_queue.push(DriverCommand::SetMicGain(mic, gain)).map_err(|_|())
+    }
+
+    /// Changes the echo and reverb on the mics. This takes effect
+    /// immediately.
+    pub fn set_mic_effects(&mut self, settings: effects::EffectSettings) -> Result<(), ()> {
+        self.command_queue.push(DriverCommand::SetMicEffects(settings)).map_err(|_|())
+    }
+
     pub fn zero_time(&mut self) -> Result<(), ()> {
         self.command_queue.push(DriverCommand::ZeroTime).map_err(|_|())
     }
 
+    /// Sets the time counter to `time`, in seconds
+    pub fn set_time_base(&mut self, time: f64) -> Result<(), ()> {
+        self.command_queue.push(DriverCommand::SetTimeBase(time)).map_err(|_|())
+    }
+
+    /// Sets the output gain, as a linear factor. This takes effect
+    /// immediately, without waiting for a commit.
+    pub fn set_volume(&mut self, volume: f32) -> Result<(), ()> {
+        self.command_queue.push(DriverCommand::SetVolume(volume)).map_err(|_|())
+    }
+
+    pub fn pause(&mut self) -> Result<(), ()> {
+        self.command_queue.push(DriverCommand::Pause).map_err(|_|())
+    }
+
+    pub fn resume(&mut self) -> Result<(), ()> {
+        self.command_queue.push(DriverCommand::Resume).map_err(|_|())
+    }
+
     pub fn commit(&mut self) -> Result<u16, ()> {
         self.last_cmd_sent += 1;
         self.command_queue.push(DriverCommand::Commit(self.last_cmd_sent))
@@ -222,31 +620,61 @@ impl DriverFrontend {
         self.current_status().last_command == self.last_cmd_sent
     }
 
+    /// The current time counter, in seconds. This does not advance
+    /// while playback is paused.
     pub fn timestamp(&mut self) -> f64 {
-        self.driver.time() - self.current_status().timestamp
+        let status = self.current_status();
+        status.paused.unwrap_or_else(|| self.driver.time() - status.timestamp)
+    }
+
+    /// How many samples have been dropped from the recording since
+    /// the last call, because the recorder fell behind
+    pub fn take_recording_overruns(&mut self) -> usize {
+        self.recording_overruns.swap(0, atomic::Ordering::Relaxed)
     }
 
     pub fn start(&mut self) -> Result<(), Box<Error>> {
-        self.driver.start()
+        try!(self.driver.start());
+        if let Some(ref mut input) = self.mic_input {
+            try!(input.start());
+        }
+        Ok(())
     }
 }
 
-pub fn open() -> Result<DriverFrontend, Box<Error>> {
+/// Open an output, and optionally a mic input to mix into it
+pub fn open(kind: &OutputKind, mic: Option<&InputKind>) -> Result<DriverFrontend, Box<Error>> {
     let status_chan = Arc::new(AtomicOption::new());
-    let (cmd_rd, cmd_wt) = rt::ringbuffer::new(16);
-    let backend = DriverBackend::new(status_chan.clone(), cmd_rd);
-    let frontend = DriverFrontend::new(status_chan, cmd_wt, try!(pa::Driver::open(backend)));
-
-    // Initialize PortAudio
-    return Ok(frontend);
+    let (cmd_rd, cmd_wt) = rt::ringbuffer::new(COMMAND_QUEUE);
+    let (mic_rd, mic_input) = match mic {
+        Some(kind) => {
+            let (rd, wr) = rt::ringbuffer::new(MIC_BUFFER);
+            let input = match *kind {
+                InputKind::PortAudio(ref device) => Box::new(try!(pa::InputDriver::open(wr, device.as_ref().map(String::as_str)))) as Box<Input>,
+                InputKind::Wav(ref path) => Box::new(try!(wav_input::Driver::open(path, wr))),
+            };
+            (Some(rd), Some(input))
+        },
+        None => (None, None),
+    };
+    let backend = DriverBackend::new(status_chan.clone(), cmd_rd, mic_rd);
+    let recording_overruns = backend.recording_overruns.clone();
+    let output = match *kind {
+        OutputKind::PortAudio(ref device) => Box::new(try!(pa::Driver::open(backend, device.as_ref().map(String::as_str)))) as Box<Output>,
+        OutputKind::Null => Box::new(clocked::Driver::new(backend, Box::new(clocked::NullSink))),
+        OutputKind::Wav(ref path) => Box::new(clocked::Driver::new(backend, Box::new(try!(clocked::WavSink::create(path))))),
+    };
+    Ok(DriverFrontend::new(status_chan, cmd_wt, recording_overruns, output, mic_input))
 }
 
 mod pa {
     use portaudio;
     use std::error::Error;
     use std::time;
+
+    use rt;
+    use types::{self, SAMPLE_RATE};
     
-    const SAMPLE_RATE: f64 = 48_000.;
     const FRAMES_PER_BUFFER: u32 = 64;
     const CHANNELS: i32 = 2;
 
@@ -261,13 +689,86 @@ mod pa {
         base: time::Instant,
     }
 
-    impl Driver {
-        pub fn time(&self) -> f64 {
-            override_time(self.base, self.stream.time())
+    /// Find an output device by its name or index
+    fn find_device(pa: &portaudio::PortAudio, name: &str) -> Result<portaudio::StreamParameters<f32>, Box<Error>> {
+        let mut names = Vec::new();
+        for device in try!(pa.devices()) {
+            let (idx, info) = try!(device);
+            if info.max_output_channels < CHANNELS {
+                continue;
+            }
+            if info.name == name || idx.0.to_string() == name {
+                return Ok(portaudio::StreamParameters::new(idx, CHANNELS, true, info.default_low_output_latency));
+            }
+            names.push(format!("{}: {}", idx.0, info.name));
+        }
+        Err(From::from(format!("No audio output device {:?}. Available devices are:\n  {}", name, names.join("\n  "))))
+    }
+
+    /// Find an input device by its name or index, or the default
+    /// one. Mono devices are opened as such; anything more is opened
+    /// in stereo.
+    fn find_input_device(pa: &portaudio::PortAudio, name: Option<&str>) -> Result<portaudio::StreamParameters<f32>, Box<Error>> {
+        let mut names = Vec::new();
+        let default = try!(pa.default_input_device());
+        for device in try!(pa.devices()) {
+            let (idx, info) = try!(device);
+            if info.max_input_channels < 1 {
+                continue;
+            }
+            let found = match name {
+                Some(name) => info.name == name || idx.0.to_string() == name,
+                None => idx == default,
+            };
+            if found {
+                let channels = if info.max_input_channels < CHANNELS { 1 } else { CHANNELS };
+                return Ok(portaudio::StreamParameters::new(idx, channels, true, info.default_low_input_latency));
+            }
+            names.push(format!("{}: {}", idx.0, info.name));
         }
-        pub fn open(mut backend: super::DriverBackend) -> Result<Driver, Box<Error>> {
+        Err(From::from(format!("No audio input device {:?}. Available devices are:\n  {}", name.unwrap_or("(default)"), names.join("\n  "))))
+    }
+
+    pub struct InputDriver {
+        pa: portaudio::PortAudio,
+        stream: portaudio::Stream<portaudio::NonBlocking, portaudio::Input<f32>>,
+    }
+
+    impl InputDriver {
+        pub fn open(mut mics: rt::ringbuffer::Writer<types::Sample>, device: Option<&str>) -> Result<InputDriver, Box<Error>> {
             let pa = try!(portaudio::PortAudio::new());
-            let mut settings = try!(pa.default_output_stream_settings(CHANNELS, SAMPLE_RATE, FRAMES_PER_BUFFER));
+            let params = try!(find_input_device(&pa, device));
+            let channels = params.channel_count as usize;
+            let settings = portaudio::InputStreamSettings::new(params, SAMPLE_RATE as f64, FRAMES_PER_BUFFER);
+
+            let callback = move |portaudio::InputStreamCallbackArgs{buffer, ..}| {
+                for frame in buffer.chunks(channels) {
+                    // If the output has fallen behind, drop input
+                    // rather than let the mics lag
+                    mics.push([frame[0], if channels > 1 { frame[1] } else { 0.0 }]).ok();
+                }
+                portaudio::Continue
+            };
+            let stream = try!(pa.open_non_blocking_stream(settings, callback));
+            Ok(InputDriver{pa: pa, stream: stream})
+        }
+    }
+
+    impl super::Input for InputDriver {
+        fn start(&mut self) -> Result<(), Box<Error>> {
+            try!(self.stream.start());
+            Ok(())
+        }
+    }
+
+    impl Driver {
+        pub fn open(mut backend: super::DriverBackend, device: Option<&str>) -> Result<Driver, Box<Error>> {
+            let pa = try!(portaudio::PortAudio::new());
+            let params = match device {
+                Some(name) => try!(find_device(&pa, name)),
+                None => try!(pa.default_output_stream_params(CHANNELS)),
+            };
+            let mut settings = portaudio::OutputStreamSettings::new(params, SAMPLE_RATE as f64, FRAMES_PER_BUFFER);
             settings.flags = portaudio::stream_flags::CLIP_OFF;
             let base_time = time::Instant::now();
 
@@ -283,17 +784,532 @@ mod pa {
             let stream = try!(pa.open_non_blocking_stream(settings, callback));
             Ok(Driver{pa: pa, stream: stream, base: base_time})
         }
+    }
+
+    impl super::Output for Driver {
+        fn time(&self) -> f64 {
+            override_time(self.base, self.stream.time())
+        }
 
-        pub fn start(&mut self) -> Result<(), Box<Error>> {
+        fn start(&mut self) -> Result<(), Box<Error>> {
             try!(self.stream.start());
             Ok(())
         }
 
-        /// Note that this is an absolute minimum; codecs should
-        /// probably set their buffer sizes to be at least two video
-        /// frames long
-        pub fn min_buffer_size(&self) -> u32 {
+        fn min_buffer_size(&self) -> u32 {
+            FRAMES_PER_BUFFER * 2
+        }
+    }
+}
+
+/// Outputs that have no hardware clock. A thread pulls a buffer from
+/// the backend every buffer period, as measured by the monotonic
+/// system clock, and hands it to a sink.
+mod clocked {
+    use std::error::Error;
+    use std::fs;
+    use std::io;
+    use std::path::Path;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use types::{self, SAMPLE_RATE};
+    use wav;
+
+    /// 10ms
+    const FRAMES_PER_BUFFER: u32 = 480;
+
+    pub trait Sink: Send {
+        fn consume(&mut self, samples: &[types::Sample]) -> io::Result<()>;
+        /// Called once the output is shut down
+        fn finish(&mut self) -> io::Result<()>;
+    }
+
+    pub struct NullSink;
+
+    impl Sink for NullSink {
+        fn consume(&mut self, _: &[types::Sample]) -> io::Result<()> { Ok(()) }
+        fn finish(&mut self) -> io::Result<()> { Ok(()) }
+    }
+
+    pub struct WavSink(Option<wav::WavWriter<io::BufWriter<fs::File>>>);
+
+    impl WavSink {
+        pub fn create(path: &Path) -> io::Result<Self> {
+            let file = try!(fs::File::create(path));
+            Ok(WavSink(Some(try!(wav::WavWriter::new(io::BufWriter::new(file))))))
+        }
+    }
+
+    impl Sink for WavSink {
+        fn consume(&mut self, samples: &[types::Sample]) -> io::Result<()> {
+            match self.0 {
+                Some(ref mut writer) => writer.write_samples(samples),
+                None => Ok(()),
+            }
+        }
+
+        fn finish(&mut self) -> io::Result<()> {
+            match self.0.take() {
+                Some(writer) => writer.finish().map(|_| ()),
+                None => Ok(()),
+            }
+        }
+    }
+
+    fn seconds(duration: Duration) -> f64 {
+        duration.as_secs() as f64 + (duration.subsec_nanos() as f64) / 1000_000_000.
+    }
+
+    pub struct Driver {
+        base: Instant,
+        running: Arc<AtomicBool>,
+        /// Only Some until started
+        parts: Option<(super::DriverBackend, Box<Sink>)>,
+        thread: Option<thread::JoinHandle<()>>,
+    }
+
+    impl Driver {
+        pub fn new(backend: super::DriverBackend, sink: Box<Sink>) -> Self {
+            Driver{
+                base: Instant::now(),
+                running: Arc::new(AtomicBool::new(false)),
+                parts: Some((backend, sink)),
+                thread: None,
+            }
+        }
+    }
+
+    fn run(mut backend: super::DriverBackend, mut sink: Box<Sink>, base: Instant, running: Arc<AtomicBool>) {
+        let period = Duration::new(0, (FRAMES_PER_BUFFER as u64 * 1000_000_000 / SAMPLE_RATE as u64) as u32);
+        let mut buffer = vec![[0.0, 0.0]; FRAMES_PER_BUFFER as usize];
+        let mut next = Instant::now();
+        while running.load(Ordering::Acquire) {
+            let now = Instant::now();
+            if next > now {
+                thread::sleep(next - now);
+            }
+            backend.handle_commands(seconds(next - base));
+            for (dst, src) in buffer.iter_mut().zip(backend.signal()) {
+                *dst = src;
+            }
+            if let Err(e) = sink.consume(&buffer) {
+                println!("Audio output failed: {}", e);
+                break;
+            }
+            next += period;
+        }
+        if let Err(e) = sink.finish() {
+            println!("Failed to finish audio output: {}", e);
+        }
+    }
+
+    impl super::Output for Driver {
+        fn time(&self) -> f64 {
+            seconds(Instant::now() - self.base)
+        }
+
+        fn start(&mut self) -> Result<(), Box<Error>> {
+            let (backend, sink) = match self.parts.take() {
+                Some(parts) => parts,
+                None => return Err(From::from("Output already started")),
+            };
+            let base = self.base;
+            let running = self.running.clone();
+            running.store(true, Ordering::Release);
+            self.thread = Some(try!(thread::Builder::new()
+                                    .name("audio output".to_owned())
+                                    .spawn(move || run(backend, sink, base, running))));
+            Ok(())
+        }
+
+        fn min_buffer_size(&self) -> u32 {
             FRAMES_PER_BUFFER * 2
         }
     }
+
+    impl Drop for Driver {
+        fn drop(&mut self) {
+            self.running.store(false, Ordering::Release);
+            if let Some(thread) = self.thread.take() {
+                thread.join().ok();
+            }
+        }
+    }
+}
+
+/// Plays a WAV file in as the mic input. A thread keeps the ring
+/// buffer topped up, so the file comes in at whatever rate the output
+/// consumes it.
+mod wav_input {
+    use std::error::Error;
+    use std::fs;
+    use std::io;
+    use std::path::Path;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread;
+    use std::time::Duration;
+
+    use rt;
+    use types;
+    use wav;
+
+    /// 10ms
+    const FRAMES_PER_BUFFER: usize = 480;
+
+    type Reader = wav::WavReader<io::BufReader<fs::File>>;
+
+    pub struct Driver {
+        running: Arc<AtomicBool>,
+        /// Only Some until started
+        parts: Option<(Reader, rt::ringbuffer::Writer<types::Sample>)>,
+        thread: Option<thread::JoinHandle<()>>,
+    }
+
+    impl Driver {
+        pub fn open(path: &Path, mics: rt::ringbuffer::Writer<types::Sample>) -> Result<Driver, Box<Error>> {
+            let file = try!(fs::File::open(path));
+            let reader = try!(wav::WavReader::new(io::BufReader::new(file))
+                              .map_err(|e| format!("{}: {}", path.display(), e)));
+            Ok(Driver{
+                running: Arc::new(AtomicBool::new(false)),
+                parts: Some((reader, mics)),
+                thread: None,
+            })
+        }
+    }
+
+    fn run(mut reader: Reader, mut mics: rt::ringbuffer::Writer<types::Sample>, running: Arc<AtomicBool>) {
+        let mut buffer = vec![[0.0, 0.0]; FRAMES_PER_BUFFER];
+        while running.load(Ordering::Acquire) {
+            let len = match reader.read_samples(&mut buffer) {
+                Ok(0) => break,
+                Ok(len) => len,
+                Err(e) => {
+                    println!("Audio input failed: {}", e);
+                    break;
+                },
+            };
+            let mut pending = &buffer[..len];
+            while !pending.is_empty() && running.load(Ordering::Acquire) {
+                match mics.push(pending[0]) {
+                    Ok(()) => pending = &pending[1..],
+                    // Full; wait for the output to catch up
+                    Err(_) => thread::sleep(Duration::from_millis(5)),
+                }
+            }
+        }
+    }
+
+    impl super::Input for Driver {
+        fn start(&mut self) -> Result<(), Box<Error>> {
+            let (reader, mics) = match self.parts.take() {
+                Some(parts) => parts,
+                None => return Err(From::from("Input already started")),
+            };
+            let running = self.running.clone();
+            running.store(true, Ordering::Release);
+            self.thread = Some(try!(thread::Builder::new()
+                                    .name("audio input".to_owned())
+                                    .spawn(move || run(reader, mics, running))));
+            Ok(())
+        }
+    }
+
+    impl Drop for Driver {
+        fn drop(&mut self) {
+            self.running.store(false, Ordering::Release);
+            if let Some(thread) = self.thread.take() {
+                thread.join().ok();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    fn backend() -> (DriverBackend, rt::ringbuffer::Writer<DriverCommand>) {
+        let (cmd_rd, cmd_wt) = rt::ringbuffer::new(COMMAND_QUEUE);
+        (DriverBackend::new(Arc::new(AtomicOption::new()), cmd_rd, None), cmd_wt)
+    }
+
+    /// A stream holding `len` samples of `value` on both channels
+    fn constant(value: f32, len: usize) -> Stream {
+        let (rd, mut wr) = rt::ringbuffer::new(len + 1);
+        for _ in 0..len {
+            wr.push([value, value]).unwrap();
+        }
+        Some(rd)
+    }
+
+    fn left(backend: &mut DriverBackend, len: usize) -> Vec<f32> {
+        backend.signal().take(len).map(|[l, _]| l).collect()
+    }
+
+    /// An output whose clock is set by hand
+    struct Clock(Rc<Cell<f64>>);
+
+    impl Output for Clock {
+        fn time(&self) -> f64 { self.0.get() }
+        fn start(&mut self) -> Result<(), Box<Error>> { Ok(()) }
+        fn min_buffer_size(&self) -> u32 { 0 }
+    }
+
+    /// A backend, and a frontend whose output runs on `clock`
+    fn driver() -> (DriverBackend, DriverFrontend, Rc<Cell<f64>>) {
+        let shared = Arc::new(AtomicOption::new());
+        let (cmd_rd, cmd_wt) = rt::ringbuffer::new(COMMAND_QUEUE);
+        let clock = Rc::new(Cell::new(0.));
+        let backend = DriverBackend::new(shared.clone(), cmd_rd, None);
+        let overruns = backend.recording_overruns.clone();
+        let frontend = DriverFrontend::new(shared, cmd_wt, overruns, Box::new(Clock(clock.clone())), None);
+        (backend, frontend, clock)
+    }
+
+    /// Move the clock on to `time`, and start a buffer period there
+    fn tick(backend: &mut DriverBackend, clock: &Cell<f64>, time: f64) {
+        clock.set(time);
+        backend.handle_commands(time);
+    }
+
+    #[test]
+    fn pause_freezes_the_clock() {
+        let (mut backend, mut frontend, clock) = driver();
+        frontend.zero_time().unwrap();
+        frontend.commit().unwrap();
+        tick(&mut backend, &clock, 10.);
+        assert_eq!(backend.time_base, 10.);
+        tick(&mut backend, &clock, 12.);
+        assert_eq!(frontend.timestamp(), 2.);
+
+        frontend.pause().unwrap();
+        frontend.commit().unwrap();
+        // Nothing changes until the driver gets to the commit
+        assert!(!frontend.current_status().paused.is_some());
+        tick(&mut backend, &clock, 12.5);
+        assert_eq!(backend.paused, Some(2.5));
+        assert!(frontend.current_status().paused.is_some());
+        tick(&mut backend, &clock, 20.);
+        assert_eq!(frontend.timestamp(), 2.5);
+
+        // Pausing again doesn't move the clock
+        frontend.pause().unwrap();
+        frontend.commit().unwrap();
+        tick(&mut backend, &clock, 25.);
+        assert_eq!(backend.paused, Some(2.5));
+        assert_eq!(backend.time_base, 10.);
+
+        // The clock picks up where it stopped
+        frontend.resume().unwrap();
+        frontend.commit().unwrap();
+        tick(&mut backend, &clock, 30.);
+        assert_eq!(backend.paused, None);
+        assert_eq!(backend.time_base, 27.5);
+        assert!(!frontend.current_status().paused.is_some());
+        assert_eq!(frontend.timestamp(), 2.5);
+        tick(&mut backend, &clock, 31.);
+        assert_eq!(frontend.timestamp(), 3.5);
+
+        // Resuming again does nothing
+        frontend.resume().unwrap();
+        frontend.commit().unwrap();
+        tick(&mut backend, &clock, 32.);
+        assert_eq!(backend.time_base, 27.5);
+        assert_eq!(frontend.timestamp(), 4.5);
+    }
+
+    #[test]
+    fn set_time_while_paused() {
+        let (mut backend, mut frontend, clock) = driver();
+        frontend.commit().unwrap();
+        tick(&mut backend, &clock, 0.);
+        frontend.pause().unwrap();
+        frontend.commit().unwrap();
+        tick(&mut backend, &clock, 4.);
+        assert_eq!(backend.paused, Some(4.));
+
+        // The clock jumps, but stays stopped
+        frontend.set_time_base(30.).unwrap();
+        frontend.commit().unwrap();
+        tick(&mut backend, &clock, 6.);
+        assert_eq!(backend.paused, Some(30.));
+        tick(&mut backend, &clock, 9.);
+        assert_eq!(frontend.timestamp(), 30.);
+
+        frontend.resume().unwrap();
+        frontend.commit().unwrap();
+        tick(&mut backend, &clock, 10.);
+        assert_eq!(backend.time_base, -20.);
+        tick(&mut backend, &clock, 11.);
+        assert_eq!(frontend.timestamp(), 31.);
+
+        frontend.pause().unwrap();
+        frontend.commit().unwrap();
+        tick(&mut backend, &clock, 12.);
+        frontend.zero_time().unwrap();
+        frontend.commit().unwrap();
+        tick(&mut backend, &clock, 13.);
+        assert_eq!(backend.paused, Some(0.));
+        assert_eq!(frontend.timestamp(), 0.);
+    }
+
+    #[test]
+    fn pause_and_set_time_in_one_commit() {
+        let (mut backend, mut frontend, clock) = driver();
+        // The time slot comes first, so the pause freezes the new
+        // time, whatever order the commands were sent in
+        frontend.pause().unwrap();
+        frontend.set_time_base(30.).unwrap();
+        frontend.commit().unwrap();
+        tick(&mut backend, &clock, 5.);
+        assert_eq!(backend.paused, Some(30.));
+        assert_eq!(backend.time_base, -25.);
+        tick(&mut backend, &clock, 8.);
+        assert_eq!(frontend.timestamp(), 30.);
+
+        frontend.resume().unwrap();
+        frontend.set_time_base(40.).unwrap();
+        frontend.commit().unwrap();
+        tick(&mut backend, &clock, 10.);
+        assert_eq!(backend.paused, None);
+        assert_eq!(backend.time_base, -30.);
+        tick(&mut backend, &clock, 11.);
+        assert_eq!(frontend.timestamp(), 41.);
+
+        // A pause and a resume in one commit cancel out
+        frontend.pause().unwrap();
+        frontend.resume().unwrap();
+        frontend.commit().unwrap();
+        tick(&mut backend, &clock, 12.);
+        assert_eq!(backend.paused, None);
+        assert_eq!(frontend.timestamp(), 42.);
+    }
+
+    #[test]
+    fn crossfade() {
+        let (mut backend, mut commands) = backend();
+        commands.push(DriverCommand::ChangeStream(constant(1., 8))).unwrap();
+        commands.push(DriverCommand::Commit(1)).unwrap();
+        backend.handle_commands(0.);
+        assert_eq!(left(&mut backend, 2), vec![1., 1.]);
+
+        commands.push(DriverCommand::CrossfadeStream(constant(-1., 8), 4. / SAMPLE_RATE as f64)).unwrap();
+        commands.push(DriverCommand::Commit(2)).unwrap();
+        backend.handle_commands(0.);
+        assert_eq!(left(&mut backend, 6), vec![1., 0.5, 0., -0.5, -1., -1.]);
+        backend.handle_commands(0.);
+        assert!(backend.fade.is_none() && backend.outgoing_stream.is_none());
+    }
+
+    #[test]
+    fn crossfade_without_a_duration_cuts() {
+        let (mut backend, mut commands) = backend();
+        commands.push(DriverCommand::ChangeStream(constant(1., 8))).unwrap();
+        commands.push(DriverCommand::CrossfadeStream(constant(-1., 8), 4. / SAMPLE_RATE as f64)).unwrap();
+        commands.push(DriverCommand::Commit(1)).unwrap();
+        backend.handle_commands(0.);
+        left(&mut backend, 1);
+        commands.push(DriverCommand::CrossfadeStream(constant(0.5, 8), 0.)).unwrap();
+        commands.push(DriverCommand::Commit(2)).unwrap();
+        backend.handle_commands(0.);
+        assert!(backend.fade.is_none() && backend.outgoing_stream.is_none());
+        assert_eq!(left(&mut backend, 2), vec![0.5, 0.5]);
+    }
+
+    #[test]
+    fn fade_out() {
+        let (mut backend, mut commands) = backend();
+        commands.push(DriverCommand::ChangeStream(constant(1., 8))).unwrap();
+        commands.push(DriverCommand::Commit(1)).unwrap();
+        backend.handle_commands(0.);
+
+        // Fade out halfway through a crossfade to silence
+        commands.push(DriverCommand::CrossfadeStream(constant(1., 8), 4. / SAMPLE_RATE as f64)).unwrap();
+        commands.push(DriverCommand::Commit(2)).unwrap();
+        backend.handle_commands(0.);
+        left(&mut backend, 2);
+        commands.push(DriverCommand::FadeOut(2. / SAMPLE_RATE as f64)).unwrap();
+        commands.push(DriverCommand::Commit(3)).unwrap();
+        backend.handle_commands(0.);
+        assert_eq!(left(&mut backend, 3), vec![0.5, 0.25, 0.]);
+    }
+
+    #[test]
+    fn queue_holds_a_full_commit() {
+        let (mut backend, mut commands) = backend();
+        for _ in 0..2 {
+            commands.push(DriverCommand::CrossfadeStream(constant(1., 8), 0.)).unwrap();
+            commands.push(DriverCommand::SetTimeBase(1.)).unwrap();
+            commands.push(DriverCommand::Pause).unwrap();
+            commands.push(DriverCommand::Record(None)).unwrap();
+            commands.push(DriverCommand::SetVolume(0.5)).unwrap();
+            commands.push(DriverCommand::SetMicGain(0, 0.5)).unwrap();
+            commands.push(DriverCommand::SetMicGain(1, 0.5)).unwrap();
+            commands.push(DriverCommand::SetMicEffects(Default::default())).unwrap();
+            commands.push(DriverCommand::Commit(1)).unwrap();
+        }
+        backend.handle_commands(0.);
+        assert_eq!(backend.command_id, 1);
+        assert!(backend.paused.is_some());
+    }
+
+    #[test]
+    fn mics() {
+        let (cmd_rd, mut commands) = rt::ringbuffer::new(COMMAND_QUEUE);
+        let (mic_rd, mut mic_wt) = rt::ringbuffer::new(8);
+        let mut backend = DriverBackend::new(Arc::new(AtomicOption::new()), cmd_rd, Some(mic_rd));
+        commands.push(DriverCommand::ChangeStream(constant(0.5, 8))).unwrap();
+        commands.push(DriverCommand::SetVolume(0.5)).unwrap();
+        commands.push(DriverCommand::SetMicGain(1, 0.5)).unwrap();
+        commands.push(DriverCommand::Commit(1)).unwrap();
+        backend.handle_commands(0.);
+        mic_wt.push([0.5, 0.]).unwrap();
+        mic_wt.push([0., 1.]).unwrap();
+        // Both mics go to both channels, and the volume is only for
+        // the song. The mics carry on through an underrun.
+        let output : Vec<types::Sample> = backend.signal().take(3).collect();
+        assert_eq!(output, vec![[0.75, 0.75], [0.75, 0.75], [0.25, 0.25]]);
+    }
+
+    #[test]
+    fn record() {
+        let (mut backend, mut commands) = backend();
+        let (mut recording, recorder) = rt::ringbuffer::new(8);
+        commands.push(DriverCommand::ChangeStream(constant(0.5, 8))).unwrap();
+        commands.push(DriverCommand::Record(Some(recorder))).unwrap();
+        commands.push(DriverCommand::Commit(1)).unwrap();
+        backend.handle_commands(0.);
+        left(&mut backend, 2);
+        commands.push(DriverCommand::Pause).unwrap();
+        commands.push(DriverCommand::Commit(2)).unwrap();
+        backend.handle_commands(0.);
+        left(&mut backend, 2);
+        commands.push(DriverCommand::Resume).unwrap();
+        commands.push(DriverCommand::Record(None)).unwrap();
+        commands.push(DriverCommand::Commit(3)).unwrap();
+        backend.handle_commands(0.);
+        left(&mut backend, 2);
+        assert_eq!(recording.iter().collect::<Vec<_>>(), vec![[0.5, 0.5]; 2]);
+    }
+
+    #[test]
+    fn recording_overruns() {
+        let (mut backend, mut frontend, clock) = driver();
+        // Room for three samples
+        let (_recording, recorder) = rt::ringbuffer::new(3);
+        frontend.change_stream(constant(0.5, 8)).unwrap();
+        frontend.record(Some(recorder)).unwrap();
+        frontend.commit().unwrap();
+        tick(&mut backend, &clock, 0.);
+        left(&mut backend, 5);
+        assert_eq!(frontend.take_recording_overruns(), 2);
+        assert_eq!(frontend.take_recording_overruns(), 0);
+    }
 }
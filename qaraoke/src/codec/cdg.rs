@@ -1,13 +1,14 @@
 use cdg;
 use cdg_renderer;
-use image;
 use glium;
+use glium::texture::{MipmapsOption, UncompressedFloatFormat};
 use std::borrow::Cow;
 use std::collections::VecDeque;
 use std::rc::Rc;
 use std::cell::RefCell;
 use ogk::ogg;
 use ogk;
+use screen;
 use types;
 
 
@@ -21,21 +22,46 @@ struct Vertex {
 
 implement_vertex!(Vertex, position, tex_coords);
 
+/// CD+G was made to fill a 4:3 television, so its pixels are a little
+/// taller than they are wide
+const DISPLAY_ASPECT: (u32, u32) = (4, 3);
+
+/// Where on a `width` by `height` surface the picture goes: as large as
+/// it will fit at the right shape, centred, with bars on either side or
+/// above and below
+fn viewport(width: u32, height: u32) -> glium::Rect {
+    let (left, top, w, h) = screen::letterbox(DISPLAY_ASPECT.0, DISPLAY_ASPECT.1, width, height);
+    glium::Rect{
+        left: left,
+        // GL counts from the bottom
+        bottom: height - top - h,
+        width: w,
+        height: h,
+    }
+}
+
 struct CdgPlayerRsrc {
     program: glium::Program,
     indices: glium::index::NoIndices,
     vtx_buffer: glium::VertexBuffer<Vertex>,
+    /// The CLUT index of each pixel of the current frame; only the
+    /// dirty region is re-uploaded
+    indices_texture: glium::texture::Texture2d,
+    /// The 16 colors of the CLUT, in a row. Changing the palette only
+    /// means uploading this again.
+    palette_texture: glium::texture::Texture2d,
 }
 
 impl CdgPlayerRsrc {
     fn new(ctx: &Rc<glium::backend::Context>) -> Self {
         let billboard_vtx = [
             // Note that the texture coordinates are inverted from GL coordinates
-            // you'd expect; this puts 0,0 at the top left corner
-            Vertex{position: [-0.5, -0.5], tex_coords: [0.0, 1.0]},
-            Vertex{position: [-0.5,  0.5], tex_coords: [0.0, 0.0]},
-            Vertex{position: [ 0.5,  0.5], tex_coords: [1.0, 0.0]},
-            Vertex{position: [ 0.5, -0.5], tex_coords: [1.0, 1.0]},
+            // you'd expect; this puts 0,0 at the top left corner. The
+            // quad fills the viewport, which does the letterboxing.
+            Vertex{position: [-1.0, -1.0], tex_coords: [0.0, 1.0]},
+            Vertex{position: [-1.0,  1.0], tex_coords: [0.0, 0.0]},
+            Vertex{position: [ 1.0,  1.0], tex_coords: [1.0, 0.0]},
+            Vertex{position: [ 1.0, -1.0], tex_coords: [1.0, 1.0]},
         ];
 
         let vertex_buffer = glium::VertexBuffer::new(ctx, &billboard_vtx).unwrap();
@@ -60,25 +86,51 @@ impl CdgPlayerRsrc {
         in vec2 v_tex_coords;
         out vec4 color;
 
-        uniform sampler2D tex;
+        uniform sampler2D indices;
+        uniform sampler2D palette;
+        // -1 if no color is transparent
+        uniform int transparent;
 
         void main() {
-            color = texture(tex, v_tex_coords);
+            // Indices mustn't be filtered, so fetch the texel directly
+            ivec2 size = textureSize(indices, 0);
+            ivec2 pos = min(ivec2(v_tex_coords * vec2(size)), size - 1);
+            int index = int(texelFetch(indices, pos, 0).r * 255.0 + 0.5);
+            if (index == transparent) {
+                color = vec4(0.0);
+            } else {
+                color = texelFetch(palette, ivec2(index, 0), 0);
+            }
         }
 "#;
 
         let program = glium::Program::from_source(ctx, vertex_shader_src, fragment_shader_src, None).unwrap();
+        let indices_texture = glium::texture::Texture2d::empty_with_format(
+            ctx, UncompressedFloatFormat::U8, MipmapsOption::NoMipmap, 300, 216).unwrap();
+        let palette_texture = glium::texture::Texture2d::empty_with_format(
+            ctx, UncompressedFloatFormat::U8U8U8U8, MipmapsOption::NoMipmap, 16, 1).unwrap();
         CdgPlayerRsrc{
             program: program,
             indices: indices,
             vtx_buffer: vertex_buffer,
+            indices_texture: indices_texture,
+            palette_texture: palette_texture,
         }
     }
 }
 
+enum QueueEntry {
+    Command(cdg::Command),
+    /// Replaces the whole state of the interpreter
+    Keyframe(cdg_renderer::Snapshot),
+}
+
 struct DecodeChannel {
-    queue: VecDeque<(u32, cdg::Command)>,
+    queue: VecDeque<(u32, QueueEntry)>,
     finished: bool,
+    /// Set when commands have been lost, until the player has thrown
+    /// away the picture they would have drawn on
+    gap: bool,
 }
 
 impl Default for DecodeChannel {
@@ -86,6 +138,7 @@ impl Default for DecodeChannel {
         DecodeChannel{
             queue: Default::default(),
             finished: false,
+            gap: false,
         }
     }
 }
@@ -100,7 +153,12 @@ pub struct CdgPlayer {
 
     current_sector: u32,
 
-    out_buffer: image::RgbaImage,
+    /// Playback speed relative to the recording
+    tempo: f64,
+
+    /// Scratch space for the dirty region of the frame: RGBA for
+    /// `render_software`, or CLUT indices for `render`
+    out_buffer: Vec<u8>,
     render_resources: Option<CdgPlayerRsrc>,
 }
 
@@ -113,33 +171,92 @@ impl CdgPlayer {
 
             current_sector: 0,
 
-            out_buffer: image::RgbaImage::new(300,216),
+            tempo: 1.,
+
+            out_buffer: Vec::with_capacity(300 * 216 * 4),
             render_resources: None,
         }
     }
 
     /// Update playback to time `time`, measured in milliseconds since
-    /// start of playback.
+    /// start of playback. At any tempo other than 1, the recording's
+    /// 75 sectors per second are spread over more or less time.
     /// # Returns
     /// 
     fn update(&mut self, time: f64) {
-        let target_sector = (time * 75. + 0.5) as u32;
+        let target_sector = (time * self.tempo * 75. + 0.5) as u32;
         let mut stream = self.cdg_stream.borrow_mut();
-        while self.current_sector < target_sector {
-            if let Some((ts, cmd)) = stream.queue.pop_front() {
+        if stream.gap {
+            // Start again from a blank screen rather than keep showing
+            // a picture that is out of date. The decoder queues
+            // nothing more until it has a keyframe, or the start of
+            // the stream, to go on.
+            stream.gap = false;
+            self.interp = cdg_renderer::CdgInterpreter::new();
+            self.current_sector = 0;
+        }
+        // All of the target sector's commands are run, so that the
+        // picture is the same whether it was reached by playing or from
+        // a keyframe
+        while self.current_sector <= target_sector {
+            if let Some((ts, entry)) = stream.queue.pop_front() {
                 if ts > target_sector {
-                    stream.queue.push_front((ts, cmd));
+                    stream.queue.push_front((ts, entry));
                     break;
                 }
-                self.interp.handle_cmd(cmd);
+                match entry {
+                    QueueEntry::Command(cmd) => self.interp.handle_cmd(cmd),
+                    QueueEntry::Keyframe(snapshot) => self.interp.restore(&snapshot),
+                }
                 self.current_sector = ts;
+            } else {
+                // Nothing has been decoded that far yet
+                break;
             }
         }
     }
 
+    /// Upload whatever has changed since the last frame to the
+    /// textures. The palette is applied by the fragment shader, so a
+    /// change of palette doesn't touch the indices.
     fn render(&mut self) {
-        use image::GenericImage;
-        self.out_buffer.copy_from(&self.interp, 0,0);
+        let rsrc = self.render_resources.as_ref().unwrap();
+        if self.interp.palette_dirty() {
+            let palette : Vec<u8> = self.interp.clut().iter()
+                .flat_map(|color| vec![color.r(), color.g(), color.b(), 255])
+                .collect();
+            let glimage = glium::texture::RawImage2d{
+                data: Cow::Owned(palette),
+                width: 16,
+                height: 1,
+                format: glium::texture::ClientFormat::U8U8U8U8,
+            };
+            rsrc.palette_texture.write(glium::Rect{left: 0, bottom: 0, width: 16, height: 1}, glimage);
+        }
+
+        if let Some(region) = self.interp.dirty_index_pixels() {
+            let width = (region.se.x - region.nw.x) as usize;
+            let height = (region.se.y - region.nw.y) as usize;
+            self.out_buffer.resize(width * height, 0);
+            self.interp.scanout_indices(region, &mut self.out_buffer, width);
+
+            let glimage = glium::texture::RawImage2d{
+                data: Cow::Borrowed(&self.out_buffer),
+                width: width as u32,
+                height: height as u32,
+                format: glium::texture::ClientFormat::U8,
+            };
+            // Texture rows are stored top-first; see the texture
+            // coordinates in CdgPlayerRsrc
+            let rect = glium::Rect{
+                left: region.nw.x as u32,
+                bottom: region.nw.y as u32,
+                width: width as u32,
+                height: height as u32,
+            };
+            rsrc.indices_texture.write(rect, glimage);
+        }
+        self.interp.clear_dirty_region();
     }
 }
 
@@ -148,32 +265,57 @@ impl <S: glium::Surface> types::VideoCodec<S> for CdgPlayer {
         self.render_resources = Some(CdgPlayerRsrc::new(ctx));
     }
     
+    #[allow(unused_variables)]
     fn render_frame(&mut self, ctx: &Rc<glium::backend::Context>, target: &mut S, when: f64) {
         self.update(when);
         self.render();
         let rsrc = self.render_resources.as_ref().unwrap();
 
-        // Render
-        target.clear_color(0.0, 0.0, 1.0, 1.0);
+        // Render. The layout is worked out afresh every frame, so it
+        // follows the window as it is resized or made fullscreen.
+        target.clear_color(0.0, 0.0, 0.0, 1.0);
 
-        let glimage = glium::texture::RawImage2d{
-            data: Cow::Borrowed(&self.out_buffer),
-            width: 300,
-            height: 216,
-            format: glium::texture::ClientFormat::U8U8U8U8,
+        let (width, height) = target.get_dimensions();
+        let params = glium::DrawParameters{
+            viewport: Some(viewport(width, height)),
+            .. Default::default()
         };
-        //let glimage = glium::texture::RawImage2d::from_raw_rgba_reversed(image.into_raw(), (300,216));
-        let texture = glium::texture::Texture2d::new(ctx, glimage).unwrap();
         let uniforms = uniform!{
-            tex: texture.sampled().magnify_filter(glium::uniforms::MagnifySamplerFilter::Nearest),
+            indices: &rsrc.indices_texture,
+            palette: &rsrc.palette_texture,
+            transparent: self.interp.transparent().map_or(-1, |color| color as i32),
         };
-        target.draw(&rsrc.vtx_buffer, &rsrc.indices, &rsrc.program, &uniforms, &Default::default()).unwrap();
+        target.draw(&rsrc.vtx_buffer, &rsrc.indices, &rsrc.program, &uniforms, &params).unwrap();
+    }
+
+    fn set_tempo(&mut self, tempo: f64) {
+        self.tempo = tempo;
+    }
+
+    fn frame_size(&self) -> (u32, u32) {
+        (300, 216)
+    }
+
+    fn render_software(&mut self, buffer: &mut [u8], when: f64) -> Option<cdg_renderer::Rectangle<u16>> {
+        use cdg_renderer::PixelFormat;
+        self.update(when);
+        let dirty = self.interp.dirty_pixels();
+        if let Some(region) = dirty {
+            let offset = (region.nw.y as usize * 300 + region.nw.x as usize) * 4;
+            self.interp.scanout(region, &mut buffer[offset..], 300 * 4, PixelFormat::Rgba);
+            self.interp.clear_dirty_region();
+        }
+        dirty
     }
 }
 
 struct CdgDecoder {
     header: ogk::cdg::CdgHeader,
     queue: CommandQueue,
+    /// False when commands have been lost, and so the picture they
+    /// draw on is unknown. Commands are dropped until the next
+    /// keyframe brings the player back in sync.
+    synced: bool,
 }
 
 impl ogg::BitstreamDecoder for CdgDecoder {
@@ -186,23 +328,42 @@ impl ogg::BitstreamDecoder for CdgDecoder {
         use ogk::cdg::PacketType;
         let last_sector = last_granule >> 20;
         let last_keyframe = last_granule & 0xFFFFF;
+        if last_granule == 0 {
+            // Starting over; the start of the stream is as good as a
+            // keyframe
+            self.synced = true;
+        }
         let mut cur_sector = 0;
         let mut queue = self.queue.borrow_mut();
         match self.header.decode_packet(packet) {
             Some((PacketType::Command, cmds)) => {
                 for sector in cmds.chunks(96) {
                     cur_sector += 1;
+                    // Commands are no use without the picture they
+                    // draw on
+                    if !self.synced {
+                        continue;
+                    }
                     for cmd in cdg::SectorIter::new(sector) {
-                        queue.queue.push_back( ((last_sector + cur_sector) as u32, cmd) );
+                        queue.queue.push_back( ((last_sector + cur_sector) as u32, QueueEntry::Command(cmd)) );
                     }
                 }
-                println!("Processed sector {}; queue at {} commands", last_sector + cur_sector, queue.queue.len());
-                (last_sector + cur_sector) << 20 | (last_keyframe + cur_sector) & 0xFFFF
+                (last_sector + cur_sector) << 20 | last_keyframe
             },
-            Some((PacketType::Keyframe, _)) => {
-                println!("keyframe?");
-                // We ignore the keyframe
-                last_sector & !0xFFFFF
+            Some((PacketType::Keyframe, keyframe)) => {
+                // A keyframe describes the state that the commands
+                // already in the queue lead to, so it only needs to be
+                // applied if some of them went missing.
+                if !self.synced {
+                    match ogk::cdg::decode_keyframe(&keyframe) {
+                        Some(snapshot) => {
+                            queue.queue.push_back((last_sector as u32, QueueEntry::Keyframe(snapshot)));
+                            self.synced = true;
+                        },
+                        None => println!("Invalid keyframe at sector {}", last_sector),
+                    }
+                }
+                last_sector << 20 | last_sector & 0xFFFFF
             },
             None => {
                 println!("Parse failure");
@@ -214,7 +375,18 @@ impl ogg::BitstreamDecoder for CdgDecoder {
             },
         }
     }
-    fn notice_gap(&mut self) {}
+    /// The picture can only be rebuilt from the last keyframe, or from
+    /// the start if there hasn't been one
+    fn restart_time(&self, granule: u64) -> Option<u64> {
+        Some(ogk::cdg::keyframe_time(granule))
+    }
+    fn notice_gap(&mut self) {
+        self.synced = false;
+        // Whatever is queued leads up to the gap, not past it
+        let mut queue = self.queue.borrow_mut();
+        queue.queue.clear();
+        queue.gap = true;
+    }
     fn finish(&mut self) {
         self.queue.borrow_mut().finished = true;
     }
@@ -228,9 +400,114 @@ pub fn try_start_stream<S: glium::Surface>(raw_header: &[u8]) -> Option<(Box<ogg
         let decoder = Box::new(CdgDecoder{
             header: header,
             queue: queue.clone(),
+            // The start of the stream has an implicit keyframe
+            synced: true,
         }) as Box<ogg::BitstreamDecoder>;
         let sd = types::StreamDesc::Video(Some(Box::new(CdgPlayer::new(queue))));
         (decoder, sd)
     })
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::{viewport, CdgDecoder, CdgPlayer, CommandQueue};
+    use cdg;
+    use cdg_renderer::{Position, Rectangle};
+    use glium::Rect;
+    use ogk;
+    use ogk::cdg::{CdgHeader, Compression, PacketType};
+    use ogk::ogg::BitstreamDecoder;
+    use types::VideoCodec;
+
+    /// An uncompressed command packet of `sectors` sectors, with
+    /// `cmds` in its first sector
+    fn packet(cmds: &[cdg::Command], sectors: usize) -> Vec<u8> {
+        let mut writer = cdg::SubchannelStreamWriter::new(vec![PacketType::Command.to_u8(), sectors as u8]);
+        writer.write_sector(cmds).unwrap();
+        for _ in 1..sectors {
+            writer.write_sector(&[]).unwrap();
+        }
+        writer.into_inner()
+    }
+
+    /// A packet that clears the screen to `color` in its first sector
+    fn clear_packet(color: u8, sectors: usize) -> Vec<u8> {
+        packet(&[cdg::Command::MemoryPreset{color: color, repeat: 0}], sectors)
+    }
+
+    fn decoder(queue: &CommandQueue) -> CdgDecoder {
+        CdgDecoder{
+            header: CdgHeader{compression: Compression::None, sectors_per_packet: 75},
+            queue: queue.clone(),
+            synced: true,
+        }
+    }
+
+    #[test]
+    fn gaps_blank_the_screen_until_a_keyframe() {
+        let queue = CommandQueue::default();
+        let mut decoder = decoder(&queue);
+        let mut player = CdgPlayer::new(queue);
+        let blank = player.interp.snapshot();
+
+        let granule = decoder.process_packet(&clear_packet(3, 75), 0);
+        player.update(1.);
+        let cleared = player.interp.snapshot();
+        assert!(cleared != blank);
+
+        // Neither what was queued before the gap nor what comes after
+        // it is drawn on the old picture
+        decoder.process_packet(&clear_packet(5, 75), granule);
+        decoder.notice_gap();
+        player.update(2.);
+        assert_eq!(player.interp.snapshot(), blank);
+        let granule = decoder.process_packet(&clear_packet(5, 75), 150 << 20);
+        player.update(3.);
+        assert_eq!(player.interp.snapshot(), blank);
+
+        // Until a keyframe brings it back
+        let keyframe = [vec![PacketType::Keyframe.to_u8(), 0], ogk::cdg::encode_keyframe(&cleared)].concat();
+        let granule = decoder.process_packet(&keyframe, granule);
+        decoder.process_packet(&clear_packet(5, 75), granule);
+        player.update(3.);
+        assert_eq!(player.interp.snapshot(), cleared);
+        player.update(4.);
+        assert!(player.interp.snapshot() != cleared);
+    }
+
+    fn render(player: &mut CdgPlayer, frame: &mut [u8], time: f64) -> Option<Rectangle<u16>> {
+        VideoCodec::<::glium::Frame>::render_software(player, frame, time)
+    }
+
+    #[test]
+    fn software_rendering_reports_what_changed() {
+        let queue = CommandQueue::default();
+        let mut decoder = decoder(&queue);
+        let mut player = CdgPlayer::new(queue);
+        let mut frame = vec![0; 300 * 216 * 4];
+        let everything = Rectangle::new(Position::new(0, 0), Position::new(300, 216));
+        assert_eq!(render(&mut player, &mut frame, 0.), Some(everything));
+        assert_eq!(render(&mut player, &mut frame, 0.), None);
+
+        let tile = cdg::Command::TileNormal{tile: cdg::Tile{
+            pos: (1, 2),
+            color: (0, 15),
+            content: [0x3F; 12],
+            channel: 0,
+        }};
+        decoder.process_packet(&packet(&[tile], 75), 0);
+        assert_eq!(render(&mut player, &mut frame, 0.5),
+                   Some(Rectangle::new(Position::new(6, 24), Position::new(12, 36))));
+        assert_eq!(render(&mut player, &mut frame, 0.6), None);
+    }
+
+    #[test]
+    fn letterboxing() {
+        assert_eq!(viewport(800, 600), Rect{left: 0, bottom: 0, width: 800, height: 600});
+        assert_eq!(viewport(1920, 1080), Rect{left: 240, bottom: 0, width: 1440, height: 1080});
+        assert_eq!(viewport(600, 600), Rect{left: 0, bottom: 75, width: 600, height: 450});
+        // Rounding leaves the odd pixel at the bottom
+        assert_eq!(viewport(400, 301), Rect{left: 0, bottom: 1, width: 400, height: 300});
+    }
+}
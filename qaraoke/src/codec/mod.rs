@@ -1,12 +1,72 @@
 use ogk::ogg;
 use glium;
+use soxr;
+use std::os::raw::c_ulong;
+use std::str::FromStr;
 
 use types;
 
 pub mod cdg;
 pub mod mp3;
 
-pub fn identify_header<S: glium::Surface>(header: &[u8]) -> Option<(Box<ogg::BitstreamDecoder>, types::StreamDesc<S>)> {
+/// How hard the resampler works to convert audio to 48kHz
+#[derive(Copy,Clone,Debug,PartialEq,Eq)]
+pub enum ResamplerQuality {
+    Quick,
+    Low,
+    Medium,
+    High,
+    VeryHigh,
+}
+
+pub const RESAMPLER_QUALITIES: [&'static str; 5] = ["quick", "low", "medium", "high", "very-high"];
+
+impl ResamplerQuality {
+    /// The soxr quality recipe
+    pub fn recipe(self) -> c_ulong {
+        match self {
+            ResamplerQuality::Quick => soxr::sys::SOXR_QQ,
+            ResamplerQuality::Low => soxr::sys::SOXR_LQ,
+            ResamplerQuality::Medium => soxr::sys::SOXR_MQ,
+            ResamplerQuality::High => soxr::sys::SOXR_HQ,
+            ResamplerQuality::VeryHigh => soxr::sys::SOXR_VHQ,
+        }
+    }
+}
+
+impl FromStr for ResamplerQuality {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "quick" => Ok(ResamplerQuality::Quick),
+            "low" => Ok(ResamplerQuality::Low),
+            "medium" => Ok(ResamplerQuality::Medium),
+            "high" => Ok(ResamplerQuality::High),
+            "very-high" => Ok(ResamplerQuality::VeryHigh),
+            _ => Err(format!("Unknown resampler quality {:?}; expected one of {}", s, RESAMPLER_QUALITIES.join(", "))),
+        }
+    }
+}
+
+/// Settings that apply to every stream that is decoded
+#[derive(Copy,Clone,Debug)]
+pub struct Options {
+    pub resampler_quality: ResamplerQuality,
+    /// Whether vocal reduction starts out switched on
+    pub vocal_reduction: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options{
+            resampler_quality: ResamplerQuality::Low,
+            vocal_reduction: false,
+        }
+    }
+}
+
+pub fn identify_header<S: glium::Surface>(header: &[u8], options: &Options) -> Option<(Box<ogg::BitstreamDecoder>, types::StreamDesc<S>)> {
     None.or_else(|| cdg::try_start_stream(header))
-        .or_else(|| mp3::try_start_stream(header))
+        .or_else(|| mp3::try_start_stream(header, options))
 }
@@ -1,25 +1,61 @@
 use ogk::ogg;
 use mpg123;
-use types;
+use types::{self, SAMPLE_RATE};
 use glium;
 use rt::ringbuffer;
+use std::cmp;
 use std::sync::mpsc;
 use std::vec;
 use std::os::raw as ostyp;
 use soxr;
 
+use dsp::limiter::Limiter;
+use dsp::stretch::TimeStretch;
+use dsp::vocal::VocalReducer;
+
+/// The furthest that `set_key` will transpose, in semitones
+const MAX_KEY_CHANGE: i8 = 12;
+
+/// How the audio should be changed from the recording. The frontend
+/// sends a fresh copy to the decoder whenever it changes, and the
+/// decoder applies the latest one to each packet.
+#[derive(Copy,Clone,Debug)]
+struct Adjustments {
+    /// In semitones
+    key: i8,
+    /// Speed relative to the recording
+    tempo: f64,
+    vocal_reduction: bool,
+    /// Loudness normalization, as a linear gain
+    gain: Option<f32>,
+}
+
 struct Mp3Decoder {
     queue_sender: mpsc::Sender<Vec<types::Sample>>,
     decoder: mpg123::Handle<f32>,
     sample_frequency: u64,
     aux_headers: usize,
     soxr: soxr::Soxr<types::Sample, types::Sample>,
+    adjustments: Adjustments,
+    /// Changes to `adjustments` from the frontend
+    adjustment_receiver: mpsc::Receiver<Adjustments>,
+    stretch: TimeStretch,
+    /// Scratch space for the output of `stretch`
+    stretched: Vec<types::Sample>,
+    /// The input rate that the resampler was last set to
+    last_rate: Option<f64>,
+    vocals: VocalReducer,
+    /// Only set up once normalization has been asked for
+    limiter: Option<Limiter>,
 }
 
 struct Mp3DecoderFrontend {
     receiver: mpsc::Receiver<Vec<types::Sample>>,
     ringbuffer: Option<ringbuffer::Writer<types::Sample>>,
     queued_samples: Option<vec::IntoIter<types::Sample>>,
+    /// The frontend's copy of the decoder's adjustments
+    adjustments: Adjustments,
+    adjustment_sender: mpsc::Sender<Adjustments>,
 }
 
 fn as_interlaced<T>(buf: &mut [[T; 2]]) -> &mut [T] {
@@ -45,20 +81,48 @@ fn as_frames<T>(buf: &[T]) -> &[[T;2]] {
 
 
 impl Mp3Decoder {
+    /// To shift the pitch, the audio is first stretched by the pitch
+    /// ratio, then resampled as though it were recorded that much
+    /// faster, which brings it back to its original duration. A tempo
+    /// change is just more stretching.
     fn handle_samples(&mut self, rate: f64, buf: &[f32]) {
-        let mut obuf : Vec<[f32;2]> = vec![[0.0;2]; (buf.len() as f64 / 2. * 48000. / rate + 0.5) as usize];
-        self.soxr.change_rate(rate, 48000., 0).unwrap();
+        while let Ok(adjustments) = self.adjustment_receiver.try_recv() {
+            self.adjustments = adjustments;
+        }
+        let adjustments = self.adjustments;
+        let key = cmp::max(-MAX_KEY_CHANGE, cmp::min(MAX_KEY_CHANGE, adjustments.key));
+        let pitch = (key as f64 / 12.).exp2();
+        self.stretch.set_ratio(pitch / adjustments.tempo);
+        self.vocals.set_enabled(adjustments.vocal_reduction);
+        if let Some(gain) = adjustments.gain {
+            self.limiter.get_or_insert_with(|| Limiter::new(gain)).set_gain(gain);
+        }
+        self.stretch.process(as_frames(buf), &mut self.stretched);
+        self.soxr.change_rate(rate * pitch, SAMPLE_RATE as f64, 0).unwrap();
+        self.last_rate = Some(rate * pitch);
+        self.resample(rate * pitch);
+    }
+
+    /// Resample whatever is in `stretched` and pass it on
+    fn resample(&mut self, rate: f64) {
+        let mut obuf : Vec<[f32;2]> = vec![[0.0;2]; (self.stretched.len() as f64 * SAMPLE_RATE as f64 / rate + 0.5) as usize];
         // This function is deceptively unsafe; regardless of type
         // parameters, it will always use the types given in the initializer (f32/f32)
-        if let Ok(done) = self.soxr.process(Some(as_frames(buf)), &mut obuf[..]) {
+        if let Ok(done) = self.soxr.process(Some(&self.stretched[..]), &mut obuf[..]) {
             obuf.truncate(done);
+            self.post_process(&mut obuf);
             self.queue_sender.send(obuf).ok();
         } else {
             panic!("Soxr somehow failed");
         }
+        self.stretched.clear();
     }
 
     fn handle_finish(&mut self) {
+        if let Some(rate) = self.last_rate {
+            self.stretch.finish(&mut self.stretched);
+            self.resample(rate);
+        }
         loop {
             let mut obuf = vec![[0.;2]; 512];
             if let Ok(odone) = self.soxr.process(None, &mut obuf[..]) {
@@ -66,11 +130,25 @@ impl Mp3Decoder {
                     break;
                 }
                 obuf.truncate(odone);
+                self.post_process(&mut obuf);
                 self.queue_sender.send(obuf).ok();
             } else {
                 panic!("Soxr somehow failed");
             }
         }
+        if let Some(ref mut limiter) = self.limiter {
+            let mut obuf = Vec::new();
+            limiter.finish(&mut obuf);
+            self.queue_sender.send(obuf).ok();
+        }
+    }
+
+    /// Everything that happens at 48kHz
+    fn post_process(&mut self, buf: &mut Vec<types::Sample>) {
+        self.vocals.process(buf);
+        if let Some(ref mut limiter) = self.limiter {
+            limiter.process(buf);
+        }
     }
 }
 
@@ -109,6 +187,14 @@ impl ogg::BitstreamDecoder for Mp3Decoder {
     fn finish(&mut self) { self.handle_finish(); }
 }
 
+impl Mp3DecoderFrontend {
+    /// Change the adjustments, and pass them on to the decoder
+    fn adjust<F: FnOnce(&mut Adjustments)>(&mut self, change: F) {
+        change(&mut self.adjustments);
+        self.adjustment_sender.send(self.adjustments).ok();
+    }
+}
+
 impl types::AudioCodec for Mp3DecoderFrontend {
     fn quality(&self) -> u32 { 240_000 }
 
@@ -118,6 +204,22 @@ impl types::AudioCodec for Mp3DecoderFrontend {
 
     fn min_buffer_size(&self) -> u32 { 1152 }
 
+    fn set_key(&mut self, semitones: i8) {
+        self.adjust(|adjustments| adjustments.key = semitones);
+    }
+
+    fn set_tempo(&mut self, tempo: f64) {
+        self.adjust(|adjustments| adjustments.tempo = tempo);
+    }
+
+    fn set_vocal_reduction(&mut self, enabled: bool) {
+        self.adjust(|adjustments| adjustments.vocal_reduction = enabled);
+    }
+
+    fn set_gain(&mut self, gain: f32) {
+        self.adjust(|adjustments| adjustments.gain = Some(gain));
+    }
+
     fn do_needful(&mut self) {
         if self.ringbuffer.is_none() {
             return
@@ -154,7 +256,7 @@ impl types::AudioCodec for Mp3DecoderFrontend {
     }
 }
 
-pub fn try_start_stream<S: glium::Surface>(raw_header: &[u8]) -> Option<(Box<ogg::BitstreamDecoder>, types::StreamDesc<S>)> {
+pub fn try_start_stream<S: glium::Surface>(raw_header: &[u8], options: &super::Options) -> Option<(Box<ogg::BitstreamDecoder>, types::StreamDesc<S>)> {
     use byteorder::{ByteOrder, LittleEndian};
     if &raw_header[0..9] != b"OggMP3\0\0\0" {
         return None;
@@ -162,9 +264,17 @@ pub fn try_start_stream<S: glium::Surface>(raw_header: &[u8]) -> Option<(Box<ogg
     let aux_headers = raw_header[11] as usize;
     let sample_freq = LittleEndian::read_u32(&raw_header[16..20]) as u64;
     let (sq_sender, sq_receiver) = mpsc::channel();
+    let (adjustment_sender, adjustment_receiver) = mpsc::channel();
+    let adjustments = Adjustments{
+        key: 0,
+        tempo: 1.,
+        vocal_reduction: options.vocal_reduction,
+        gain: None,
+    };
 
     let soxr = soxr::SoxrBuilder::new()
-        .set_quality(soxr::sys::SOXR_LQ, soxr::sys::VR)
+        .with_max_ratio(sample_freq as f64 * (MAX_KEY_CHANGE as f64 / 12.).exp2() / SAMPLE_RATE as f64)
+        .set_quality(options.resampler_quality.recipe(), soxr::sys::VR)
         .build()
         .unwrap();
     
@@ -179,6 +289,13 @@ pub fn try_start_stream<S: glium::Surface>(raw_header: &[u8]) -> Option<(Box<ogg
         },
         sample_frequency: sample_freq,
         aux_headers: aux_headers,
+        adjustments: adjustments,
+        adjustment_receiver: adjustment_receiver,
+        stretch: TimeStretch::new(),
+        stretched: Vec::new(),
+        last_rate: None,
+        vocals: VocalReducer::new(),
+        limiter: None,
     }) as Box<ogg::BitstreamDecoder>;
 
     let frontend = types::StreamDesc::Audio(
@@ -186,6 +303,8 @@ pub fn try_start_stream<S: glium::Surface>(raw_header: &[u8]) -> Option<(Box<ogg
             receiver: sq_receiver,
             ringbuffer: None,
             queued_samples: None,
+            adjustments: adjustments,
+            adjustment_sender: adjustment_sender,
         }))
     );
 
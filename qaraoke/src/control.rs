@@ -0,0 +1,217 @@
+//! Changes to the queue while the player runs. The host writes
+//! commands to a named pipe (or a file, which is read once), one per
+//! line with fields separated by tabs, as in a queue file:
+//!
+//! * `add SINGER PATH [KEY [TEMPO]]`
+//! * `remove ID`
+//! * `move ID POSITION`, within the song's singer's list
+//! * `move-singer NAME POSITION`, in the rotation
+//! * `remove-singer NAME`
+//! * `skip-turn`, passing over whoever is up next
+//! * `set ID KEY TEMPO`
+//! * `list`, which prints the queue with the IDs of its songs
+//!
+//! A thread reads the commands and hands them to the player, which
+//! applies them between video frames. Changes to the song that is
+//! playing wait until it comes round again.
+
+use std::fs;
+use std::io::{BufRead, BufReader};
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::mpsc;
+use std::thread;
+
+use queue::{self, EntryId, Queue, Settings};
+
+#[derive(Clone,Debug,PartialEq)]
+pub enum Command {
+    Add(String, PathBuf, Settings),
+    Remove(EntryId),
+    Move(EntryId, usize),
+    MoveSinger(String, usize),
+    RemoveSinger(String),
+    SkipTurn,
+    Set(EntryId, Settings),
+    List,
+}
+
+fn parse_number<T: FromStr>(field: &str, what: &str) -> Result<T, String> {
+    field.trim().parse().map_err(|_| format!("invalid {} {:?}", what, field))
+}
+
+impl FromStr for Command {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let fields : Vec<&str> = s.split('\t').collect();
+        let args = &fields[1..];
+        let usage = match (fields[0], args.len()) {
+            ("add", _) => return queue::parse_song(args).map(|(singer, path, settings)| Command::Add(singer, path, settings)),
+            ("remove", 1) => return Ok(Command::Remove(try!(parse_number(args[0], "ID")))),
+            ("remove", _) => "remove<tab>ID",
+            ("move", 2) => return Ok(Command::Move(try!(parse_number(args[0], "ID")),
+                                                   try!(parse_number(args[1], "position")))),
+            ("move", _) => "move<tab>ID<tab>POSITION",
+            ("move-singer", 2) => return Ok(Command::MoveSinger(args[0].to_owned(),
+                                                                try!(parse_number(args[1], "position")))),
+            ("move-singer", _) => "move-singer<tab>NAME<tab>POSITION",
+            ("remove-singer", 1) => return Ok(Command::RemoveSinger(args[0].to_owned())),
+            ("remove-singer", _) => "remove-singer<tab>NAME",
+            ("skip-turn", 0) => return Ok(Command::SkipTurn),
+            ("skip-turn", _) => "skip-turn",
+            ("set", 3) => return Ok(Command::Set(try!(parse_number(args[0], "ID")), Settings{
+                key: try!(parse_number(args[1], "key")),
+                tempo: try!(queue::parse_tempo(args[2])),
+            })),
+            ("set", _) => "set<tab>ID<tab>KEY<tab>TEMPO",
+            ("list", 0) => return Ok(Command::List),
+            ("list", _) => "list",
+            _ => return Err(format!("Unknown command {:?}", fields[0])),
+        };
+        Err(format!("expected {}", usage))
+    }
+}
+
+impl Command {
+    /// Carry out the command on `queue`. Returns what there is to tell
+    /// the host, if anything; it's up to the player where that goes.
+    pub fn apply(self, queue: &mut Queue) -> Result<Option<String>, String> {
+        let found = match self {
+            Command::Add(singer, path, settings) => {
+                let id = queue.add(&singer, path, settings);
+                return Ok(Some(format!("Queued song {}", id)));
+            },
+            Command::Remove(id) => queue.remove(id).is_some(),
+            Command::Move(id, position) => queue.move_entry(id, position),
+            Command::MoveSinger(ref name, position) => queue.move_singer(name, position),
+            Command::RemoveSinger(ref name) => {
+                let found = queue.singers().contains(&&name[..]);
+                queue.remove_singer(name);
+                found
+            },
+            Command::SkipTurn => {
+                queue.skip_turn();
+                true
+            },
+            Command::Set(id, settings) => queue.set_settings(id, settings),
+            Command::List => return Ok(Some(list(queue))),
+        };
+        if found {
+            Ok(None)
+        } else {
+            Err("No such song or singer in the queue".to_owned())
+        }
+    }
+}
+
+fn list(queue: &Queue) -> String {
+    if queue.is_empty() {
+        return "The queue is empty".to_owned();
+    }
+    let mut list = format!("{} songs; rotation: {}", queue.len(), queue.singers().join(", "));
+    for entry in queue.upcoming() {
+        list.push_str(&format!("\n{}\t{}\t{}\t{:+}\t{}", entry.id, entry.singer, entry.path.display(),
+                               entry.settings.key, entry.settings.tempo));
+    }
+    list
+}
+
+/// Reads commands from the host
+pub struct Control {
+    receiver: mpsc::Receiver<Command>,
+}
+
+impl Control {
+    /// Start reading commands from `path`
+    pub fn open(path: &Path) -> Result<Self, String> {
+        let fifo = try!(fs::metadata(path).map_err(|e| format!("{}: {}", path.display(), e))).file_type().is_fifo();
+        let (sender, receiver) = mpsc::channel();
+        let path = path.to_owned();
+        try!(thread::Builder::new()
+             .name("control".to_owned())
+             .spawn(move || run(&path, fifo, sender))
+             .map_err(|e| e.to_string()));
+        Ok(Control{receiver: receiver})
+    }
+
+    /// The commands that have come in since the last poll
+    pub fn poll(&self) -> Vec<Command> {
+        self.receiver.try_iter().collect()
+    }
+}
+
+fn run(path: &Path, fifo: bool, sender: mpsc::Sender<Command>) {
+    loop {
+        // Opening a pipe waits for a writer, and reading it ends when
+        // the writer closes it, so it is opened again for the next
+        let file = match fs::File::open(path) {
+            Ok(file) => file,
+            Err(e) => {
+                eprintln!("qaraoke: {}: {}", path.display(), e);
+                return;
+            },
+        };
+        for line in BufReader::new(file).lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(e) => {
+                    eprintln!("qaraoke: {}: {}", path.display(), e);
+                    return;
+                },
+            };
+            if line.trim().is_empty() {
+                continue;
+            }
+            match line.parse() {
+                Ok(command) => if sender.send(command).is_err() {
+                    // The player has gone
+                    return;
+                },
+                Err(e) => eprintln!("qaraoke: {}: {}", line, e),
+            }
+        }
+        if !fifo {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        assert_eq!("add\talice\ta1.ogg\t-2".parse(), Ok(Command::Add("alice".to_owned(), PathBuf::from("a1.ogg"),
+                                                                      Settings{key: -2, tempo: 1.})));
+        assert_eq!("move\t3\t0".parse(), Ok(Command::Move(3, 0)));
+        assert_eq!("move-singer\tbob smith\t1".parse(), Ok(Command::MoveSinger("bob smith".to_owned(), 1)));
+        assert_eq!("set\t3\t1\t0.9".parse(), Ok(Command::Set(3, Settings{key: 1, tempo: 0.9})));
+        assert_eq!("skip-turn".parse(), Ok(Command::SkipTurn));
+        assert!("remove".parse::<Command>().is_err());
+        assert!("remove\tthree".parse::<Command>().is_err());
+        assert!("set\t3\t1\t0".parse::<Command>().is_err());
+        assert!("dance".parse::<Command>().is_err());
+    }
+
+    #[test]
+    fn apply() {
+        let mut queue = Queue::new();
+        for command in &["add\talice\ta1", "add\talice\ta2", "add\tbob\tb1", "move\t1\t0",
+                         "move-singer\tbob\t0", "set\t0\t2\t1"] {
+            command.parse::<Command>().unwrap().apply(&mut queue).unwrap();
+        }
+        let order : Vec<_> = queue.upcoming().iter().map(|e| (e.id, e.settings.key)).collect();
+        assert_eq!(order, vec![(2, 0), (1, 0), (0, 2)]);
+
+        Command::SkipTurn.apply(&mut queue).unwrap();
+        assert_eq!(queue.peek_next().unwrap().id, 1);
+        Command::RemoveSinger("alice".to_owned()).apply(&mut queue).unwrap();
+        assert_eq!(queue.len(), 1);
+        assert!(Command::Remove(0).apply(&mut queue).is_err());
+        assert!(Command::RemoveSinger("alice".to_owned()).apply(&mut queue).is_err());
+        assert_eq!(Command::List.apply(&mut queue), Ok(Some("1 songs; rotation: bob\n2\tbob\tb1\t+0\t1".to_owned())));
+    }
+}
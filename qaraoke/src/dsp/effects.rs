@@ -0,0 +1,214 @@
+//! Echo and reverb for the microphones. Unlike the rest of `dsp`,
+//! these run in the audio callback, so every buffer is allocated up
+//! front and changing the settings never allocates.
+
+use types::{Sample, SAMPLE_RATE};
+
+/// The longest echo delay, in seconds
+pub const MAX_ECHO_DELAY: f32 = 1.;
+
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub struct EffectSettings {
+    /// Time between repeats, in seconds, up to `MAX_ECHO_DELAY`
+    pub echo_delay: f32,
+    /// How much of each repeat comes back in the next, from 0 to 1
+    pub echo_feedback: f32,
+    /// How loud the echo is next to the dry signal
+    pub echo_level: f32,
+    /// How loud the reverb is next to the dry signal
+    pub reverb_level: f32,
+    /// How long the reverb rings on, from 0 to 1
+    pub reverb_size: f32,
+}
+
+impl Default for EffectSettings {
+    fn default() -> Self {
+        EffectSettings{
+            echo_delay: 0.25,
+            echo_feedback: 0.3,
+            echo_level: 0.,
+            reverb_level: 0.,
+            reverb_size: 0.7,
+        }
+    }
+}
+
+/// A fixed-size circular buffer of past samples
+struct DelayLine {
+    buffer: Vec<f32>,
+    position: usize,
+}
+
+impl DelayLine {
+    fn new(len: usize) -> Self {
+        DelayLine{
+            buffer: vec![0.; len],
+            position: 0,
+        }
+    }
+
+    /// The sample written `delay` samples ago, where `delay` is at
+    /// least 1 and at most the length of the line
+    fn read(&self, delay: usize) -> f32 {
+        let len = self.buffer.len();
+        self.buffer[(self.position + len - delay) % len]
+    }
+
+    fn write(&mut self, x: f32) {
+        self.buffer[self.position] = x;
+        self.position = (self.position + 1) % self.buffer.len();
+    }
+}
+
+/// A feedback comb filter with a lowpass in the loop, which is what
+/// makes the reverb tail darken as it decays
+struct Comb {
+    line: DelayLine,
+    filtered: f32,
+}
+
+impl Comb {
+    fn process(&mut self, x: f32, feedback: f32, damping: f32) -> f32 {
+        let len = self.line.buffer.len();
+        let y = self.line.read(len);
+        self.filtered = y * (1. - damping) + self.filtered * damping;
+        self.line.write(x + self.filtered * feedback);
+        y
+    }
+}
+
+struct Allpass {
+    line: DelayLine,
+}
+
+impl Allpass {
+    fn process(&mut self, x: f32) -> f32 {
+        let len = self.line.buffer.len();
+        let delayed = self.line.read(len);
+        self.line.write(x + delayed * 0.5);
+        delayed - x
+    }
+}
+
+/// Freeverb's tunings, scaled from 44.1kHz to 48kHz
+const COMBS: [usize; 4] = [1215, 1293, 1390, 1476];
+const ALLPASSES: [usize; 2] = [605, 480];
+const DAMPING: f32 = 0.2;
+
+/// A mono echo followed by a Schroeder reverb, in parallel with the
+/// dry signal
+pub struct Effects {
+    settings: EffectSettings,
+    echo: DelayLine,
+    combs: Vec<Comb>,
+    allpasses: Vec<Allpass>,
+}
+
+impl Effects {
+    pub fn new(settings: EffectSettings) -> Self {
+        let mut effects = Effects{
+            settings: settings,
+            echo: DelayLine::new((MAX_ECHO_DELAY * SAMPLE_RATE as f32) as usize),
+            combs: COMBS.iter().map(|&len| Comb{line: DelayLine::new(len), filtered: 0.}).collect(),
+            allpasses: ALLPASSES.iter().map(|&len| Allpass{line: DelayLine::new(len)}).collect(),
+        };
+        effects.configure(settings);
+        effects
+    }
+
+    /// Change the settings, clamping them to sensible ranges. The
+    /// echo and reverb tails carry on.
+    pub fn configure(&mut self, settings: EffectSettings) {
+        let clamp = |x: f32, max: f32| x.max(0.).min(max);
+        self.settings = EffectSettings{
+            echo_delay: clamp(settings.echo_delay, MAX_ECHO_DELAY),
+            echo_feedback: clamp(settings.echo_feedback, 0.95),
+            echo_level: clamp(settings.echo_level, 1.),
+            reverb_level: clamp(settings.reverb_level, 1.),
+            reverb_size: clamp(settings.reverb_size, 1.),
+        };
+    }
+
+    pub fn process(&mut self, x: f32) -> f32 {
+        let settings = self.settings;
+        let delay = ((settings.echo_delay * SAMPLE_RATE as f32) as usize).max(1).min(self.echo.buffer.len());
+        let echo = self.echo.read(delay);
+        self.echo.write(x + echo * settings.echo_feedback);
+        let x = x + echo * settings.echo_level;
+
+        // Freeverb's room size maps 0..1 onto feedback of 0.7..0.98
+        let feedback = 0.7 + settings.reverb_size * 0.28;
+        let mut wet = 0.;
+        for comb in self.combs.iter_mut() {
+            wet += comb.process(x, feedback, DAMPING);
+        }
+        for allpass in self.allpasses.iter_mut() {
+            wet = allpass.process(wet);
+        }
+        // The combs add up to about four times the input
+        x + wet * settings.reverb_level / COMBS.len() as f32
+    }
+}
+
+/// Up to two microphones, one on each channel of the input, mixed down
+/// to mono through the effects and then into both output channels
+pub struct MicMixer {
+    pub gains: [f32; 2],
+    pub effects: Effects,
+}
+
+impl MicMixer {
+    pub fn new() -> Self {
+        MicMixer{
+            gains: [1.; 2],
+            effects: Effects::new(Default::default()),
+        }
+    }
+
+    pub fn process(&mut self, input: Sample) -> Sample {
+        let y = self.effects.process(input[0] * self.gains[0] + input[1] * self.gains[1]);
+        [y, y]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn impulse_response(settings: EffectSettings, len: usize) -> Vec<f32> {
+        let mut effects = Effects::new(settings);
+        (0..len).map(|i| effects.process(if i == 0 { 1. } else { 0. })).collect()
+    }
+
+    #[test]
+    fn dry() {
+        let response = impulse_response(Default::default(), 48000);
+        assert_eq!(response[0], 1.);
+        assert!(response[1..].iter().all(|&x| x == 0.));
+    }
+
+    #[test]
+    fn echo() {
+        let response = impulse_response(EffectSettings{
+            echo_delay: 0.1,
+            echo_feedback: 0.5,
+            echo_level: 0.8,
+            ..Default::default()
+        }, 48000);
+        let repeats : Vec<(usize, f32)> = response.iter().cloned().enumerate().filter(|&(_, x)| x != 0.).collect();
+        assert_eq!(&repeats[..4], &[(0, 1.), (4800, 0.8), (9600, 0.4), (14400, 0.2)]);
+    }
+
+    #[test]
+    fn reverb_decays() {
+        let response = impulse_response(EffectSettings{
+            reverb_level: 1.,
+            ..Default::default()
+        }, 96000);
+        let energy = |range: &[f32]| range.iter().map(|x| x * x).sum::<f32>();
+        let early = energy(&response[1..24000]);
+        let late = energy(&response[72000..]);
+        assert!(early > 0.01 && late < early / 100., "{} {}", early, late);
+        assert!(response.iter().all(|x| x.abs() <= 1.));
+    }
+}
@@ -0,0 +1,159 @@
+//! Loudness normalization: a fixed gain, followed by a look-ahead
+//! limiter that keeps the true peak under a ceiling. The look-ahead
+//! lets the gain ramp down smoothly before a peak arrives, rather
+//! than clipping it.
+
+use std::collections::VecDeque;
+
+use dsp::loudness::TruePeak;
+use types::Sample;
+
+/// -1dBTP
+const CEILING: f32 = 0.891;
+/// 5ms
+const LOOKAHEAD: usize = 240;
+/// How many samples the gain takes to ramp down to a peak. The true
+/// peak detector lags its input, so a peak may be due a little before
+/// the sample that reported it, and the ramp has to be done that much
+/// sooner.
+const RAMP: usize = LOOKAHEAD - TruePeak::DELAY;
+/// How much of the gap back to unity gain is closed each sample; about
+/// a 50ms time constant
+const RELEASE: f32 = 1. / 2400.;
+
+/// The gain that each sample needs to stay under the ceiling is held
+/// for the length of the look-ahead, as a running minimum, and then
+/// smoothed by averaging over `RAMP` samples. Every sample in the
+/// average's window has seen the peak coming, so the gain is all the
+/// way down by the time the peak comes out of the delay line.
+///
+/// The output starts as the delay line fills, rather than with
+/// `LOOKAHEAD` samples of silence, so it lines up with the input.
+pub struct Limiter {
+    gain: f32,
+    /// Delayed input, after `gain`
+    delay: VecDeque<Sample>,
+    /// Samples pushed so far
+    count: u64,
+    /// The gains needed by the last `LOOKAHEAD + 1` samples that could
+    /// still be the smallest, with the sample each came in with. The
+    /// gains increase from front to back, so the front is the minimum.
+    minimum: VecDeque<(u64, f32)>,
+    /// The minimum, recovering towards unity no faster than `RELEASE`
+    held: f32,
+    /// The last `RAMP` values of `held`, and their sum
+    ramp: VecDeque<f32>,
+    ramp_sum: f64,
+    true_peak: TruePeak,
+}
+
+impl Limiter {
+    pub fn new(gain: f32) -> Self {
+        Limiter{
+            gain: gain,
+            delay: VecDeque::with_capacity(LOOKAHEAD + 1),
+            count: 0,
+            minimum: VecDeque::with_capacity(LOOKAHEAD + 2),
+            held: 1.,
+            ramp: vec![1.; RAMP].into_iter().collect(),
+            ramp_sum: RAMP as f64,
+            true_peak: TruePeak::new(),
+        }
+    }
+
+    /// Change the gain that is applied before limiting, from the next
+    /// sample on
+    pub fn set_gain(&mut self, gain: f32) {
+        self.gain = gain;
+    }
+
+    /// Add a sample, and return the one leaving the delay line, if it
+    /// is full
+    fn push(&mut self, sample: Sample) -> Option<Sample> {
+        let sample = [sample[0] * self.gain, sample[1] * self.gain];
+        let peak = self.true_peak.push(sample);
+        let needed = if peak > CEILING { CEILING / peak } else { 1. };
+
+        while self.minimum.back().map_or(false, |&(_, gain)| gain >= needed) {
+            self.minimum.pop_back();
+        }
+        self.minimum.push_back((self.count, needed));
+        if self.count - self.minimum[0].0 > LOOKAHEAD as u64 {
+            self.minimum.pop_front();
+        }
+        self.count += 1;
+        self.held = self.minimum[0].1.min(self.held + (1. - self.held) * RELEASE);
+        self.ramp_sum += self.held as f64 - self.ramp.pop_front().unwrap() as f64;
+        self.ramp.push_back(self.held);
+
+        self.delay.push_back(sample);
+        if self.delay.len() <= LOOKAHEAD {
+            return None;
+        }
+        let envelope = (self.ramp_sum / RAMP as f64) as f32;
+        let sample = self.delay.pop_front().unwrap();
+        Some([sample[0] * envelope, sample[1] * envelope])
+    }
+
+    /// Process `samples` in place. Until the delay line has filled,
+    /// less comes out than goes in.
+    pub fn process(&mut self, samples: &mut Vec<Sample>) {
+        let mut out = 0;
+        for i in 0..samples.len() {
+            if let Some(sample) = self.push(samples[i]) {
+                samples[out] = sample;
+                out += 1;
+            }
+        }
+        samples.truncate(out);
+    }
+
+    /// Flush out the audio that is still in the delay line
+    pub fn finish(&mut self, output: &mut Vec<Sample>) {
+        // Push silence through behind it, so that the last of the
+        // audio gets the gain it needs
+        let mut remaining = self.delay.len();
+        while remaining > 0 {
+            if let Some(sample) = self.push([0.; 2]) {
+                output.push(sample);
+                remaining -= 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stays_under_ceiling() {
+        let mut limiter = Limiter::new(4.);
+        let mut samples : Vec<Sample> = (0..48000).map(|i| {
+            let x = (i as f32 * 0.05).sin() * if i > 24000 { 1. } else { 0.1 };
+            [x, -x]
+        }).collect();
+        let input = samples.clone();
+        limiter.process(&mut samples);
+        limiter.finish(&mut samples);
+        assert_eq!(samples.len(), input.len());
+        assert!(samples.iter().all(|s| s[0].abs() <= CEILING && s[1].abs() <= CEILING));
+        // Quiet parts are just amplified, without a delay
+        assert_eq!(&samples[..1000], &input[..1000].iter()
+                   .map(|s| [s[0] * 4., s[1] * 4.]).collect::<Vec<_>>()[..]);
+    }
+
+    #[test]
+    fn gain_changes() {
+        // Shorter than the look-ahead, so it all comes out at the end
+        let mut limiter = Limiter::new(0.5);
+        let mut samples = vec![[0.1, 0.1]; 100];
+        limiter.process(&mut samples);
+        assert!(samples.is_empty());
+        limiter.set_gain(2.);
+        samples = vec![[0.1, 0.1]; 100];
+        limiter.process(&mut samples);
+        limiter.finish(&mut samples);
+        assert_eq!(samples, [vec![[0.05, 0.05]; 100], vec![[0.2, 0.2]; 100]].concat());
+    }
+}
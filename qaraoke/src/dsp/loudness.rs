@@ -0,0 +1,221 @@
+//! Loudness measurement following ITU-R BS.1770 and EBU R128:
+//! integrated loudness in LUFS, with the absolute and relative gates,
+//! and true peak, estimated by 4x oversampling.
+
+use std::f64::consts::PI;
+
+use types::Sample;
+
+/// Gating blocks are 400ms long and start every 100ms
+const STEP: usize = 4_800;
+const STEPS_PER_BLOCK: usize = 4;
+const ABSOLUTE_GATE: f64 = -70.;
+/// Relative to the loudness of the blocks that pass the absolute gate
+const RELATIVE_GATE: f64 = -10.;
+
+/// A second order IIR filter, in direct form I
+#[derive(Copy,Clone,Debug)]
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    x: [f64; 2],
+    y: [f64; 2],
+}
+
+impl Biquad {
+    fn new(b: [f64; 3], a: [f64; 2]) -> Self {
+        Biquad{b: b, a: a, x: [0.; 2], y: [0.; 2]}
+    }
+
+    fn process(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.b[1] * self.x[0] + self.b[2] * self.x[1]
+            - self.a[0] * self.y[0] - self.a[1] * self.y[1];
+        self.x = [x, self.x[0]];
+        self.y = [y, self.y[0]];
+        y
+    }
+}
+
+/// The K-weighting curve: a high shelf for the head, then a highpass.
+/// These are the coefficients given by BS.1770 for 48kHz.
+fn k_weighting() -> [Biquad; 2] {
+    [Biquad::new([1.53512485958697, -2.69169618940638, 1.19839281085285],
+                 [-1.69065929318241, 0.73248077421585]),
+     Biquad::new([1.0, -2.0, 1.0],
+                 [-1.99004745483398, 0.99007225036621])]
+}
+
+fn to_lufs(power: f64) -> f64 {
+    -0.691 + 10. * power.log10()
+}
+
+/// Input samples between each oversampled point
+const OVERSAMPLE: usize = 4;
+/// Input samples that each oversampled point is interpolated from
+const TAPS: usize = 12;
+
+/// Estimates the peaks between samples by interpolating each channel
+/// at 4 times the sample rate
+pub struct TruePeak {
+    /// The interpolation filter, split into one set of taps per
+    /// oversampled point
+    phases: [[f32; TAPS]; OVERSAMPLE],
+    /// The last `TAPS` samples of each channel, most recent first
+    history: [[f32; TAPS]; 2],
+}
+
+impl TruePeak {
+    /// How many samples behind the input the interpolated points lag
+    pub const DELAY: usize = TAPS / 2;
+
+    pub fn new() -> Self {
+        // A Hann-windowed sinc, cutting off at the original Nyquist
+        // frequency
+        let len = TAPS * OVERSAMPLE;
+        let mut phases = [[0.; TAPS]; OVERSAMPLE];
+        for (p, phase) in phases.iter_mut().enumerate() {
+            for (k, tap) in phase.iter_mut().enumerate() {
+                let m = k * OVERSAMPLE + p;
+                let t = (m as f64 - (len / 2) as f64) / OVERSAMPLE as f64;
+                let sinc = if t == 0. { 1. } else { (PI * t).sin() / (PI * t) };
+                let window = 0.5 - 0.5 * (2. * PI * m as f64 / len as f64).cos();
+                *tap = (sinc * window) as f32;
+            }
+            // Keep the gain at DC exactly 1
+            let sum : f32 = phase.iter().sum();
+            for tap in phase.iter_mut() {
+                *tap /= sum;
+            }
+        }
+        TruePeak{
+            phases: phases,
+            history: [[0.; TAPS]; 2],
+        }
+    }
+
+    /// Add a sample, and return the largest magnitude on either
+    /// channel among the points interpolated up to it
+    pub fn push(&mut self, sample: Sample) -> f32 {
+        let mut peak = 0f32;
+        for (channel, history) in self.history.iter_mut().enumerate() {
+            for i in (1..TAPS).rev() {
+                history[i] = history[i - 1];
+            }
+            history[0] = sample[channel];
+            for phase in &self.phases {
+                let y : f32 = phase.iter().zip(history.iter()).map(|(h, x)| h * x).sum();
+                peak = peak.max(y.abs());
+            }
+        }
+        peak
+    }
+}
+
+pub struct LoudnessMeter {
+    filters: [[Biquad; 2]; 2],
+    /// The K-weighted power of the current 100ms step, summed over
+    /// the channels
+    step_power: f64,
+    step_len: usize,
+    /// The mean power of each completed 100ms step
+    steps: Vec<f64>,
+    true_peak: TruePeak,
+    peak: f32,
+}
+
+impl LoudnessMeter {
+    pub fn new() -> Self {
+        LoudnessMeter{
+            filters: [k_weighting(), k_weighting()],
+            step_power: 0.,
+            step_len: 0,
+            steps: Vec::new(),
+            true_peak: TruePeak::new(),
+            peak: 0.,
+        }
+    }
+
+    /// Measure some more 48kHz audio
+    pub fn add(&mut self, samples: &[Sample]) {
+        for sample in samples {
+            for (channel, filters) in self.filters.iter_mut().enumerate() {
+                let shelved = filters[0].process(sample[channel] as f64);
+                let y = filters[1].process(shelved);
+                self.step_power += y * y;
+            }
+            self.step_len += 1;
+            if self.step_len == STEP {
+                self.steps.push(self.step_power / STEP as f64);
+                self.step_power = 0.;
+                self.step_len = 0;
+            }
+            self.peak = self.peak.max(self.true_peak.push(*sample));
+        }
+    }
+
+    /// Integrated loudness, in LUFS. None if the audio was too short
+    /// or too quiet to measure.
+    pub fn integrated(&self) -> Option<f64> {
+        let blocks : Vec<f64> = self.steps.windows(STEPS_PER_BLOCK)
+            .map(|steps| steps.iter().sum::<f64>() / STEPS_PER_BLOCK as f64)
+            .filter(|&power| to_lufs(power) > ABSOLUTE_GATE)
+            .collect();
+        if blocks.is_empty() {
+            return None;
+        }
+        let threshold = to_lufs(blocks.iter().sum::<f64>() / blocks.len() as f64) + RELATIVE_GATE;
+        let gated : Vec<f64> = blocks.into_iter().filter(|&power| to_lufs(power) > threshold).collect();
+        Some(to_lufs(gated.iter().sum::<f64>() / gated.len() as f64))
+    }
+
+    /// True peak, in dBTP
+    pub fn true_peak(&self) -> f64 {
+        20. * (self.peak as f64).log10()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sine(freq: f64, amplitude: f32, seconds: usize, phase: f64) -> Vec<Sample> {
+        (0..seconds * 48000).map(|i| {
+            let x = amplitude * (2. * PI * freq * i as f64 / 48000. + phase).sin() as f32;
+            [x, x]
+        }).collect()
+    }
+
+    #[test]
+    fn reference_tone() {
+        // BS.1770 is calibrated so that a 0dBFS 997Hz sine on one
+        // channel reads -3.01 LUFS, so on both it reads 0
+        let mut meter = LoudnessMeter::new();
+        meter.add(&sine(997., 1., 5, 0.));
+        assert!(meter.integrated().unwrap().abs() < 0.1, "{:?}", meter.integrated());
+
+        let mut meter = LoudnessMeter::new();
+        meter.add(&sine(997., 0.1, 5, 0.));
+        assert!((meter.integrated().unwrap() + 20.).abs() < 0.1, "{:?}", meter.integrated());
+    }
+
+    #[test]
+    fn gating() {
+        let mut meter = LoudnessMeter::new();
+        assert_eq!(meter.integrated(), None);
+        meter.add(&vec![[0., 0.]; 48000 * 5]);
+        assert_eq!(meter.integrated(), None);
+        // Silence doesn't drag the loudness down
+        meter.add(&sine(997., 0.1, 5, 0.));
+        assert!((meter.integrated().unwrap() + 20.).abs() < 0.2, "{:?}", meter.integrated());
+    }
+
+    #[test]
+    fn inter_sample_peaks() {
+        // A quarter of the sample rate, sampled 45° off its peaks,
+        // never has a sample above 0.707 of its true amplitude
+        let mut meter = LoudnessMeter::new();
+        meter.add(&sine(12000., 1., 1, PI / 4.));
+        let peak = meter.true_peak();
+        assert!(peak > -0.5 && peak < 0.5, "{}", peak);
+    }
+}
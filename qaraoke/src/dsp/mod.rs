@@ -0,0 +1,10 @@
+//! Signal processing that happens between decoding and the ring
+//! buffer. Everything here works on 48kHz stereo `types::Sample`s
+//! unless noted otherwise, and runs in the decoding thread, not the
+//! audio callback. The exception is `effects`, for the microphones.
+
+pub mod effects;
+pub mod limiter;
+pub mod loudness;
+pub mod stretch;
+pub mod vocal;
@@ -0,0 +1,226 @@
+//! Changing the duration of audio without changing its pitch, by
+//! waveform-similarity overlap-add (WSOLA).
+//!
+//! The input is cut into overlapping Hann-windowed grains, which are
+//! laid down at a fixed hop in the output but taken from the input
+//! at a hop scaled by the stretch ratio. Each grain is nudged, within
+//! a small tolerance, to wherever it best lines up with the grain
+//! before it, which keeps the phase of tonal sounds coherent.
+
+use std::cmp;
+use std::f32::consts::PI;
+
+use types::Sample;
+
+/// Length of a grain, in samples
+const FRAME: usize = 1024;
+/// Distance between grains in the output, in samples
+const HOP: usize = FRAME / 2;
+/// How far a grain may be moved from its nominal position to line up
+/// with the previous one, in samples
+const TOLERANCE: usize = 256;
+
+pub struct TimeStretch {
+    /// Output duration relative to input duration
+    ratio: f64,
+    window: Vec<f32>,
+    /// Input that may still be part of a grain
+    input: Vec<Sample>,
+    /// Nominal start of the next grain in `input`
+    position: f64,
+    /// Where the next grain would start if it carried straight on
+    /// from the previous one
+    continuation: usize,
+    /// The windowed second half of the previous grain
+    tail: Vec<Sample>,
+    /// Audio is passed through untouched until the ratio is first
+    /// changed, so that songs that are never stretched don't pay for
+    /// it
+    bypass: bool,
+}
+
+impl TimeStretch {
+    pub fn new() -> Self {
+        TimeStretch{
+            ratio: 1.,
+            window: (0..FRAME).map(|i| 0.5 - 0.5 * (2. * PI * i as f32 / FRAME as f32).cos()).collect(),
+            input: Vec::new(),
+            position: 0.,
+            continuation: 0,
+            tail: vec![[0.; 2]; HOP],
+            bypass: true,
+        }
+    }
+
+    /// Set the output duration relative to the input duration. This
+    /// takes effect with the next grain.
+    pub fn set_ratio(&mut self, ratio: f64) {
+        assert!(ratio > 0.);
+        self.ratio = ratio;
+        if self.bypass && ratio != 1. {
+            self.bypass = false;
+            // The samples that were held back become the second half
+            // of a grain that ended just before them, so the first
+            // real grain joins on seamlessly
+            if self.input.len() < HOP {
+                let mut padded = vec![[0.; 2]; HOP - self.input.len()];
+                padded.extend_from_slice(&self.input);
+                self.input = padded;
+            }
+            for (tail, (x, w)) in self.tail.iter_mut().zip(self.input.iter().zip(&self.window[HOP..])) {
+                *tail = [x[0] * w, x[1] * w];
+            }
+            self.continuation = 0;
+            self.position = 0.;
+        }
+    }
+
+    /// Stretch `input`, appending the result to `output`. Output lags
+    /// input by up to a few grains.
+    pub fn process(&mut self, input: &[Sample], output: &mut Vec<Sample>) {
+        self.input.extend_from_slice(input);
+        if self.bypass {
+            // Hold back half a grain, in case stretching starts
+            let ready = self.input.len().saturating_sub(HOP);
+            output.extend(self.input.drain(..ready));
+            return;
+        }
+
+        loop {
+            let nominal = self.position as usize;
+            let latest = cmp::max(nominal + TOLERANCE, self.continuation);
+            if latest + FRAME > self.input.len() {
+                return;
+            }
+            let start = self.best_match(nominal.saturating_sub(TOLERANCE), nominal + TOLERANCE);
+
+            for i in 0..HOP {
+                let (x, w, tail) = (self.input[start + i], self.window[i], self.tail[i]);
+                output.push([tail[0] + x[0] * w, tail[1] + x[1] * w]);
+            }
+            for i in 0..HOP {
+                let (x, w) = (self.input[start + HOP + i], self.window[HOP + i]);
+                self.tail[i] = [x[0] * w, x[1] * w];
+            }
+            self.continuation = start + HOP;
+            self.position += HOP as f64 / self.ratio;
+
+            // Forget input that no later grain can use
+            let used = cmp::min(self.continuation, (self.position as usize).saturating_sub(TOLERANCE));
+            if used >= FRAME {
+                self.input.drain(..used);
+                self.position -= used as f64;
+                self.continuation -= used;
+            }
+        }
+    }
+
+    /// Flush out whatever input is left at the end of the stream,
+    /// without stretching it
+    pub fn finish(&mut self, output: &mut Vec<Sample>) {
+        if !self.bypass {
+            let rest = &self.input[cmp::min(self.continuation, self.input.len())..];
+            for (i, x) in rest.iter().enumerate() {
+                if i < HOP {
+                    let (w, tail) = (self.window[i], self.tail[i]);
+                    output.push([tail[0] + x[0] * w, tail[1] + x[1] * w]);
+                } else {
+                    output.push(*x);
+                }
+            }
+        } else {
+            output.extend_from_slice(&self.input);
+        }
+        self.input.clear();
+    }
+
+    /// The grain start in `[lo, hi]` whose first half is most like the
+    /// continuation of the previous grain, by normalized
+    /// cross-correlation of the channels' sum
+    fn best_match(&self, lo: usize, hi: usize) -> usize {
+        let target = &self.input[self.continuation..self.continuation + HOP];
+        let mut best = (self.continuation, ::std::f32::NEG_INFINITY);
+        for start in lo..hi + 1 {
+            let candidate = &self.input[start..start + HOP];
+            let (mut corr, mut energy) = (0., 1e-9);
+            // Every other sample is plenty to find the alignment
+            for i in (0..HOP / 2).map(|i| i * 2) {
+                let (x, y) = (candidate[i][0] + candidate[i][1], target[i][0] + target[i][1]);
+                corr += x * y;
+                energy += x * x;
+            }
+            let score = corr / energy.sqrt();
+            if score > best.1 {
+                best = (start, score);
+            }
+        }
+        best.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sine(len: usize, period: f32) -> Vec<Sample> {
+        (0..len).map(|i| {
+            let x = (2. * PI * i as f32 / period).sin();
+            [x, x]
+        }).collect()
+    }
+
+    fn stretch(ratio: f64, input: &[Sample]) -> Vec<Sample> {
+        let mut stretch = TimeStretch::new();
+        stretch.set_ratio(ratio);
+        let mut output = Vec::new();
+        for chunk in input.chunks(1152) {
+            stretch.process(chunk, &mut output);
+        }
+        stretch.finish(&mut output);
+        output
+    }
+
+    #[test]
+    fn bypass() {
+        let input = sine(10000, 100.);
+        assert_eq!(stretch(1., &input), input);
+    }
+
+    #[test]
+    fn duration() {
+        let input = sine(48000, 109.);
+        for &ratio in &[0.5, 0.8, 1.25, 2.] {
+            let output = stretch(ratio, &input);
+            let expected = input.len() as f64 * ratio;
+            assert!((output.len() as f64 - expected).abs() < (2 * FRAME) as f64,
+                    "ratio {}: {} samples, expected {}", ratio, output.len(), expected);
+        }
+    }
+
+    #[test]
+    fn keeps_pitch() {
+        // A pure tone should come out with the same period, and
+        // without dips where grains join
+        let output = stretch(1.5, &sine(48000, 120.));
+        let steady = &output[FRAME..output.len() - 2 * FRAME];
+        let crossings = steady.windows(2).filter(|w| w[0][0] < 0. && w[1][0] >= 0.).count();
+        let expected = steady.len() as f32 / 120.;
+        assert!((crossings as f32 - expected).abs() < 3., "{} crossings, expected {}", crossings, expected);
+        assert!(steady.iter().all(|x| x[0].abs() <= 1.01));
+        let peak = steady.chunks(120).map(|c| c.iter().map(|x| x[0].abs()).fold(0., f32::max)).fold(1., f32::min);
+        assert!(peak > 0.9, "peak dropped to {}", peak);
+    }
+
+    #[test]
+    fn change_mid_stream() {
+        let input = sine(48000, 120.);
+        let mut stretch = TimeStretch::new();
+        let mut output = Vec::new();
+        stretch.process(&input[..10000], &mut output);
+        stretch.set_ratio(0.75);
+        stretch.process(&input[10000..], &mut output);
+        // No click where stretching starts
+        let peak = output[..output.len() - 2 * FRAME].windows(2).map(|w| (w[1][0] - w[0][0]).abs()).fold(0., f32::max);
+        assert!(peak < 0.06, "jump of {}", peak);
+    }
+}
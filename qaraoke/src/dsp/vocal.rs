@@ -0,0 +1,174 @@
+//! Vocal reduction by centre-channel cancellation. Lead vocals are
+//! usually mixed dead centre, so they are in the mid (L+R) signal but
+//! not the side (L-R) signal. Cancelling the mid signal outright
+//! would take the bass and kick drum with it, which are usually
+//! centred too, so only the band that the voice occupies is removed.
+
+use std::f64::consts::PI;
+
+use types::{Sample, SAMPLE_RATE};
+
+/// The band that is cancelled, in Hz
+const LOW_CUTOFF: f64 = 200.;
+const HIGH_CUTOFF: f64 = 6_000.;
+/// How long switching on or off takes, in samples
+const RAMP: f32 = 480.;
+
+/// A second order IIR filter, in transposed direct form II
+#[derive(Copy,Clone,Debug)]
+struct Biquad {
+    b: [f32; 3],
+    a: [f32; 2],
+    state: [f32; 2],
+}
+
+impl Biquad {
+    /// Butterworth filters, from the Audio EQ Cookbook
+    fn new(cutoff: f64, highpass: bool) -> Self {
+        let w0 = 2. * PI * cutoff / SAMPLE_RATE as f64;
+        let alpha = w0.sin() / 2f64.sqrt();
+        let cos = w0.cos();
+        let a0 = 1. + alpha;
+        let b = if highpass {
+            [(1. + cos) / 2., -(1. + cos), (1. + cos) / 2.]
+        } else {
+            [(1. - cos) / 2., 1. - cos, (1. - cos) / 2.]
+        };
+        Biquad{
+            b: [(b[0] / a0) as f32, (b[1] / a0) as f32, (b[2] / a0) as f32],
+            a: [(-2. * cos / a0) as f32, ((1. - alpha) / a0) as f32],
+            state: [0.; 2],
+        }
+    }
+
+    fn process(&mut self, x: f32) -> f32 {
+        let y = self.b[0] * x + self.state[0];
+        self.state[0] = self.b[1] * x - self.a[0] * y + self.state[1];
+        self.state[1] = self.b[2] * x - self.a[1] * y;
+        y
+    }
+
+    fn reset(&mut self) {
+        self.state = [0.; 2];
+    }
+}
+
+/// Two Butterworth filters in series make a Linkwitz-Riley filter,
+/// whose lowpass and highpass halves sum back to flat
+#[derive(Copy,Clone,Debug)]
+struct LinkwitzRiley([Biquad; 2]);
+
+impl LinkwitzRiley {
+    fn new(cutoff: f64, highpass: bool) -> Self {
+        let stage = Biquad::new(cutoff, highpass);
+        LinkwitzRiley([stage, stage])
+    }
+
+    fn process(&mut self, x: f32) -> f32 {
+        let y = self.0[0].process(x);
+        self.0[1].process(y)
+    }
+
+    fn reset(&mut self) {
+        self.0[0].reset();
+        self.0[1].reset();
+    }
+}
+
+pub struct VocalReducer {
+    enabled: bool,
+    /// How much of the vocal band is being cancelled, from 0 to 1.
+    /// This follows `enabled` gradually, so that switching doesn't
+    /// click.
+    amount: f32,
+    /// The parts of the mid signal that are kept
+    lows: LinkwitzRiley,
+    highs: LinkwitzRiley,
+}
+
+impl VocalReducer {
+    pub fn new() -> Self {
+        VocalReducer{
+            enabled: false,
+            amount: 0.,
+            lows: LinkwitzRiley::new(LOW_CUTOFF, false),
+            highs: LinkwitzRiley::new(HIGH_CUTOFF, true),
+        }
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        if enabled && self.amount == 0. {
+            self.lows.reset();
+            self.highs.reset();
+        }
+        self.enabled = enabled;
+    }
+
+    /// Process 48kHz audio in place
+    pub fn process(&mut self, samples: &mut [Sample]) {
+        if !self.enabled && self.amount == 0. {
+            return;
+        }
+        let target = if self.enabled { 1. } else { 0. };
+        for sample in samples.iter_mut() {
+            if self.amount != target {
+                let step = (target - self.amount).max(-1. / RAMP).min(1. / RAMP);
+                self.amount += step;
+            }
+            let mid = (sample[0] + sample[1]) / 2.;
+            let kept = self.lows.process(mid) + self.highs.process(mid);
+            let cancelled = (mid - kept) * self.amount;
+            *sample = [sample[0] - cancelled, sample[1] - cancelled];
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The RMS level of each channel over the second half of the
+    /// output, after the filters have settled
+    fn level(reducer: &mut VocalReducer, freq: f64, left: f32, right: f32) -> (f32, f32) {
+        let mut samples : Vec<Sample> = (0..9600).map(|i| {
+            let x = (2. * PI * freq * i as f64 / SAMPLE_RATE as f64).sin() as f32;
+            [x * left, x * right]
+        }).collect();
+        reducer.process(&mut samples);
+        let tail = &samples[4800..];
+        let rms = |c: usize| (tail.iter().map(|s| s[c] * s[c]).sum::<f32>() / tail.len() as f32).sqrt();
+        (rms(0), rms(1))
+    }
+
+    #[test]
+    fn cancels_centred_voice() {
+        let mut reducer = VocalReducer::new();
+        reducer.set_enabled(true);
+        let (l, r) = level(&mut reducer, 1000., 1., 1.);
+        assert!(l < 0.1 && r < 0.1, "{} {}", l, r);
+    }
+
+    #[test]
+    fn keeps_bass_and_sides() {
+        let mut reducer = VocalReducer::new();
+        reducer.set_enabled(true);
+        let (l, r) = level(&mut reducer, 50., 1., 1.);
+        assert!(l > 0.6 && r > 0.6, "{} {}", l, r);
+        // Something panned hard left only loses its share of the mid
+        // signal, leaving half of it in each channel
+        let (l, r) = level(&mut reducer, 1000., 1., 0.);
+        assert!((l - 0.35).abs() < 0.05 && (r - 0.35).abs() < 0.05, "{} {}", l, r);
+    }
+
+    #[test]
+    fn disabled_is_untouched() {
+        let mut reducer = VocalReducer::new();
+        let mut samples = vec![[0.5, 0.5]; 100];
+        reducer.process(&mut samples);
+        assert!(samples.iter().all(|s| *s == [0.5, 0.5]));
+        reducer.set_enabled(true);
+        reducer.set_enabled(false);
+        reducer.process(&mut samples);
+        assert!(samples.iter().all(|s| *s == [0.5, 0.5]));
+    }
+}
@@ -0,0 +1,318 @@
+//! Keyboard controls. Keys are named as glutin names them in
+//! `VirtualKeyCode`, such as "Space", "Left", "Q" or "F11", and each
+//! is bound to an `Action` for the player to carry out. Displays
+//! without a window take keys from the terminal instead, under the
+//! same names.
+
+use std::collections::HashMap;
+use std::io::{self, Read};
+use std::mem;
+use std::str::FromStr;
+
+use glium::backend::glutin_backend::GlutinFacade;
+use glium::glutin::{ElementState, Event, VirtualKeyCode};
+use libc;
+
+#[derive(Copy,Clone,Debug,PartialEq,Eq)]
+pub enum Action {
+    /// Pause, or resume if paused
+    Pause,
+    /// Go on to the next song in the queue
+    Skip,
+    SeekBack,
+    SeekForward,
+    /// Transpose the current song up a semitone
+    KeyUp,
+    KeyDown,
+    VolumeUp,
+    VolumeDown,
+    /// Switch vocal reduction on or off
+    Vocals,
+    Fullscreen,
+    Quit,
+}
+
+pub const ACTIONS: [&'static str; 11] = [
+    "pause", "skip", "seek-back", "seek-forward", "key-up", "key-down",
+    "volume-up", "volume-down", "vocals", "fullscreen", "quit",
+];
+
+impl FromStr for Action {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "pause" => Ok(Action::Pause),
+            "skip" => Ok(Action::Skip),
+            "seek-back" => Ok(Action::SeekBack),
+            "seek-forward" => Ok(Action::SeekForward),
+            "key-up" => Ok(Action::KeyUp),
+            "key-down" => Ok(Action::KeyDown),
+            "volume-up" => Ok(Action::VolumeUp),
+            "volume-down" => Ok(Action::VolumeDown),
+            "vocals" => Ok(Action::Vocals),
+            "fullscreen" => Ok(Action::Fullscreen),
+            "quit" => Ok(Action::Quit),
+            _ => Err(format!("Unknown action {:?}; expected one of {}", s, ACTIONS.join(", "))),
+        }
+    }
+}
+
+/// Which key does what
+#[derive(Clone,Debug)]
+pub struct Bindings {
+    keys: HashMap<String, Action>,
+}
+
+impl Default for Bindings {
+    fn default() -> Self {
+        let mut bindings = Bindings{keys: HashMap::new()};
+        for &(key, action) in &[
+            ("Space", Action::Pause),
+            ("N", Action::Skip),
+            ("Left", Action::SeekBack),
+            ("Right", Action::SeekForward),
+            ("Up", Action::KeyUp),
+            ("Down", Action::KeyDown),
+            ("Equals", Action::VolumeUp),
+            ("Add", Action::VolumeUp),
+            ("Minus", Action::VolumeDown),
+            ("Subtract", Action::VolumeDown),
+            ("V", Action::Vocals),
+            ("F", Action::Fullscreen),
+            ("F11", Action::Fullscreen),
+            ("Q", Action::Quit),
+            ("Escape", Action::Quit),
+        ] {
+            bindings.bind(key, action);
+        }
+        bindings
+    }
+}
+
+impl Bindings {
+    /// Bind `key` to `action`, replacing whatever it did before
+    pub fn bind(&mut self, key: &str, action: Action) {
+        self.keys.insert(key.to_owned(), action);
+    }
+
+    /// Apply a binding given as "KEY=ACTION". An ACTION of "none"
+    /// unbinds the key.
+    pub fn parse(&mut self, binding: &str) -> Result<(), String> {
+        let mut parts = binding.splitn(2, '=');
+        let key = parts.next().unwrap();
+        let action = match parts.next() {
+            Some(action) if !key.is_empty() => action,
+            _ => return Err(format!("{:?} is not of the form KEY=ACTION", binding)),
+        };
+        if action == "none" {
+            self.keys.remove(key);
+        } else {
+            let action = try!(action.parse());
+            self.bind(key, action);
+        }
+        Ok(())
+    }
+
+    fn action(&self, key: &str) -> Option<Action> {
+        self.keys.get(key).cloned()
+    }
+
+    pub fn key_action(&self, key: VirtualKeyCode) -> Option<Action> {
+        self.action(&format!("{:?}", key))
+    }
+}
+
+/// Handle the window's pending events, and return the actions that
+/// they call for. Closing the window quits.
+pub fn poll(window: &GlutinFacade, bindings: &Bindings) -> Vec<Action> {
+    let mut actions = Vec::new();
+    for event in window.poll_events() {
+        match event {
+            Event::Closed => actions.push(Action::Quit),
+            Event::KeyboardInput(ElementState::Pressed, _, Some(key)) => {
+                if let Some(action) = bindings.key_action(key) {
+                    actions.push(action);
+                }
+            },
+            _ => (),
+        }
+    }
+    actions
+}
+
+/// The escape sequences that terminals send for keys other than
+/// letters, digits and punctuation
+static ESCAPES: &'static [(&'static [u8], &'static str)] = &[
+    (b"\x1b[A", "Up"), (b"\x1b[B", "Down"), (b"\x1b[C", "Right"), (b"\x1b[D", "Left"),
+    (b"\x1bOA", "Up"), (b"\x1bOB", "Down"), (b"\x1bOC", "Right"), (b"\x1bOD", "Left"),
+    (b"\x1b[H", "Home"), (b"\x1b[F", "End"),
+    (b"\x1b[2~", "Insert"), (b"\x1b[3~", "Delete"), (b"\x1b[5~", "PageUp"), (b"\x1b[6~", "PageDown"),
+    (b"\x1b[21~", "F10"), (b"\x1b[23~", "F11"), (b"\x1b[24~", "F12"),
+];
+
+/// Ctrl-C, which the terminal no longer turns into a signal
+const INTERRUPT: u8 = 3;
+
+/// Name the keys in `input`, as read from a terminal in raw mode, the
+/// way glutin names them. Keys that have no name here are left out,
+/// and so are keys pressed with Alt, which the terminal sends as ESC
+/// and then the key. An escape sequence can be split across reads, so
+/// if `partial` is set, one that could still be cut short at the end
+/// of `input` is left for the next read. Returns the keys and how
+/// much of `input` they took up.
+fn terminal_keys(input: &[u8], partial: bool) -> (Vec<String>, usize) {
+    let mut keys = Vec::new();
+    let mut i = 0;
+    while i < input.len() {
+        let rest = &input[i..];
+        if let Some(&(sequence, key)) = ESCAPES.iter().find(|&&(sequence, _)| rest.starts_with(sequence)) {
+            keys.push(key.to_owned());
+            i += sequence.len();
+            continue;
+        }
+        if rest[0] == 0x1b {
+            if partial && ESCAPES.iter().any(|&(sequence, _)| sequence.starts_with(rest)) {
+                break;
+            }
+            match rest.get(1) {
+                // Some other control sequence, up to its final byte
+                Some(&b'[') => {
+                    let end = rest[2..].iter().position(|&b| b >= 0x40 && b <= 0x7e);
+                    i += end.map_or(rest.len(), |end| end + 3);
+                    continue;
+                },
+                // Alt and a key
+                Some(&next) if next != 0x1b => {
+                    i += 2;
+                    continue;
+                },
+                _ => (),
+            }
+        }
+        let key = match input[i] {
+            b' ' => Some("Space".to_owned()),
+            b'\r' | b'\n' => Some("Return".to_owned()),
+            b'\t' => Some("Tab".to_owned()),
+            0x1b => Some("Escape".to_owned()),
+            0x7f => Some("Back".to_owned()),
+            b'=' => Some("Equals".to_owned()),
+            b'-' => Some("Minus".to_owned()),
+            b'+' => Some("Add".to_owned()),
+            b',' => Some("Comma".to_owned()),
+            b'.' => Some("Period".to_owned()),
+            digit @ b'0'..=b'9' => Some(format!("Key{}", digit as char)),
+            letter @ b'a'..=b'z' | letter @ b'A'..=b'Z' => Some((letter as char).to_ascii_uppercase().to_string()),
+            _ => None,
+        };
+        keys.extend(key);
+        i += 1;
+    }
+    (keys, i)
+}
+
+/// Key presses typed at the terminal, for displays that have no
+/// window to take them from. The terminal is put in raw mode for as
+/// long as this exists, so that keys arrive as they are pressed, and
+/// aren't echoed over the picture.
+pub struct Console {
+    /// The terminal settings to put back afterwards
+    saved: libc::termios,
+    /// The start of an escape sequence that may be continued in the
+    /// next read
+    pending: Vec<u8>,
+}
+
+impl Console {
+    /// Fails if standard input isn't a terminal
+    pub fn open() -> io::Result<Self> {
+        unsafe {
+            let mut saved: libc::termios = mem::zeroed();
+            if libc::tcgetattr(libc::STDIN_FILENO, &mut saved) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let mut raw = saved;
+            raw.c_lflag &= !(libc::ICANON | libc::ECHO | libc::ISIG);
+            // Reads return at once, with whatever has been typed
+            raw.c_cc[libc::VMIN] = 0;
+            raw.c_cc[libc::VTIME] = 0;
+            if libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &raw) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(Console{saved: saved, pending: Vec::new()})
+        }
+    }
+
+    /// Read the keys pressed since the last poll, and return the
+    /// actions that they call for. Ctrl-C quits. An ESC at the end of
+    /// a read is held until the next poll, and is only taken as the
+    /// Escape key if nothing has come in after it by then.
+    pub fn poll(&mut self, bindings: &Bindings) -> Vec<Action> {
+        let mut input = [0; 64];
+        let len = io::stdin().read(&mut input).unwrap_or(0);
+        if input[..len].contains(&INTERRUPT) {
+            self.pending.clear();
+            return vec![Action::Quit];
+        }
+        let partial = len > 0;
+        self.pending.extend_from_slice(&input[..len]);
+        let (keys, used) = terminal_keys(&self.pending, partial);
+        self.pending.drain(..used);
+        keys.iter().filter_map(|key| bindings.action(key)).collect()
+    }
+}
+
+impl Drop for Console {
+    fn drop(&mut self) {
+        unsafe {
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.saved);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys(input: &[u8], partial: bool) -> (Vec<String>, usize) {
+        super::terminal_keys(input, partial)
+    }
+
+    #[test]
+    fn terminal_keys() {
+        let input = b" nQ\x1b[D\x1b[C=-+7\x1b[23~\x01\x1b";
+        assert_eq!(keys(input, false),
+                   (vec!["Space", "N", "Q", "Left", "Right", "Equals", "Minus", "Add", "Key7", "F11", "Escape"]
+                    .iter().map(|&key| key.to_owned()).collect(), input.len()));
+        // Alt and a key, and sequences that have no name, are left out
+        assert_eq!(keys(b"\x1bq\x1b[1;5Cn", false), (vec!["N".to_owned()], 9));
+    }
+
+    #[test]
+    fn split_escapes() {
+        // What might be the start of a sequence waits for the rest
+        assert_eq!(keys(b" \x1b", true), (vec!["Space".to_owned()], 1));
+        assert_eq!(keys(b" \x1b[2", true), (vec!["Space".to_owned()], 1));
+        assert_eq!(keys(b"\x1b[2~", true), (vec!["Insert".to_owned()], 4));
+        // Unless nothing more came in, in which case it was Escape
+        assert_eq!(keys(b"\x1b", false), (vec!["Escape".to_owned()], 1));
+    }
+
+    #[test]
+    fn bindings() {
+        let mut bindings = Bindings::default();
+        assert_eq!(bindings.key_action(VirtualKeyCode::Space), Some(Action::Pause));
+        assert_eq!(bindings.key_action(VirtualKeyCode::F11), Some(Action::Fullscreen));
+
+        bindings.parse("P=pause").unwrap();
+        bindings.parse("Space=skip").unwrap();
+        bindings.parse("Q=none").unwrap();
+        assert_eq!(bindings.key_action(VirtualKeyCode::P), Some(Action::Pause));
+        assert_eq!(bindings.key_action(VirtualKeyCode::Space), Some(Action::Skip));
+        assert_eq!(bindings.key_action(VirtualKeyCode::Q), None);
+
+        assert!(bindings.parse("P").is_err());
+        assert!(bindings.parse("=pause").is_err());
+        assert!(bindings.parse("P=dance").is_err());
+    }
+}
@@ -1,10 +1,13 @@
 extern crate byteorder;
 extern crate cdg;
 extern crate cdg_renderer;
+#[macro_use]
+extern crate clap;
 extern crate fps_counter;
 #[macro_use]
 extern crate glium;
 extern crate image;
+extern crate libc;
 extern crate mpg123;
 extern crate ogk;
 extern crate portaudio;
@@ -17,22 +20,40 @@ extern crate glium_pib;
 
 // Import codecs
 pub mod rt;
+mod analyze;
 mod codec;
+mod control;
+mod dsp;
 mod ao;
+mod input;
+mod player;
+mod queue;
+mod record;
+mod render;
+mod screen;
+mod wav;
 
 use std::rc::Rc;
 use std::error::Error;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
 
 use glium::backend::Facade;
 
 
 pub mod types {
+    use cdg_renderer::Rectangle;
     use glium;
     use std::rc::Rc;
     use rt::ringbuffer;
     
     pub type Sample = [f32; 2];
 
+    /// The rate of all audio, in Hz. Every codec resamples to it, and
+    /// every output plays at it.
+    pub const SAMPLE_RATE: u32 = 48_000;
+
     pub struct AudioBlock {
         pub block: Vec<Sample>,
     }
@@ -60,6 +81,28 @@ pub mod types {
         /// Fill up the output buffer as much as possible.  Must be
         /// called at least once per buffer period.
         fn do_needful(&mut self);
+
+        /// Transpose by `semitones`, clamped to an octave either way,
+        /// without changing the tempo. This applies from the next
+        /// packet decoded; audio that has already been decoded keeps
+        /// the old key.
+        fn set_key(&mut self, semitones: i8);
+
+        /// Play `tempo` times as fast as recorded, without changing
+        /// the key. This should be set before any audio is decoded,
+        /// as the video codec has to be told the same thing at the
+        /// same point in the song.
+        fn set_tempo(&mut self, tempo: f64);
+
+        /// Switch vocal reduction on or off. Like `set_key`, this
+        /// applies from the next packet decoded.
+        fn set_vocal_reduction(&mut self, enabled: bool);
+
+        /// Apply a linear `gain` for loudness normalization, with a
+        /// limiter to keep the true peak under -1dBTP. Like
+        /// `set_tempo`, this should be set before any audio is
+        /// decoded.
+        fn set_gain(&mut self, gain: f32);
     }
 
     pub trait VideoCodec<Surface: glium::Surface> {
@@ -69,6 +112,23 @@ pub mod types {
         /// Render a frame. initialize will be called first.
         /// when is measured in milliseconds since the start of playback.
         fn render_frame(&mut self, context: &Rc<glium::backend::Context>, target: &mut Surface, when: f64);
+
+        /// Follow audio that is played `tempo` times as fast as
+        /// recorded; `when` is then measured on the stretched
+        /// timeline. Set this before the first frame is rendered.
+        fn set_tempo(&mut self, tempo: f64);
+
+        /// The size of the frames produced by `render_software`, in pixels
+        fn frame_size(&self) -> (u32, u32);
+
+        /// Render a frame into an RGBA buffer of `frame_size()`
+        /// pixels, row by row, without using the GPU. The buffer must
+        /// hold the frame from the previous call, as only what has
+        /// changed since then is redrawn. Returns the region that was
+        /// redrawn, in pixels, or None if nothing has changed. `when`
+        /// is as in `render_frame`; initialize need not be called
+        /// first.
+        fn render_software(&mut self, buffer: &mut [u8], when: f64) -> Option<Rectangle<u16>>;
     }
 
     //#[derive(Clone)]
@@ -84,11 +144,11 @@ struct KaraokeSource<R, S> {
     video: Option<Box<types::VideoCodec<S>>>,
 }
 
-impl <R: std::io::Read, S: glium::Surface + 'static> KaraokeSource<R, S> {
-    pub fn from_stream(reader: R) -> Result<Self, Box<Error>> {
+impl <R: io::Read, S: glium::Surface + 'static> KaraokeSource<R, S> {
+    pub fn from_stream(reader: R, options: codec::Options) -> Result<Self, Box<Error>> {
         use types::StreamDesc;
         let mut source = KaraokeSource{
-            demux: try!(ogk::ogg::OggDemux::new(reader, codec::identify_header)),
+            demux: try!(ogk::ogg::OggDemux::new(reader, move |header| codec::identify_header(header, &options))),
             audio: None,
             video: None,
         };
@@ -135,108 +195,502 @@ impl <R: std::io::Read, S: glium::Surface + 'static> KaraokeSource<R, S> {
     }
 }
 
+impl <R: io::Read + io::Seek, S: glium::Surface + 'static> KaraokeSource<R, S> {
+    /// Skip to `time`, in seconds. Returns the time that playback
+    /// actually resumes from, which may be slightly earlier.
+    pub fn seek(&mut self, time: f64) -> Result<f64, Box<Error>> {
+        let resumed = try!(self.demux.seek_to((time * 1e6) as u64));
+        try!(self.demux.pump_until(resumed + 1000));
+        Ok(resumed as f64 / 1e6)
+    }
+}
+
 // TODO: Add glium_pib for bare metal Raspberry Pi support
 
 #[cfg(feature="raspberry_pi")]
-fn pib_open_display() -> Rc<glium::backend::Context> {
+fn pib_open_display() -> Result<Rc<glium::backend::Context>, Box<Error>> {
     use std::sync::Arc;
-    let system = glium_pib::System::new(Default::default());
-    let system = match system {
+    let system = match glium_pib::System::new(Default::default()) {
         Ok(s) => s,
-        Err(_) => {
-            panic!("Failed to use broadcom libraries.");
-        }
+        Err(_) => return Err(From::from("Failed to use broadcom libraries")),
     };
     let system = Arc::new(system);
     let facade : Result<Rc<glium::backend::Context>,_> = glium_pib::create_window_facade(
         &system,
         &std::default::Default::default()
     );
-    match facade {
-        Ok(f) => f,
-        Err(_) => {
-            panic!("Failed to use broadcom libraries.");
-        },
-    }
+    facade.map_err(|_| From::from("Failed to use broadcom libraries"))
 }
 
 #[cfg(not(feature="raspberry_pi"))]
-fn pib_open_display() -> Rc<glium::backend::Context> {
-    panic!("Unable to create window");
+fn pib_open_display() -> Result<Rc<glium::backend::Context>, Box<Error>> {
+    Err(From::from("Unable to create window"))
 }
 
-fn main() {
-    use std::fs;
-    let args: Vec<String> = std::env::args().collect();
-    let filename = args.get(1).expect("Usage: $0 filename");
-    let mut player = KaraokeSource::from_stream(fs::File::open(filename).unwrap()).unwrap();
+/// Where the video is played
+enum Display {
+    /// Through OpenGL. There is a window, unless this is a bare-metal
+    /// Pi, which has only the screen, and no input events.
+    Gl{
+        context: Rc<glium::backend::Context>,
+        window: Option<glium::backend::glutin_backend::GlutinFacade>,
+    },
+    /// Drawn on the CPU, for machines without a GPU
+    Software(Box<screen::Screen>),
+}
+
+fn window_builder(fullscreen: bool) -> glium::glutin::WindowBuilder<'static> {
+    let builder = glium::glutin::WindowBuilder::new().with_title("qaraoke");
+    if fullscreen {
+        builder.with_fullscreen(glium::glutin::get_primary_monitor())
+    } else {
+        builder
+    }
+}
+
+/// Open a window, or failing that, whatever screen there is. Without
+/// OpenGL, the video is drawn on the default framebuffer device. If
+/// `framebuffer` is given, that is used without trying OpenGL, and
+/// likewise the terminal if `terminal` is given.
+fn open_display(fullscreen: bool, framebuffer: Option<&Path>,
+                terminal: Option<cdg_renderer::terminal::Mode>) -> Result<Display, Box<Error>> {
     use glium::DisplayBuild;
+    if let Some(mode) = terminal {
+        return Ok(Display::Software(Box::new(screen::Terminal::new(mode))));
+    }
+    if let Some(device) = framebuffer {
+        return match screen::Framebuffer::open(device) {
+            Ok(fb) => Ok(Display::Software(Box::new(fb))),
+            Err(e) => Err(From::from(format!("{}: {}", device.display(), e))),
+        };
+    }
+    if let Ok(f) = window_builder(fullscreen).build_glium() {
+        return Ok(Display::Gl{
+            context: f.get_context().clone(),
+            window: Some(f),
+        });
+    }
+    if let Ok(context) = pib_open_display() {
+        return Ok(Display::Gl{
+            context: context,
+            window: None,
+        });
+    }
+    match screen::Framebuffer::open(Path::new(screen::DEFAULT_FRAMEBUFFER)) {
+        Ok(fb) => {
+            println!("OpenGL is unavailable, so drawing on {}", screen::DEFAULT_FRAMEBUFFER);
+            Ok(Display::Software(Box::new(fb)))
+        },
+        Err(e) => Err(From::from(format!("Unable to create window, or to draw on {}: {}", screen::DEFAULT_FRAMEBUFFER, e))),
+    }
+}
+
+/// Print the streams in `filename`, as identified by `codec::identify_header`
+fn list_streams(filename: &str, options: codec::Options) -> Result<(), Box<Error>> {
+    use std::cell::RefCell;
+    use types::StreamDesc;
+
+    let found = Rc::new(RefCell::new(Vec::new()));
+    let found_by_demux = found.clone();
+    let file = try!(fs::File::open(filename));
+    try!(ogk::ogg::OggDemux::new(file, move |header| {
+        let stream = codec::identify_header::<glium::Frame>(header, &options);
+        found_by_demux.borrow_mut().push(match stream {
+            Some((_, StreamDesc::Audio(Some(ref codec)))) => format!("audio (quality {})", codec.quality()),
+            Some((_, StreamDesc::Video(Some(ref codec)))) => {
+                let (width, height) = codec.frame_size();
+                format!("video ({}x{})", width, height)
+            },
+            Some(_) => "unusable".to_owned(),
+            None => {
+                let magic : String = header.iter().take(8)
+                    .take_while(|&&c| c >= 0x20 && c < 0x7F)
+                    .map(|&c| c as char)
+                    .collect();
+                format!("unknown (header starts with {:?})", magic)
+            },
+        });
+        stream
+    }));
+
+    println!("{}:", filename);
+    for (i, desc) in found.borrow().iter().enumerate() {
+        println!("  stream {}: {}", i, desc);
+    }
+    Ok(())
+}
+
+fn validate_non_negative(value: String) -> Result<(), String> {
+    match value.parse::<f64>() {
+        Ok(v) if v >= 0. => Ok(()),
+        _ => Err(format!("{:?} is not a non-negative number", value)),
+    }
+}
+
+fn validate_loudness(value: String) -> Result<(), String> {
+    match value.parse::<f64>() {
+        Ok(v) if v < 0. => Ok(()),
+        _ => Err(format!("{:?} is not a negative number of LUFS", value)),
+    }
+}
+
+fn validate_output(value: String) -> Result<(), String> {
+    value.parse::<ao::OutputKind>().map(|_| ())
+}
+
+fn validate_binding(value: String) -> Result<(), String> {
+    input::Bindings::default().parse(&value)
+}
+
+fn validate_input(value: String) -> Result<(), String> {
+    value.parse::<ao::InputKind>().map(|_| ())
+}
 
-    let display = glium::glutin::WindowBuilder::new().build_glium();
-    let display: Rc<glium::backend::Context> = match display {
-        Ok(f) => f.get_context().clone(),
-        Err(_) => pib_open_display(),
+fn cli() -> clap::App<'static, 'static> {
+    use clap::{App, AppSettings, Arg};
+    App::new("qaraoke")
+        .about("Plays Ogg karaoke files")
+        .setting(AppSettings::SubcommandsNegateReqs)
+        .arg(Arg::with_name("FILES")
+             .help("Songs to add to the queue")
+             .required_unless("queue")
+             .multiple(true))
+        .arg(Arg::with_name("singer")
+             .long("singer")
+             .value_name("NAME")
+             .help("Who is singing the songs given on the command line")
+             .default_value(""))
+        .arg(Arg::with_name("queue")
+             .long("queue")
+             .short("q")
+             .value_name("QUEUEFILE")
+             .help("Add the songs listed in a file, one per line as SINGER<tab>PATH[<tab>KEY[<tab>TEMPO]]"))
+        .arg(Arg::with_name("control")
+             .long("control")
+             .value_name("PIPE")
+             .help("Take commands to change the queue from a named pipe while playing"))
+        .arg(Arg::with_name("backend")
+             .long("backend")
+             .short("b")
+             .value_name("OUTPUT")
+             .help("Audio output: portaudio, portaudio:DEVICE, null or wav:FILENAME")
+             .default_value("portaudio")
+             .validator(validate_output))
+        .arg(Arg::with_name("device")
+             .long("device")
+             .short("d")
+             .value_name("DEVICE")
+             .help("PortAudio output device, by name or index"))
+        .arg(Arg::with_name("fullscreen")
+             .long("fullscreen")
+             .short("f")
+             .help("Play fullscreen")
+             .overrides_with("windowed"))
+        .arg(Arg::with_name("windowed")
+             .long("windowed")
+             .short("w")
+             .help("Play in a window (the default)")
+             .overrides_with("fullscreen"))
+        .arg(Arg::with_name("framebuffer")
+             .long("framebuffer")
+             .value_name("DEVICE")
+             .help("Draw the video on a framebuffer device, without OpenGL. This happens anyway, on /dev/fb0, if OpenGL is unavailable."))
+        .arg(Arg::with_name("terminal")
+             .long("terminal")
+             .value_name("MODE")
+             .help("Draw the video on the terminal, in truecolor half blocks or as sixel graphics")
+             .possible_values(&cdg_renderer::terminal::MODES)
+             .conflicts_with("framebuffer"))
+        .arg(Arg::with_name("start")
+             .long("start")
+             .short("s")
+             .value_name("SECONDS")
+             .help("Start the first song this far in")
+             .default_value("0")
+             .validator(validate_non_negative))
+        .arg(Arg::with_name("volume")
+             .long("volume")
+             .value_name("PERCENT")
+             .help("Output volume")
+             .default_value("100")
+             .validator(validate_non_negative))
+        .arg(Arg::with_name("crossfade")
+             .long("crossfade")
+             .value_name("SECONDS")
+             .help("Fade from one song into the next over this long, when a song ends or is skipped")
+             .default_value("2")
+             .validator(validate_non_negative))
+        .arg(Arg::with_name("mic")
+             .long("mic")
+             .value_name("INPUT")
+             .help("Mix in microphones from: portaudio, portaudio:DEVICE or wav:FILENAME")
+             .validator(validate_input))
+        .arg(Arg::with_name("mic-gain")
+             .long("mic-gain")
+             .value_name("PERCENT")
+             .help("Microphone volume; give two, separated by a comma, for separate mics on the left and right input channels")
+             .default_value("100")
+             .use_delimiter(true)
+             .max_values(2)
+             .validator(validate_non_negative))
+        .arg(Arg::with_name("echo")
+             .long("echo")
+             .value_name("PERCENT")
+             .help("Echo on the microphones")
+             .default_value("0")
+             .validator(validate_non_negative))
+        .arg(Arg::with_name("echo-delay")
+             .long("echo-delay")
+             .value_name("SECONDS")
+             .help("Time between echoes, up to 1 second")
+             .default_value("0.25")
+             .validator(validate_non_negative))
+        .arg(Arg::with_name("reverb")
+             .long("reverb")
+             .value_name("PERCENT")
+             .help("Reverb on the microphones")
+             .default_value("0")
+             .validator(validate_non_negative))
+        .arg(Arg::with_name("record")
+             .long("record")
+             .value_name("DIRECTORY")
+             .help("Record each performance, mics included, to a file named after the singer and song"))
+        .arg(Arg::with_name("record-format")
+             .long("record-format")
+             .value_name("FORMAT")
+             .help("File format for recordings. oggpcm keeps the singer and song in tags, but few players can open it.")
+             .possible_values(&record::FORMATS)
+             .default_value("wav"))
+        .arg(Arg::with_name("bind")
+             .long("bind")
+             .value_name("KEY=ACTION")
+             .help("Bind a key, named as in glutin's VirtualKeyCode, to one of: pause, skip, seek-back, seek-forward, key-up, key-down, volume-up, volume-down, vocals, fullscreen, quit or none")
+             .multiple(true)
+             .number_of_values(1)
+             .validator(validate_binding))
+        .arg(Arg::with_name("resampler-quality")
+             .long("resampler-quality")
+             .value_name("QUALITY")
+             .help("Quality of the conversion to 48kHz")
+             .possible_values(&codec::RESAMPLER_QUALITIES)
+             .default_value("low")
+             .global(true))
+        .arg(Arg::with_name("reduce-vocals")
+             .long("reduce-vocals")
+             .help("Cancel out centred lead vocals")
+             .global(true))
+        .arg(Arg::with_name("normalize")
+             .long("normalize")
+             .value_name("LUFS")
+             .help("Bring every song to this loudness, as measured by the analyze subcommand")
+             .allow_hyphen_values(true)
+             .validator(validate_loudness))
+        .arg(Arg::with_name("list-streams")
+             .long("list-streams")
+             .help("Print the streams in each file instead of playing them"))
+        .subcommand(render::subcommand())
+        .subcommand(analyze::subcommand())
+}
+
+fn run(matches: &clap::ArgMatches) -> Result<(), Box<Error>> {
+    let codec_options = codec::Options{
+        resampler_quality: value_t_or_exit!(matches, "resampler-quality", codec::ResamplerQuality),
+        vocal_reduction: matches.is_present("reduce-vocals"),
     };
 
-    let mut frame_count = 0;
-    let mut fps = fps_counter::FPSCounter::new();
-    let start_time = std::time::Instant::now();
+    match matches.subcommand() {
+        ("render", Some(render_matches)) => return render::main(render_matches, codec_options),
+        ("analyze", Some(analyze_matches)) => return analyze::main(analyze_matches, codec_options),
+        _ => (),
+    }
 
-    let mut ao_driver = ao::open().unwrap();
-    ao_driver.start().unwrap();
-    {
-        // Set up a stream
-        if let Some(ref mut vcodec) = player.video {
-            vcodec.initialize(display.get_context())
+    let files : Vec<&str> = matches.values_of("FILES").map(|files| files.collect()).unwrap_or_default();
+    if matches.is_present("list-streams") {
+        for filename in files {
+            if let Err(e) = list_streams(filename, codec_options) {
+                return Err(From::from(format!("{}: {}", filename, e)));
+            }
         }
-        if let Some(ref mut acodec) = player.audio {
-            // We cheat here and always initialize ring buffers to half a
-            // second.
-            let (rd, wr) = rt::ringbuffer::new(96000);
-            acodec.set_ringbuffer(wr);
-            acodec.do_needful();
-            ao_driver.change_stream(Some(rd)).unwrap();
-        } else {
-            ao_driver.change_stream(None).unwrap();
+        return Ok(());
+    }
+
+    let mut output = value_t_or_exit!(matches, "backend", ao::OutputKind);
+    if let Some(device) = matches.value_of("device") {
+        match output {
+            ao::OutputKind::PortAudio(ref mut selected @ None) => *selected = Some(device.to_owned()),
+            _ => return Err(From::from("--device only applies to the portaudio backend")),
         }
+    }
 
-        ao_driver.zero_time().unwrap();
-        ao_driver.commit().unwrap();
+    let recorder = match matches.value_of("record") {
+        Some(directory) => {
+            match fs::metadata(directory) {
+                Ok(ref metadata) if metadata.is_dir() => (),
+                Ok(_) => return Err(From::from(format!("{}: Not a directory", directory))),
+                Err(e) => return Err(From::from(format!("{}: {}", directory, e))),
+            }
+            Some(record::Recorder{
+                directory: directory.into(),
+                format: value_t_or_exit!(matches, "record-format", record::Format),
+            })
+        },
+        None => None,
+    };
+
+    let mut bindings = input::Bindings::default();
+    for binding in matches.values_of("bind").into_iter().flat_map(|values| values) {
+        try!(bindings.parse(binding));
     }
 
-    // Wait for the driver to synchronize
-    while !ao_driver.all_commands_processed() {
-        // Do nothing
+    let mut song_queue = queue::Queue::new();
+    if let Some(filename) = matches.value_of("queue") {
+        let file = match fs::File::open(filename) {
+            Ok(file) => file,
+            Err(e) => return Err(From::from(format!("{}: {}", filename, e))),
+        };
+        if let Err(e) = song_queue.load(io::BufReader::new(file)) {
+            return Err(From::from(format!("{}: {}", filename, e)));
+        }
     }
-    
-    loop {
-        // Do updates
-        let time = ao_driver.timestamp();
-        if let Some(ref mut vcodec) = player.video {
-            let mut target = glium::Frame::new(
-                display.clone(),
-                display.get_framebuffer_dimensions(),
-            );
-            vcodec.render_frame(display.get_context(), &mut target, time);
-            target.finish().unwrap();
+    // Catch typos before anything starts playing
+    let singer = matches.value_of("singer").unwrap();
+    for filename in files {
+        if let Err(e) = fs::metadata(filename) {
+            return Err(From::from(format!("{}: {}", filename, e)));
         }
-        player.demux.pump_until((time * 1000. + 1000.) as u64).unwrap();
-        if let Some(ref mut acodec) = player.audio {
-            acodec.do_needful()
+        song_queue.add(singer, filename, Default::default());
+    }
+
+    let mut player = try!(player::Player::new(&player::PlayerConfig{
+        output: output,
+        fullscreen: matches.is_present("fullscreen"),
+        volume: value_t_or_exit!(matches, "volume", f32) / 100.,
+        crossfade: value_t_or_exit!(matches, "crossfade", f64),
+        loudness_target: if matches.is_present("normalize") {
+            Some(value_t_or_exit!(matches, "normalize", f64))
+        } else {
+            None
+        },
+        mic: if matches.is_present("mic") {
+            Some(value_t_or_exit!(matches, "mic", ao::InputKind))
+        } else {
+            None
+        },
+        mic_gains: values_t_or_exit!(matches, "mic-gain", f32).into_iter().map(|gain| gain / 100.).collect(),
+        mic_effects: dsp::effects::EffectSettings{
+            echo_delay: value_t_or_exit!(matches, "echo-delay", f32),
+            echo_level: value_t_or_exit!(matches, "echo", f32) / 100.,
+            reverb_level: value_t_or_exit!(matches, "reverb", f32) / 100.,
+            ..Default::default()
+        },
+        recorder: recorder,
+        bindings: bindings,
+        framebuffer: matches.value_of("framebuffer").map(PathBuf::from),
+        terminal: if matches.is_present("terminal") {
+            Some(value_t_or_exit!(matches, "terminal", cdg_renderer::terminal::Mode))
+        } else {
+            None
+        },
+        control: matches.value_of("control").map(PathBuf::from),
+        codec_options: codec_options,
+    }, song_queue));
+    player.run(value_t_or_exit!(matches, "start", f64))
+}
+
+fn main() {
+    let matches = cli().get_matches();
+    if let Err(e) = run(&matches) {
+        eprintln!("qaraoke: {}", e);
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use cdg;
+    use glium;
+    use ogk;
+    use super::KaraokeSource;
+
+    type Source = KaraokeSource<Cursor<Vec<u8>>, glium::Frame>;
+
+    /// Two minutes of CD+G that changes every sector, muxed with
+    /// keyframes every `keyframe_interval` sectors. The tiles are
+    /// noise, so that the file spans enough pages to seek in.
+    pub fn test_file(keyframe_interval: u64) -> Vec<u8> {
+        let mut writer = cdg::SubchannelStreamWriter::new(Vec::new());
+        let mut noise = 1u32;
+        for i in 0..9000 {
+            let mut cmds = Vec::new();
+            if i % 1000 == 0 {
+                cmds.push(cdg::Command::MemoryPreset{color: (i / 1000 % 16) as u8, repeat: 0});
+            }
+            while cmds.len() < 4 {
+                let mut content = [0; 12];
+                for row in &mut content {
+                    noise = noise.wrapping_mul(1103515245).wrapping_add(12345);
+                    *row = (noise >> 16) as u8 & 0x3F;
+                }
+                let pos = (i + cmds.len()) * 7;
+                cmds.push(cdg::Command::TileXOR{tile: cdg::Tile{
+                    pos: ((pos % 50) as u8, (pos / 50 % 18) as u8),
+                    color: ((i % 16) as u8, (i / 7 % 16) as u8),
+                    content: content,
+                    channel: 0,
+                }});
+            }
+            writer.write_sector(&cmds).unwrap();
         }
-        // Handle events
-        /*
-        for ev in display.poll_events() {
-            match ev {
-                glium::glutin::Event::Closed => return,
-                _ => (),
+        let coder = ogk::cdg::OggCdgCoder::new(Cursor::new(writer.into_inner()))
+            .with_keyframe_interval(keyframe_interval);
+        let mut mux = ogk::ogg::OgkMux::new();
+        mux.add_stream(Box::new(coder));
+        let mut file = Vec::new();
+        mux.write_to(&mut file).unwrap();
+        file
+    }
+
+    fn open(file: &[u8]) -> Source {
+        KaraokeSource::from_stream(Cursor::new(file.to_vec()), Default::default()).unwrap()
+    }
+
+    /// Play from `from` to `to` seconds in, a video frame at a time,
+    /// as the player does
+    fn play(source: &mut Source, frame: &mut [u8], from: f64, to: f64) {
+        let mut time = from;
+        loop {
+            source.demux.pump_until(((time + 1.) * 1e6) as u64).unwrap();
+            source.video.as_mut().unwrap().render_software(frame, time);
+            if time >= to {
+                return;
             }
+            time = (time + 0.04).min(to);
         }
-        frame_count += 1;
-        let fps_c = fps.tick();
-        if frame_count % 100 == 0 {
-            display.get_window().map(|win| win.set_title(&format!("{} fps", fps_c)));
+    }
+
+    #[test]
+    fn seeking_matches_playing_through() {
+        for &interval in &[75 * 20, 0] {
+            let file = test_file(interval);
+            for &target in &[95., 30., 61.5, 5.] {
+                let mut seeked = open(&file);
+                let resumed = seeked.seek(target).unwrap();
+                let mut seeked_frame = vec![0; 300 * 216 * 4];
+                let mut straight = open(&file);
+                let mut straight_frame = vec![0; 300 * 216 * 4];
+
+                // The picture is right from the first frame after the
+                // seek, and stays right
+                play(&mut seeked, &mut seeked_frame, resumed, resumed);
+                play(&mut straight, &mut straight_frame, 0., resumed);
+                assert!(seeked_frame == straight_frame,
+                        "seek to {} with keyframes every {} sectors", target, interval);
+                play(&mut seeked, &mut seeked_frame, resumed, resumed + 2.);
+                play(&mut straight, &mut straight_frame, resumed, resumed + 2.);
+                assert!(seeked_frame == straight_frame,
+                        "seek to {} with keyframes every {} sectors, 2s on", target, interval);
+            }
         }
-        */
     }
 }
@@ -0,0 +1,571 @@
+//! Plays the songs in a `queue::Queue` one after another. While a song
+//! is playing, the song that is due next is opened and its first
+//! packets decoded, so that typos and broken files turn up before its
+//! turn comes, and the next singer doesn't wait on the disk. Once the
+//! current song has been read to the end, the next one is primed as
+//! well, and switched in at the same commit that resets the clock, so
+//! there is no gap between songs. The new song fades in over the
+//! last few seconds of the old one, and a skipped song fades out the
+//! same way.
+//!
+//! Key presses are handled between video frames; see `input` for the
+//! bindings. Displays without a window read them from the terminal.
+
+use std::error::Error;
+use std::fs;
+use std::mem::replace;
+use std::path::PathBuf;
+
+use glium;
+use glium::DisplayBuild;
+use cdg_renderer::terminal;
+
+use analyze;
+use ao;
+use codec;
+use control;
+use dsp::effects;
+use input::{self, Action};
+use queue;
+use record;
+use rt;
+use KaraokeSource;
+
+pub struct PlayerConfig {
+    pub output: ao::OutputKind,
+    pub fullscreen: bool,
+    /// Linear gain
+    pub volume: f32,
+    /// How long one song fades into the next, in seconds, when a song
+    /// ends or is skipped
+    pub crossfade: f64,
+    /// Normalize every song to this integrated loudness, in LUFS
+    pub loudness_target: Option<f64>,
+    /// Where to take microphones from, if anywhere
+    pub mic: Option<ao::InputKind>,
+    /// Linear gain for each mic. A single gain applies to both.
+    pub mic_gains: Vec<f32>,
+    pub mic_effects: effects::EffectSettings,
+    /// Record each song, if Some
+    pub recorder: Option<record::Recorder>,
+    pub bindings: input::Bindings,
+    /// Draw the video on this framebuffer device rather than through
+    /// OpenGL
+    pub framebuffer: Option<PathBuf>,
+    /// Draw the video on the terminal instead, if Some
+    pub terminal: Option<terminal::Mode>,
+    /// Where the host's commands for changing the queue come from
+    pub control: Option<PathBuf>,
+    pub codec_options: codec::Options,
+}
+
+/// How far the seek actions jump, in seconds
+const SEEK_STEP: f64 = 10.;
+/// How much the volume actions change the volume, as a linear gain
+const VOLUME_STEP: f32 = 0.1;
+const MAX_VOLUME: f32 = 2.;
+/// Key changes go no further than an octave either way
+const MAX_KEY: i8 = 12;
+
+type Source = KaraokeSource<fs::File, glium::Frame>;
+
+/// A queue entry that has been opened, ready to play
+struct Prepared {
+    entry: queue::Entry,
+    source: Source,
+    /// The audio that `source` decodes into, once it has been primed
+    stream: Option<ao::Stream>,
+}
+
+impl Prepared {
+    /// Set up the codecs and decode the first second or so from
+    /// `start`, in seconds into the recording, so that the song can start the moment it
+    /// is switched in. Does nothing if that has already been done.
+    fn prime(&mut self, display: &::Display, start: f64) -> Result<(), Box<Error>> {
+        if self.stream.is_some() {
+            return Ok(());
+        }
+        let source = &mut self.source;
+        // The software path needs no setting up
+        if let ::Display::Gl{ref context, ..} = *display {
+            if let Some(ref mut vcodec) = source.video {
+                vcodec.initialize(context)
+            }
+        }
+        try!(source.demux.pump_until(((start + 1.) * 1e6) as u64));
+        self.stream = Some(match source.audio {
+            Some(ref mut acodec) => {
+                // We cheat here and always initialize ring buffers to half a
+                // second.
+                let (rd, wr) = rt::ringbuffer::new(96000);
+                acodec.set_ringbuffer(wr);
+                acodec.do_needful();
+                Some(rd)
+            },
+            None => None,
+        });
+        Ok(())
+    }
+}
+
+/// Print a status line, as `println!`. With the video on the terminal,
+/// standard output is the picture, so when `$terminal` is true the line
+/// goes to standard error instead, where it can be redirected out of
+/// the way.
+macro_rules! status {
+    ($terminal:expr, $($arg:tt)*) => {
+        if $terminal {
+            eprintln!($($arg)*)
+        } else {
+            println!($($arg)*)
+        }
+    }
+}
+
+fn finish_recording(recording: record::Recording, terminal: bool) {
+    let path = recording.path().to_owned();
+    match recording.finish() {
+        Ok(()) => status!(terminal, "Saved recording {}", path.display()),
+        Err(e) => eprintln!("qaraoke: {}: {}", path.display(), e),
+    }
+}
+
+/// Map the `()` errors of the driver's command queue
+fn queue_full(_: ()) -> Box<Error> {
+    From::from("Audio driver command queue is full")
+}
+
+pub struct Player {
+    display: ::Display,
+    /// Whether the video is drawn on the terminal, in which case status
+    /// goes to standard error
+    terminal: bool,
+    /// The current song's video, as last drawn by the software path
+    frame: Vec<u8>,
+    fullscreen: bool,
+    bindings: input::Bindings,
+    /// Where keys come from when the display has no window
+    console: Option<input::Console>,
+    volume: f32,
+    crossfade: f64,
+    ao_driver: ao::DriverFrontend,
+    /// Whether playback should be paused. The driver only reports this
+    /// once it has taken the command, so a second press before then
+    /// goes by what was asked for, not what the driver says.
+    paused: bool,
+    recorder: Option<record::Recorder>,
+    /// The recording of the current song. This has to be dropped after
+    /// the driver.
+    recording: Option<record::Recording>,
+    codec_options: codec::Options,
+    loudness_target: Option<f64>,
+    queue: queue::Queue,
+    control: Option<control::Control>,
+    /// The song at the front of the queue, if it has been opened
+    next: Option<Prepared>,
+    /// The last song, while it is fading out. It is kept until the
+    /// fade is over, so that its decoder can keep the driver fed.
+    fading: Option<Source>,
+}
+
+impl Player {
+    pub fn new(config: &PlayerConfig, queue: queue::Queue) -> Result<Self, Box<Error>> {
+        let display = try!(::open_display(config.fullscreen, config.framebuffer.as_ref().map(|path| path.as_path()),
+                                          config.terminal));
+        let mut ao_driver = try!(ao::open(&config.output, config.mic.as_ref()));
+        try!(ao_driver.set_volume(config.volume).map_err(queue_full));
+        if config.mic.is_some() {
+            for mic in 0..2 {
+                let gain = config.mic_gains.get(mic).or(config.mic_gains.last()).cloned().unwrap_or(1.);
+                try!(ao_driver.set_mic_gain(mic, gain).map_err(queue_full));
+            }
+            try!(ao_driver.set_mic_effects(config.mic_effects).map_err(queue_full));
+        }
+        let control = match config.control {
+            Some(ref path) => Some(try!(control::Control::open(path))),
+            None => None,
+        };
+        try!(ao_driver.start());
+        let console = match display {
+            ::Display::Gl{window: Some(_), ..} => None,
+            _ => match input::Console::open() {
+                Ok(console) => Some(console),
+                Err(e) => {
+                    eprintln!("qaraoke: not reading keys from the terminal: {}", e);
+                    None
+                },
+            },
+        };
+        Ok(Player{
+            display: display,
+            terminal: config.terminal.is_some(),
+            frame: Vec::new(),
+            fullscreen: config.fullscreen,
+            bindings: config.bindings.clone(),
+            console: console,
+            volume: config.volume,
+            crossfade: config.crossfade,
+            ao_driver: ao_driver,
+            paused: false,
+            recorder: config.recorder.clone(),
+            recording: None,
+            codec_options: config.codec_options,
+            loudness_target: config.loudness_target,
+            queue: queue,
+            control: control,
+            next: None,
+            fading: None,
+        })
+    }
+
+    /// Play until the queue runs dry. The first song starts `start`
+    /// seconds in.
+    pub fn run(&mut self, start: f64) -> Result<(), Box<Error>> {
+        let mut song = match self.take_next() {
+            Some(song) => song,
+            None => return Ok(()),
+        };
+        if start > 0. {
+            let resumed = try!(song.source.seek(start));
+            let tempo = song.entry.settings.tempo;
+            try!(self.perform(&mut song, Some(resumed / tempo), 0.));
+        } else {
+            try!(self.perform(&mut song, None, 0.));
+        }
+
+        loop {
+            // Do updates
+            let time = self.ao_driver.timestamp();
+            // Where that is in the recording
+            let song_time = time * song.entry.settings.tempo;
+            // Keep the last song's audio coming until it has faded out
+            if time >= self.crossfade {
+                self.fading = None;
+            }
+            if let Some(acodec) = self.fading.as_mut().and_then(|source| source.audio.as_mut()) {
+                acodec.do_needful()
+            }
+            if let Some(ref mut vcodec) = song.source.video {
+                match self.display {
+                    ::Display::Gl{ref context, ..} => {
+                        let mut target = glium::Frame::new(
+                            context.clone(),
+                            context.get_framebuffer_dimensions(),
+                        );
+                        vcodec.render_frame(context, &mut target, time);
+                        try!(target.finish());
+                    },
+                    ::Display::Software(ref mut screen) => {
+                        let (width, height) = vcodec.frame_size();
+                        self.frame.resize(width as usize * height as usize * 4, 0);
+                        let changed = vcodec.render_software(&mut self.frame, time);
+                        try!(screen.present(&self.frame, width, height, changed));
+                    },
+                }
+            }
+            let actions = match self.display {
+                ::Display::Gl{window: Some(ref window), ..} => input::poll(window, &self.bindings),
+                _ => match self.console {
+                    Some(ref mut console) => console.poll(&self.bindings),
+                    None => Vec::new(),
+                },
+            };
+            if !actions.is_empty() {
+                for action in actions {
+                    if !try!(self.handle(action, &mut song)) {
+                        return self.stop_recording();
+                    }
+                }
+                // The song or the clock may have changed under us
+                continue;
+            }
+            // A recording that can't keep up, or can't be written at
+            // all, is reported from here rather than from the driver
+            if self.recording.as_ref().map_or(false, |recording| recording.has_failed()) {
+                try!(self.stop_recording());
+                self.ao_driver.take_recording_overruns();
+            }
+            let dropped = self.ao_driver.take_recording_overruns();
+            if dropped != 0 {
+                eprintln!("qaraoke: Dropped {} samples from the recording", dropped);
+            }
+            // Changes to the queue show up in `next` when it is
+            // prepared again, below
+            let commands = self.control.as_ref().map_or(Vec::new(), |control| control.poll());
+            for command in commands {
+                match command.apply(&mut self.queue) {
+                    Ok(Some(reply)) => status!(self.terminal, "{}", reply),
+                    Ok(None) => (),
+                    Err(e) => eprintln!("qaraoke: {}", e),
+                }
+            }
+            let hwm = try!(song.source.demux.pump_until(((song_time + 1.) * 1e6) as u64));
+            if let Some(ref mut acodec) = song.source.audio {
+                acodec.do_needful()
+            }
+            if !song.source.demux.is_eof() {
+                // Get the next song ready while this one plays
+                self.prepare_next();
+                continue;
+            }
+
+            // Everything that is left of this song has been decoded,
+            // so have the next one ready to go the moment it ends
+            self.prepare_next();
+            let failed = match self.next {
+                Some(ref mut next) => next.prime(&self.display, 0.).err().map(|e| (next.entry.clone(), e)),
+                None => None,
+            };
+            if let Some((entry, e)) = failed {
+                eprintln!("qaraoke: {}: {}", entry.path.display(), e);
+                self.queue.remove(entry.id);
+                self.next = None;
+                self.prepare_next();
+            }
+            // Start the next song in time for the fade to end with
+            // this one
+            let lead = if self.next.is_some() { self.crossfade * song.entry.settings.tempo } else { 0. };
+            if (song_time + lead) * 1e6 >= hwm as f64 {
+                let next = match self.take_next() {
+                    Some(next) => next,
+                    None => return self.stop_recording(),
+                };
+                try!(self.hand_over(&mut song, next));
+            }
+        }
+    }
+
+    /// Carry out `action` on the current song. Returns false if it's
+    /// time to quit.
+    fn handle(&mut self, action: Action, song: &mut Prepared) -> Result<bool, Box<Error>> {
+        match action {
+            Action::Pause => {
+                self.paused = !self.paused;
+                if self.paused {
+                    try!(self.ao_driver.pause().map_err(queue_full));
+                } else {
+                    try!(self.ao_driver.resume().map_err(queue_full));
+                }
+                try!(self.ao_driver.commit().map_err(queue_full));
+            },
+            Action::Skip => {
+                let next = match self.take_next() {
+                    Some(next) => next,
+                    None => return Ok(false),
+                };
+                // The next singer shouldn't have to unpause
+                self.paused = false;
+                try!(self.ao_driver.resume().map_err(queue_full));
+                try!(self.hand_over(song, next));
+            },
+            Action::SeekBack => {
+                let time = self.ao_driver.timestamp() - SEEK_STEP;
+                try!(self.seek(song, time));
+            },
+            Action::SeekForward => {
+                let time = self.ao_driver.timestamp() + SEEK_STEP;
+                try!(self.seek(song, time));
+            },
+            Action::KeyUp | Action::KeyDown => {
+                let step = if action == Action::KeyUp { 1 } else { -1 };
+                let key = (song.entry.settings.key + step).max(-MAX_KEY).min(MAX_KEY);
+                song.entry.settings.key = key;
+                if let Some(ref mut acodec) = song.source.audio {
+                    acodec.set_key(key);
+                }
+                status!(self.terminal, "Key: {:+}", key);
+            },
+            Action::VolumeUp | Action::VolumeDown => {
+                let step = if action == Action::VolumeUp { VOLUME_STEP } else { -VOLUME_STEP };
+                self.volume = (self.volume + step).max(0.).min(MAX_VOLUME);
+                try!(self.ao_driver.set_volume(self.volume).map_err(queue_full));
+                status!(self.terminal, "Volume: {:.0}%", self.volume * 100.);
+            },
+            Action::Vocals => {
+                // Songs opened from now on start out the same way
+                let enabled = !self.codec_options.vocal_reduction;
+                self.codec_options.vocal_reduction = enabled;
+                for source in Some(&mut song.source).into_iter().chain(self.next.as_mut().map(|next| &mut next.source)) {
+                    if let Some(ref mut acodec) = source.audio {
+                        acodec.set_vocal_reduction(enabled);
+                    }
+                }
+                status!(self.terminal, "Vocal reduction: {}", if enabled { "on" } else { "off" });
+            },
+            Action::Fullscreen => if let ::Display::Gl{window: Some(ref window), ..} = self.display {
+                self.fullscreen = !self.fullscreen;
+                try!(::window_builder(self.fullscreen).rebuild_glium(window)
+                     .map_err(|_| "Unable to switch between fullscreen and windowed"));
+            },
+            Action::Quit => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    /// Jump to `time` seconds into the performance of `song`. The song
+    /// is opened afresh, so that nothing decoded from before the jump
+    /// is played, and the video picks up from its last keyframe
+    /// before `time`.
+    fn seek(&mut self, song: &mut Prepared, time: f64) -> Result<(), Box<Error>> {
+        let tempo = song.entry.settings.tempo;
+        let mut source = try!(self.open(&song.entry));
+        let resumed = try!(source.seek(time.max(0.) * tempo));
+        let mut reopened = Prepared{
+            entry: song.entry.clone(),
+            source: source,
+            stream: None,
+        };
+        try!(self.switch_to(&mut reopened, Some(resumed / tempo), 0.));
+        *song = reopened;
+        Ok(())
+    }
+
+    /// Fade from `song` into `next`, which takes its place
+    fn hand_over(&mut self, song: &mut Prepared, next: Prepared) -> Result<(), Box<Error>> {
+        // Demux enough of the old song to last out the fade
+        let until = (self.ao_driver.timestamp() + self.crossfade) * song.entry.settings.tempo;
+        let mut outgoing = replace(song, next);
+        try!(outgoing.source.demux.pump_until((until * 1e6) as u64));
+        let crossfade = self.crossfade;
+        try!(self.perform(song, None, crossfade));
+        self.fading = Some(outgoing.source);
+        Ok(())
+    }
+
+    /// Start a new performance of `song`: switch to it, as
+    /// `switch_to`, and record it if recording
+    fn perform(&mut self, song: &mut Prepared, start: Option<f64>, fade: f64) -> Result<(), Box<Error>> {
+        status!(self.terminal, "Now singing: {} ({})", song.entry.singer, song.entry.path.display());
+        // The recording starts along with the song
+        let recording = match self.recorder {
+            Some(ref recorder) => match recorder.start(&song.entry) {
+                Ok((recording, writer)) => {
+                    status!(self.terminal, "Recording to {}", recording.path().display());
+                    try!(self.ao_driver.record(Some(writer)).map_err(queue_full));
+                    Some(recording)
+                },
+                Err(e) => {
+                    eprintln!("qaraoke: Can't record {}: {}", song.entry.path.display(), e);
+                    try!(self.ao_driver.record(None).map_err(queue_full));
+                    None
+                },
+            },
+            None => None,
+        };
+        try!(self.switch_to(song, start, fade));
+
+        // Now that the driver has let go of it, the last song's
+        // recording can be closed
+        if let Some(recording) = replace(&mut self.recording, recording) {
+            finish_recording(recording, self.terminal);
+        }
+        Ok(())
+    }
+
+    /// Start playing `song`, priming it first if need be. The new
+    /// stream and the time counter change in a single commit, so the
+    /// old song plays right up until the new one starts, and then
+    /// fades out over `fade` seconds. The counter is set to `start`,
+    /// in seconds, or zeroed if that is None.
+    fn switch_to(&mut self, song: &mut Prepared, start: Option<f64>, fade: f64) -> Result<(), Box<Error>> {
+        let tempo = song.entry.settings.tempo;
+        try!(song.prime(&self.display, start.map_or(0., |start| start * tempo)));
+        let stream = song.stream.take().unwrap();
+        // A new video codec draws its first frame in full
+        self.frame.clear();
+        try!(self.ao_driver.crossfade_stream(stream, fade).map_err(|_| queue_full(())));
+        // A cut silences whatever was fading out
+        if fade == 0. {
+            self.fading = None;
+        }
+        match start {
+            Some(start) => try!(self.ao_driver.set_time_base(start).map_err(queue_full)),
+            None => try!(self.ao_driver.zero_time().map_err(queue_full)),
+        }
+        try!(self.ao_driver.commit().map_err(queue_full));
+
+        // Wait for the driver to synchronize. This takes at most one
+        // buffer period, which is well under a video frame.
+        while !self.ao_driver.all_commands_processed() {
+            // Do nothing
+        }
+        Ok(())
+    }
+
+    /// Stop recording, once the driver has played everything it has
+    fn stop_recording(&mut self) -> Result<(), Box<Error>> {
+        if let Some(recording) = self.recording.take() {
+            try!(self.ao_driver.record(None).map_err(queue_full));
+            try!(self.ao_driver.commit().map_err(queue_full));
+            while !self.ao_driver.all_commands_processed() {
+                // Do nothing
+            }
+            finish_recording(recording, self.terminal);
+        }
+        Ok(())
+    }
+
+    /// Open the song at the front of the queue, unless that has
+    /// already been done. Songs that can't be opened are reported
+    /// and dropped from the queue, and the one after is tried
+    /// instead.
+    fn prepare_next(&mut self) {
+        loop {
+            let entry = match self.queue.peek_next() {
+                // The queue may have been reordered, or the song's
+                // settings changed, since the last song was prepared
+                Some(entry) if self.next.as_ref().map_or(false, |next| next.entry == *entry) => return,
+                Some(entry) => entry.clone(),
+                None => {
+                    self.next = None;
+                    return;
+                },
+            };
+            match self.open(&entry) {
+                Ok(source) => {
+                    self.next = Some(Prepared{
+                        entry: entry,
+                        source: source,
+                        stream: None,
+                    });
+                    return;
+                },
+                Err(e) => {
+                    eprintln!("qaraoke: {}: {}", entry.path.display(), e);
+                    self.queue.remove(entry.id);
+                },
+            }
+        }
+    }
+
+    fn open(&self, entry: &queue::Entry) -> Result<Source, Box<Error>> {
+        let file = try!(fs::File::open(&entry.path));
+        let mut source = try!(KaraokeSource::from_stream(file, self.codec_options));
+        if let Some(ref mut acodec) = source.audio {
+            acodec.set_key(entry.settings.key);
+            acodec.set_tempo(entry.settings.tempo);
+            if let Some(target) = self.loudness_target {
+                match try!(analyze::Analysis::load(&entry.path)) {
+                    Some(analysis) => acodec.set_gain(analysis.gain(target)),
+                    None => status!(self.terminal, "{} has not been analyzed, so it won't be normalized",
+                                    entry.path.display()),
+                }
+            }
+        }
+        if let Some(ref mut vcodec) = source.video {
+            vcodec.set_tempo(entry.settings.tempo);
+        }
+        Ok(source)
+    }
+
+    /// Take the song at the front of the queue, opening it if that
+    /// hasn't been done yet
+    fn take_next(&mut self) -> Option<Prepared> {
+        self.prepare_next();
+        let next = self.next.take();
+        if next.is_some() {
+            self.queue.next();
+        }
+        next
+    }
+}
@@ -0,0 +1,365 @@
+/*!
+The song queue for a karaoke night.
+
+Every singer has their own list of songs, and singers take turns in a
+fixed rotation: whoever is at the front of the rotation and has a song
+waiting sings next, then moves to the back. Singers who have nothing
+queued keep their place, so adding a song later doesn't cost them
+their turn. New singers join at the back of the rotation.
+
+The methods for inspecting and rearranging the queue are there for the
+host, who reaches them through `control`; the player itself only
+takes songs off the front.
+*/
+
+use std::collections::VecDeque;
+use std::io::BufRead;
+use std::path::PathBuf;
+
+pub type EntryId = u64;
+
+/// How a song should be performed
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub struct Settings {
+    /// Transposition, in semitones
+    pub key: i8,
+    /// Speed relative to the original; 1.0 is unchanged
+    pub tempo: f64,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings{
+            key: 0,
+            tempo: 1.0,
+        }
+    }
+}
+
+#[derive(Clone,Debug,PartialEq)]
+pub struct Entry {
+    /// Unique within a queue, and never reused
+    pub id: EntryId,
+    pub path: PathBuf,
+    pub singer: String,
+    pub settings: Settings,
+}
+
+struct Singer {
+    name: String,
+    songs: VecDeque<Entry>,
+}
+
+#[derive(Default)]
+pub struct Queue {
+    /// The front singer has the next turn
+    rotation: VecDeque<Singer>,
+    next_id: EntryId,
+}
+
+impl Queue {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Add a song to the end of `singer`'s list
+    pub fn add<P: Into<PathBuf>>(&mut self, singer: &str, path: P, settings: Settings) -> EntryId {
+        let id = self.next_id;
+        self.next_id += 1;
+        let entry = Entry{
+            id: id,
+            path: path.into(),
+            singer: singer.to_owned(),
+            settings: settings,
+        };
+        if let Some(singer) = self.rotation.iter_mut().find(|s| s.name == singer) {
+            singer.songs.push_back(entry);
+            return id;
+        }
+        let mut songs = VecDeque::new();
+        songs.push_back(entry);
+        self.rotation.push_back(Singer{
+            name: singer.to_owned(),
+            songs: songs,
+        });
+        id
+    }
+
+    /// Add the songs listed in a queue file. Each line holds a
+    /// singer, a path, and optionally a key change in semitones and
+    /// a tempo factor, separated by tabs. Blank lines and lines
+    /// starting with '#' are ignored. Nothing is added unless the
+    /// whole file is valid.
+    pub fn load<R: BufRead>(&mut self, reader: R) -> Result<(), String> {
+        let mut songs = Vec::new();
+        for (lineno, line) in reader.lines().enumerate() {
+            let line = try!(line.map_err(|e| e.to_string()));
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields : Vec<&str> = line.split('\t').collect();
+            songs.push(try!(parse_song(&fields).map_err(|e| format!("line {}: {}", lineno + 1, e))));
+        }
+        for (singer, path, settings) in songs {
+            self.add(&singer, path, settings);
+        }
+        Ok(())
+    }
+
+    /// The position in the rotation of the singer whose turn it is
+    fn next_singer(&self) -> Option<usize> {
+        self.rotation.iter().position(|singer| !singer.songs.is_empty())
+    }
+
+    /// The song that `next` would return
+    pub fn peek_next(&self) -> Option<&Entry> {
+        self.next_singer().and_then(|i| self.rotation[i].songs.front())
+    }
+
+    /// Take the next song, and move its singer to the back of the
+    /// rotation
+    pub fn next(&mut self) -> Option<Entry> {
+        let i = match self.next_singer() {
+            Some(i) => i,
+            None => return None,
+        };
+        let mut singer = self.rotation.remove(i).unwrap();
+        let entry = singer.songs.pop_front();
+        self.rotation.push_back(singer);
+        entry
+    }
+
+    /// Pass over the singer whose turn it is, without taking any of
+    /// their songs. They move to the back of the rotation.
+    pub fn skip_turn(&mut self) {
+        if let Some(i) = self.next_singer() {
+            let singer = self.rotation.remove(i).unwrap();
+            self.rotation.push_back(singer);
+        }
+    }
+
+    /// Every queued song, in the order that they will be sung if
+    /// nothing changes
+    pub fn upcoming(&self) -> Vec<&Entry> {
+        // Simulate the rotation as (singer, songs taken) pairs
+        let mut rotation : VecDeque<(&Singer, usize)> = self.rotation.iter().map(|s| (s, 0)).collect();
+        let mut order = Vec::new();
+        while let Some(i) = rotation.iter().position(|&(s, taken)| taken < s.songs.len()) {
+            let (singer, taken) = rotation.remove(i).unwrap();
+            order.push(&singer.songs[taken]);
+            rotation.push_back((singer, taken + 1));
+        }
+        order
+    }
+
+    pub fn len(&self) -> usize {
+        self.rotation.iter().map(|s| s.songs.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.next_singer().is_none()
+    }
+
+    /// Singers in rotation order, starting with whoever is up next
+    pub fn singers(&self) -> Vec<&str> {
+        self.rotation.iter().map(|s| &s.name[..]).collect()
+    }
+
+    /// Find the singer and position of an entry
+    fn locate(&self, id: EntryId) -> Option<(usize, usize)> {
+        for (i, singer) in self.rotation.iter().enumerate() {
+            if let Some(j) = singer.songs.iter().position(|e| e.id == id) {
+                return Some((i, j));
+            }
+        }
+        None
+    }
+
+    pub fn set_settings(&mut self, id: EntryId, settings: Settings) -> bool {
+        match self.locate(id) {
+            Some((i, j)) => {
+                self.rotation[i].songs[j].settings = settings;
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Remove a song. The singer keeps their place in the rotation.
+    pub fn remove(&mut self, id: EntryId) -> Option<Entry> {
+        self.locate(id).and_then(|(i, j)| self.rotation[i].songs.remove(j))
+    }
+
+    /// Move a song to `position` within its singer's list, clamped to
+    /// the end of the list
+    pub fn move_entry(&mut self, id: EntryId, position: usize) -> bool {
+        match self.locate(id) {
+            Some((i, j)) => {
+                let songs = &mut self.rotation[i].songs;
+                let entry = songs.remove(j).unwrap();
+                let position = ::std::cmp::min(position, songs.len());
+                songs.insert(position, entry);
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Move a singer to `position` in the rotation, where 0 is the
+    /// front, clamped to the back of the rotation
+    pub fn move_singer(&mut self, name: &str, position: usize) -> bool {
+        match self.rotation.iter().position(|s| s.name == name) {
+            Some(i) => {
+                let singer = self.rotation.remove(i).unwrap();
+                let position = ::std::cmp::min(position, self.rotation.len());
+                self.rotation.insert(position, singer);
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Remove a singer and all of their songs
+    pub fn remove_singer(&mut self, name: &str) -> Vec<Entry> {
+        match self.rotation.iter().position(|s| s.name == name) {
+            Some(i) => self.rotation.remove(i).unwrap().songs.into_iter().collect(),
+            None => Vec::new(),
+        }
+    }
+}
+
+/// Parse a song as listed in a queue file: SINGER, PATH, and
+/// optionally KEY and TEMPO
+pub fn parse_song(fields: &[&str]) -> Result<(String, PathBuf, Settings), String> {
+    if fields.len() < 2 || fields.len() > 4 {
+        return Err("expected SINGER<tab>PATH[<tab>KEY[<tab>TEMPO]]".to_owned());
+    }
+    let mut settings = Settings::default();
+    if let Some(key) = fields.get(2) {
+        settings.key = try!(key.trim().parse().map_err(|_| format!("invalid key {:?}", key)));
+    }
+    if let Some(tempo) = fields.get(3) {
+        settings.tempo = try!(parse_tempo(tempo));
+    }
+    Ok((fields[0].to_owned(), PathBuf::from(fields[1]), settings))
+}
+
+pub fn parse_tempo(tempo: &str) -> Result<f64, String> {
+    match tempo.trim().parse() {
+        Ok(tempo) if tempo > 0. => Ok(tempo),
+        _ => Err(format!("invalid tempo {:?}", tempo)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn order(queue: &Queue) -> Vec<String> {
+        queue.upcoming().iter().map(|e| format!("{}:{}", e.singer, e.path.display())).collect()
+    }
+
+    fn drain(queue: &mut Queue) -> Vec<String> {
+        let mut songs = Vec::new();
+        while let Some(e) = queue.next() {
+            songs.push(format!("{}:{}", e.singer, e.path.display()));
+        }
+        songs
+    }
+
+    fn night() -> Queue {
+        let mut queue = Queue::new();
+        queue.add("alice", "a1", Settings::default());
+        queue.add("alice", "a2", Settings::default());
+        queue.add("alice", "a3", Settings::default());
+        queue.add("bob", "b1", Settings::default());
+        queue.add("carol", "c1", Settings::default());
+        queue.add("carol", "c2", Settings::default());
+        queue
+    }
+
+    #[test]
+    fn round_robin() {
+        let mut queue = night();
+        let expected = vec!["alice:a1", "bob:b1", "carol:c1", "alice:a2", "carol:c2", "alice:a3"];
+        assert_eq!(order(&queue), expected);
+        assert_eq!(queue.len(), 6);
+        assert_eq!(queue.peek_next().unwrap().path.to_str(), Some("a1"));
+        assert_eq!(queue.singers(), vec!["alice", "bob", "carol"]);
+        assert_eq!(drain(&mut queue), expected);
+        assert!(queue.is_empty());
+        assert_eq!(queue.len(), 0);
+    }
+
+    #[test]
+    fn singers_keep_their_place() {
+        let mut queue = night();
+        assert_eq!(queue.next().unwrap().singer, "alice");
+        // Bob is up next, but pulls his song, so carol goes first.
+        // Bob keeps his place, so a song he adds now is due before
+        // alice's next turn.
+        let b1 = queue.peek_next().unwrap().id;
+        queue.remove(b1);
+        assert_eq!(queue.next().unwrap().singer, "carol");
+        queue.add("bob", "b2", Settings::default());
+        queue.add("dave", "d1", Settings::default());
+        assert_eq!(order(&queue), vec!["bob:b2", "alice:a2", "carol:c2", "dave:d1", "alice:a3"]);
+    }
+
+    #[test]
+    fn reorder() {
+        let mut queue = night();
+        let a3 = queue.upcoming()[5].id;
+        assert!(queue.move_entry(a3, 0));
+        assert!(queue.move_singer("carol", 0));
+        assert_eq!(order(&queue), vec!["carol:c1", "alice:a3", "bob:b1", "carol:c2", "alice:a1", "alice:a2"]);
+        assert!(queue.move_singer("carol", 10));
+        assert_eq!(queue.singers(), vec!["alice", "bob", "carol"]);
+        assert!(!queue.move_entry(1000, 0));
+        assert!(!queue.move_singer("mallory", 0));
+    }
+
+    #[test]
+    fn remove_and_skip() {
+        let mut queue = night();
+        let b1 = queue.upcoming()[1].id;
+        assert_eq!(queue.remove(b1).unwrap().path.to_str(), Some("b1"));
+        assert_eq!(queue.remove(b1), None);
+        assert_eq!(queue.remove_singer("carol").len(), 2);
+        queue.add("bob", "b2", Settings::default());
+        assert_eq!(order(&queue), vec!["alice:a1", "bob:b2", "alice:a2", "alice:a3"]);
+
+        queue.skip_turn();
+        assert_eq!(order(&queue), vec!["bob:b2", "alice:a1", "alice:a2", "alice:a3"]);
+    }
+
+    #[test]
+    fn settings() {
+        let mut queue = Queue::new();
+        let id = queue.add("alice", "a1", Settings{key: -2, tempo: 1.0});
+        assert_eq!(queue.peek_next().unwrap().settings.key, -2);
+        assert!(queue.set_settings(id, Settings{key: 3, tempo: 0.9}));
+        assert_eq!(queue.next().unwrap().settings, Settings{key: 3, tempo: 0.9});
+        assert!(!queue.set_settings(id, Settings::default()));
+    }
+
+    #[test]
+    fn load() {
+        let mut queue = Queue::new();
+        let file = "# Friday\nalice\tsongs/a1.ogg\n\nbob\tsongs/b1.ogg\t-2\nalice\tsongs/a2.ogg\t1\t0.95\n";
+        queue.load(file.as_bytes()).unwrap();
+        let upcoming = queue.upcoming();
+        assert_eq!(upcoming.len(), 3);
+        assert_eq!(upcoming[1].settings, Settings{key: -2, tempo: 1.0});
+        assert_eq!(upcoming[2].settings, Settings{key: 1, tempo: 0.95});
+        assert_eq!(upcoming[2].path.to_str(), Some("songs/a2.ogg"));
+
+        assert!(queue.load("alice\n".as_bytes()).unwrap_err().starts_with("line 1:"));
+        assert!(queue.load("\nalice\ta\tup\n".as_bytes()).unwrap_err().starts_with("line 2:"));
+        assert!(queue.load("alice\ta\t0\t-1\n".as_bytes()).is_err());
+
+        // A bad line partway through adds nothing
+        assert!(queue.load("carol\tc1\ncarol\tc2\tup\n".as_bytes()).unwrap_err().starts_with("line 2:"));
+        assert_eq!(queue.len(), 3);
+    }
+}
@@ -0,0 +1,404 @@
+//! Recording performances. The audio driver copies everything it plays
+//! into a ring buffer, and a thread here drains that into a file, so
+//! the audio callback never waits on the disk.
+
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use byteorder::{BigEndian, LittleEndian, WriteBytesExt};
+use ogk::ogg;
+
+use queue;
+use rt;
+use types::{Sample, SAMPLE_RATE};
+use wav;
+
+/// Two seconds; the writer thread has that long to get to the disk
+/// before the recording starts dropping samples
+const BUFFER: usize = 96_000;
+/// How often the writer thread drains the buffer
+const PERIOD_MS: u64 = 100;
+
+pub const FORMATS: [&'static str; 2] = ["wav", "oggpcm"];
+
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub enum Format {
+    /// 16-bit PCM in a WAV file
+    Wav,
+    /// 16-bit OggPCM, tagged with the song and singer. This is
+    /// uncompressed, as there is no Vorbis or Opus encoder to hand,
+    /// and few players understand it, so it's mostly for archiving and
+    /// for converting with tools that do; `wav` plays anywhere.
+    OggPcm,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "wav" => Ok(Format::Wav),
+            "oggpcm" => Ok(Format::OggPcm),
+            _ => Err(format!("Unknown recording format {:?}", s)),
+        }
+    }
+}
+
+impl Format {
+    fn extension(&self) -> &'static str {
+        match *self {
+            Format::Wav => "wav",
+            // The extension for Ogg audio that isn't Vorbis
+            Format::OggPcm => "oga",
+        }
+    }
+}
+
+trait Encoder: Send {
+    fn consume(&mut self, samples: &[Sample]) -> io::Result<()>;
+    /// Called once the recording is over
+    fn finish(&mut self) -> io::Result<()>;
+}
+
+struct WavEncoder(Option<wav::WavWriter<io::BufWriter<fs::File>>>);
+
+impl Encoder for WavEncoder {
+    fn consume(&mut self, samples: &[Sample]) -> io::Result<()> {
+        match self.0 {
+            Some(ref mut writer) => writer.write_samples(samples),
+            None => Ok(()),
+        }
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        match self.0.take() {
+            Some(writer) => writer.finish().map(|_| ()),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Frames in each Ogg packet; 10ms
+const FRAMES_PER_PACKET: usize = 480;
+
+/// Writes a single OggPCM stream of 16-bit stereo, with a Vorbis-style
+/// comment header for the tags
+struct OggEncoder<W: Write> {
+    writer: W,
+    packer: ogg::PagePacker,
+    /// Frames written so far
+    position: u64,
+    /// Frames that don't make up a full packet yet
+    pending: Vec<Sample>,
+}
+
+impl <W: Write> OggEncoder<W> {
+    fn new(writer: W, serial: u32, tags: &[(&str, &str)]) -> io::Result<Self> {
+        let mut header = Vec::with_capacity(28);
+        header.extend_from_slice(b"PCM     ");
+        try!(header.write_u16::<BigEndian>(0)); // major version
+        try!(header.write_u16::<BigEndian>(0)); // minor version
+        try!(header.write_u32::<BigEndian>(2)); // 16-bit little endian
+        try!(header.write_u32::<BigEndian>(SAMPLE_RATE));
+        header.push(16); // significant bits
+        header.push(2); // channels
+        try!(header.write_u16::<BigEndian>(FRAMES_PER_PACKET as u16));
+        try!(header.write_u32::<BigEndian>(0)); // extra headers
+
+        let mut comments = Vec::new();
+        let vendor = b"qaraoke";
+        try!(comments.write_u32::<LittleEndian>(vendor.len() as u32));
+        comments.extend_from_slice(vendor);
+        try!(comments.write_u32::<LittleEndian>(tags.len() as u32));
+        for &(name, value) in tags {
+            let comment = format!("{}={}", name, value);
+            try!(comments.write_u32::<LittleEndian>(comment.len() as u32));
+            comments.extend_from_slice(comment.as_bytes());
+        }
+
+        // Each header goes on a page of its own
+        let mut packer = ogg::PagePacker::new(serial);
+        packer.add_packet(&ogg::Packet{content: header, timestamp: 0});
+        packer.emit();
+        packer.add_packet(&ogg::Packet{content: comments, timestamp: 0});
+        packer.emit();
+        let mut encoder = OggEncoder{
+            writer: writer,
+            packer: packer,
+            position: 0,
+            pending: Vec::with_capacity(FRAMES_PER_PACKET),
+        };
+        try!(encoder.write_pages());
+        Ok(encoder)
+    }
+
+    fn write_pages(&mut self) -> io::Result<()> {
+        while let Some(page) = self.packer.take_next() {
+            try!(page.write_to(&mut self.writer));
+        }
+        Ok(())
+    }
+
+    fn add_packet(&mut self) -> io::Result<()> {
+        let mut content = Vec::with_capacity(self.pending.len() * 4);
+        for frame in &self.pending {
+            for &sample in frame {
+                try!(content.write_i16::<LittleEndian>(wav::quantize(sample)));
+            }
+        }
+        self.position += self.pending.len() as u64;
+        self.pending.clear();
+        self.packer.add_packet(&ogg::Packet{content: content, timestamp: self.position});
+        self.write_pages()
+    }
+}
+
+impl <W: Write + Send> Encoder for OggEncoder<W> {
+    fn consume(&mut self, samples: &[Sample]) -> io::Result<()> {
+        for &sample in samples {
+            self.pending.push(sample);
+            if self.pending.len() == FRAMES_PER_PACKET {
+                try!(self.add_packet());
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        if !self.pending.is_empty() {
+            try!(self.add_packet());
+        }
+        if !self.packer.is_closed() {
+            self.packer.close();
+        }
+        try!(self.write_pages());
+        self.writer.flush()
+    }
+}
+
+/// The file name for a recording of `entry`, without the extension:
+/// the singer, then the name of the song
+pub fn file_name(entry: &queue::Entry) -> String {
+    let song = entry.path.file_stem().map_or("".into(), |stem| stem.to_string_lossy());
+    let name = if entry.singer.is_empty() {
+        song.into_owned()
+    } else {
+        format!("{} - {}", entry.singer, song)
+    };
+    name.chars()
+        .map(|c| if c == '/' || c == '\\' || c.is_control() { '_' } else { c })
+        .collect()
+}
+
+/// Create `name.extension` in `directory`, or if that is taken,
+/// `name (2).extension` and so on
+fn create_numbered(directory: &Path, name: &str, extension: &str) -> io::Result<(PathBuf, fs::File)> {
+    for n in 1.. {
+        let path = directory.join(if n == 1 {
+            format!("{}.{}", name, extension)
+        } else {
+            format!("{} ({}).{}", name, n, extension)
+        });
+        match fs::OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(file) => return Ok((path, file)),
+            Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    unreachable!()
+}
+
+/// Where recordings go, and what they're saved as
+#[derive(Clone,Debug)]
+pub struct Recorder {
+    pub directory: PathBuf,
+    pub format: Format,
+}
+
+impl Recorder {
+    /// Create a new file for a recording of `entry`, numbering it if
+    /// the singer has sung the song before. Returns the end of the
+    /// ring buffer for the audio driver to fill.
+    pub fn start(&self, entry: &queue::Entry) -> io::Result<(Recording, rt::ringbuffer::Writer<Sample>)> {
+        let (path, file) = try!(create_numbered(&self.directory, &file_name(entry), self.format.extension()));
+        let file = io::BufWriter::new(file);
+        let encoder = match self.format {
+            Format::Wav => Box::new(WavEncoder(Some(try!(wav::WavWriter::new(file))))) as Box<Encoder>,
+            Format::OggPcm => {
+                // The serial only has to be unique within the file
+                let serial = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.subsec_nanos()).unwrap_or(0);
+                let song = entry.path.file_stem().map_or("".into(), |stem| stem.to_string_lossy());
+                Box::new(try!(OggEncoder::new(file, serial, &[("TITLE", &*song), ("ARTIST", &*entry.singer)])))
+            },
+        };
+        Recording::spawn(path, encoder)
+    }
+}
+
+fn run(mut recording: rt::ringbuffer::Reader<Sample>, mut encoder: Box<Encoder>, running: Arc<AtomicBool>) -> io::Result<()> {
+    let mut buffer = Vec::with_capacity(BUFFER);
+    loop {
+        // Check before draining, so that nothing played before the
+        // recording was stopped is lost
+        let stopping = !running.load(Ordering::Acquire);
+        buffer.clear();
+        buffer.extend(recording.iter());
+        try!(encoder.consume(&buffer));
+        if stopping {
+            break;
+        }
+        thread::sleep(Duration::from_millis(PERIOD_MS));
+    }
+    encoder.finish()
+}
+
+/// A recording in progress. The audio driver must have stopped filling
+/// it before it is finished or dropped, or the end will be cut off.
+pub struct Recording {
+    path: PathBuf,
+    running: Arc<AtomicBool>,
+    /// Set by the thread if it gives up on an error
+    failed: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<io::Result<()>>>,
+}
+
+impl Recording {
+    /// Start a thread feeding `encoder` from a new ring buffer, and
+    /// return the writing end
+    fn spawn(path: PathBuf, encoder: Box<Encoder>) -> io::Result<(Recording, rt::ringbuffer::Writer<Sample>)> {
+        let (reader, writer) = rt::ringbuffer::new(BUFFER);
+        let running = Arc::new(AtomicBool::new(true));
+        let failed = Arc::new(AtomicBool::new(false));
+        let (thread_running, thread_failed) = (running.clone(), failed.clone());
+        let thread = try!(thread::Builder::new()
+                          .name("recorder".to_owned())
+                          .spawn(move || {
+                              let result = run(reader, encoder, thread_running);
+                              if result.is_err() {
+                                  thread_failed.store(true, Ordering::Release);
+                              }
+                              result
+                          }));
+        Ok((Recording{
+            path: path,
+            running: running,
+            failed: failed,
+            thread: Some(thread),
+        }, writer))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Whether the writer thread has stopped on an error. The recording
+    /// should then be stopped, and `finish` returns the error.
+    pub fn has_failed(&self) -> bool {
+        self.failed.load(Ordering::Acquire)
+    }
+
+    /// Write out what is left and close the file
+    pub fn finish(mut self) -> io::Result<()> {
+        self.stop()
+    }
+
+    fn stop(&mut self) -> io::Result<()> {
+        self.running.store(false, Ordering::Release);
+        match self.thread.take().map(|thread| thread.join()) {
+            Some(Ok(result)) => result,
+            Some(Err(_)) => Err(io::Error::new(io::ErrorKind::Other, "The recorder thread panicked")),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for Recording {
+    fn drop(&mut self) {
+        self.stop().ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(singer: &str, path: &str) -> queue::Entry {
+        let mut queue = queue::Queue::new();
+        queue.add(singer, path, Default::default());
+        queue.next().unwrap()
+    }
+
+    /// An encoder for a disk that is full
+    struct Full;
+
+    impl Encoder for Full {
+        fn consume(&mut self, _: &[Sample]) -> io::Result<()> {
+            Err(io::Error::new(io::ErrorKind::Other, "No space left on device"))
+        }
+
+        fn finish(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn failure() {
+        let (recording, _writer) = Recording::spawn(PathBuf::from("full.wav"), Box::new(Full)).unwrap();
+        for _ in 0..100 {
+            if recording.has_failed() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(recording.has_failed());
+        assert!(recording.finish().is_err());
+    }
+
+    #[test]
+    fn names() {
+        assert_eq!(file_name(&entry("Ann", "/songs/Some Song.ogk")), "Ann - Some Song");
+        assert_eq!(file_name(&entry("", "Some Song.ogk")), "Some Song");
+        assert_eq!(file_name(&entry("A/B", "x.ogk")), "A_B - x");
+    }
+
+    /// Split an Ogg stream into (flags, granule position, content) for
+    /// each page
+    fn pages(mut data: &[u8]) -> Vec<(u8, u64, Vec<u8>)> {
+        use byteorder::{ByteOrder, LittleEndian};
+        let mut pages = Vec::new();
+        while !data.is_empty() {
+            assert_eq!(&data[..4], b"OggS");
+            let segments = data[26] as usize;
+            let len : usize = data[27..27 + segments].iter().map(|&s| s as usize).sum();
+            let start = 27 + segments;
+            pages.push((data[5], LittleEndian::read_u64(&data[6..14]), data[start..start + len].to_vec()));
+            data = &data[start + len..];
+        }
+        pages
+    }
+
+    #[test]
+    fn ogg() {
+        let mut output = Vec::new();
+        {
+            let mut encoder = OggEncoder::new(&mut output, 1, &[("TITLE", "Song")]).unwrap();
+            encoder.consume(&vec![[0.5, -0.5]; 1000]).unwrap();
+            encoder.finish().unwrap();
+        }
+        let pages = pages(&output);
+        assert_eq!(&pages[0].2[..8], b"PCM     ");
+        assert!(pages[1].2.ends_with(b"TITLE=Song"));
+        let data : Vec<u8> = pages[2..].iter().flat_map(|page| page.2.iter().cloned()).collect();
+        assert_eq!(data.len(), 1000 * 4);
+        assert_eq!(&data[..4], &[0x00, 0x40, 0x00, 0xc0]);
+        let last = pages.last().unwrap();
+        assert!(last.0 & 4 != 0);
+        assert_eq!(pages.iter().map(|page| page.1).filter(|&g| g != !0).max(), Some(1000));
+    }
+}
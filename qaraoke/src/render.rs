@@ -0,0 +1,231 @@
+//! Offline rendering of a karaoke file to a YUV4MPEG2 video and a WAV
+//! file. Rather than following the audio hardware, playback is driven
+//! by a synthetic clock that advances one video frame at a time, so
+//! this needs neither a GPU nor a sound card, and produces the same
+//! output every time.
+
+use std::error::Error;
+use std::fs;
+use std::io::{self, Read, Write, Seek};
+
+use clap;
+use glium;
+
+use codec;
+use rt;
+use types::{self, SAMPLE_RATE};
+use wav;
+use KaraokeSource;
+
+/// Writes 4:2:0 YUV4MPEG2 frames from RGBA pixels
+pub struct Y4mWriter<W: Write> {
+    writer: W,
+    width: usize,
+    height: usize,
+    /// Scratch space for the converted frame
+    planes: Vec<u8>,
+}
+
+impl <W: Write> Y4mWriter<W> {
+    pub fn new(mut writer: W, width: u32, height: u32, fps: u32) -> io::Result<Self> {
+        try!(writeln!(writer, "YUV4MPEG2 W{} H{} F{}:1 Ip A1:1 C420jpeg", width, height, fps));
+        Ok(Y4mWriter{
+            writer: writer,
+            width: width as usize,
+            height: height as usize,
+            planes: Vec::new(),
+        })
+    }
+
+    /// Write a frame of RGBA pixels, row by row. Alpha is ignored.
+    pub fn write_frame(&mut self, rgba: &[u8]) -> io::Result<()> {
+        let (width, height) = (self.width, self.height);
+        assert!(rgba.len() >= width * height * 4);
+        let cwidth = (width + 1) / 2;
+        let cheight = (height + 1) / 2;
+
+        // BT.601, limited range
+        let luma_size = width * height;
+        let chroma_size = cwidth * cheight;
+        self.planes.resize(luma_size + chroma_size * 2, 0);
+        let (luma, chroma) = self.planes.split_at_mut(luma_size);
+        let (cb, cr) = chroma.split_at_mut(chroma_size);
+        for (y, px) in luma.iter_mut().zip(rgba.chunks(4)) {
+            let (r, g, b) = (px[0] as f32, px[1] as f32, px[2] as f32);
+            *y = (16. + (65.481 * r + 128.553 * g + 24.966 * b) / 255. + 0.5) as u8;
+        }
+        for cy in 0..cheight {
+            for cx in 0..cwidth {
+                // Average the (up to) 2x2 block of pixels
+                let (mut r, mut g, mut b, mut n) = (0., 0., 0., 0.);
+                for y in cy * 2..::std::cmp::min(cy * 2 + 2, height) {
+                    for x in cx * 2..::std::cmp::min(cx * 2 + 2, width) {
+                        let px = &rgba[(y * width + x) * 4..];
+                        r += px[0] as f32;
+                        g += px[1] as f32;
+                        b += px[2] as f32;
+                        n += 255.;
+                    }
+                }
+                let (r, g, b) = (r / n, g / n, b / n);
+                cb[cy * cwidth + cx] = (128. + (-37.797 * r - 74.203 * g + 112.0 * b) + 0.5) as u8;
+                cr[cy * cwidth + cx] = (128. + (112.0 * r - 93.786 * g - 18.214 * b) + 0.5) as u8;
+            }
+        }
+
+        try!(self.writer.write_all(b"FRAME\n"));
+        try!(self.writer.write_all(&self.planes));
+        Ok(())
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+/// Render `input` at `fps` frames per second. Rendering continues
+/// until both the demuxer and the audio have run dry.
+pub fn render<R, V, A>(input: R, video: V, audio: A, fps: u32, options: codec::Options) -> Result<(), Box<Error>>
+    where R: Read, V: Write, A: Write + Seek
+{
+    let mut source : KaraokeSource<R, glium::Frame> = try!(KaraokeSource::from_stream(input, options));
+    let mut vcodec = match source.video.take() {
+        Some(vcodec) => vcodec,
+        None => return Err(From::from("The file has no usable video stream")),
+    };
+
+    let (width, height) = vcodec.frame_size();
+    let mut frame = vec![0; width as usize * height as usize * 4];
+    let mut video = try!(Y4mWriter::new(video, width, height, fps));
+    let mut audio = try!(wav::WavWriter::new(audio));
+
+    // Only a frame's worth of audio is taken out at a time, so a
+    // second of buffer leaves plenty of room for the decoder to get
+    // ahead.
+    let (mut audio_rd, audio_wr) = rt::ringbuffer::new(SAMPLE_RATE as usize);
+    if let Some(ref mut acodec) = source.audio {
+        acodec.set_ringbuffer(audio_wr);
+    }
+    let mut samples : Vec<types::Sample> = Vec::with_capacity(SAMPLE_RATE as usize / fps as usize + 1);
+
+    let mut frame_no = 0u64;
+    loop {
+        let time = frame_no as f64 / fps as f64;
+        let hwm = try!(source.demux.pump_until(((time + 1.) * 1e6) as u64));
+        if let Some(ref mut acodec) = source.audio {
+            acodec.do_needful();
+        }
+
+        vcodec.render_software(&mut frame, time);
+        try!(video.write_frame(&frame));
+
+        // Count samples from the start so that rounding doesn't drift
+        let (rate, fps) = (SAMPLE_RATE as u64, fps as u64);
+        let wanted = ((frame_no + 1) * rate / fps - frame_no * rate / fps) as usize;
+        samples.clear();
+        samples.extend(audio_rd.iter().take(wanted));
+        let audio_done = samples.len() < wanted;
+        samples.resize(wanted, [0.0, 0.0]);
+        try!(audio.write_samples(&samples));
+
+        frame_no += 1;
+        if source.demux.is_eof()
+            && (audio_done || source.audio.is_none())
+            && frame_no as f64 / fps as f64 * 1e6 >= hwm as f64
+        {
+            break;
+        }
+    }
+
+    try!(try!(audio.finish()).flush());
+    try!(video.into_inner().flush());
+    Ok(())
+}
+
+fn validate_fps(value: String) -> Result<(), String> {
+    match value.parse::<u32>() {
+        Ok(fps) if fps > 0 => Ok(()),
+        _ => Err(format!("{:?} is not a valid frame rate", value)),
+    }
+}
+
+pub fn subcommand() -> clap::App<'static, 'static> {
+    use clap::{Arg, SubCommand};
+    SubCommand::with_name("render")
+        .about("Renders a song to a YUV4MPEG2 video and a WAV file, without a GPU or sound card")
+        .arg(Arg::with_name("INPUT")
+             .help("The song to render")
+             .required(true))
+        .arg(Arg::with_name("VIDEO")
+             .help("Where to write the video")
+             .required(true))
+        .arg(Arg::with_name("AUDIO")
+             .help("Where to write the audio")
+             .required(true))
+        .arg(Arg::with_name("fps")
+             .long("fps")
+             .value_name("FPS")
+             .help("Video frame rate")
+             .default_value("25")
+             .validator(validate_fps))
+}
+
+pub fn main(matches: &clap::ArgMatches, options: codec::Options) -> Result<(), Box<Error>> {
+    let fps = value_t_or_exit!(matches, "fps", u32);
+    let input_name = matches.value_of("INPUT").unwrap();
+    let input = match fs::File::open(input_name) {
+        Ok(input) => input,
+        Err(e) => return Err(From::from(format!("{}: {}", input_name, e))),
+    };
+    let video = io::BufWriter::new(try!(fs::File::create(matches.value_of("VIDEO").unwrap())));
+    let audio = io::BufWriter::new(try!(fs::File::create(matches.value_of("AUDIO").unwrap())));
+    render(input, video, audio, fps, options)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use byteorder::{ByteOrder, LittleEndian};
+    use super::*;
+
+    #[test]
+    fn y4m() {
+        // 3x3, with a red right-hand column and the rest white, so the
+        // chroma planes are 2x2 and the edge blocks are partial
+        let white = [255, 255, 255, 0];
+        let red = [255, 0, 0, 0];
+        let rgba : Vec<u8> = (0..9).flat_map(|i| if i % 3 == 2 { red } else { white }.to_vec()).collect();
+        let mut writer = Y4mWriter::new(Vec::new(), 3, 3, 25).unwrap();
+        writer.write_frame(&rgba).unwrap();
+        let output = writer.into_inner();
+
+        let header = b"YUV4MPEG2 W3 H3 F25:1 Ip A1:1 C420jpeg\nFRAME\n";
+        assert_eq!(&output[..header.len()], &header[..]);
+        let planes = &output[header.len()..];
+        assert_eq!(planes.len(), 9 + 4 + 4);
+        assert_eq!(&planes[..9], &[235, 235, 81, 235, 235, 81, 235, 235, 81]);
+        assert_eq!(&planes[9..13], &[128, 90, 128, 90]);
+        assert_eq!(&planes[13..], &[128, 240, 128, 240]);
+    }
+
+    #[test]
+    fn render_song() {
+        // Two minutes of video and no audio, at a frame rate that
+        // doesn't divide the sample rate
+        let fps = 7;
+        let mut video = Vec::new();
+        let mut audio = Cursor::new(Vec::new());
+        render(Cursor::new(::tests::test_file(0)), &mut video, &mut audio, fps, Default::default()).unwrap();
+
+        let header = format!("YUV4MPEG2 W300 H216 F{}:1 Ip A1:1 C420jpeg\n", fps);
+        assert!(video.starts_with(header.as_bytes()));
+        let frame_size = 6 + 300 * 216 + 150 * 108 * 2;
+        assert_eq!((video.len() - header.len()) % frame_size, 0);
+        assert_eq!((video.len() - header.len()) / frame_size, 120 * fps as usize);
+
+        let audio = audio.into_inner();
+        let samples = (audio.len() - 44) / 4;
+        assert_eq!(samples, 120 * SAMPLE_RATE as usize);
+        assert_eq!(LittleEndian::read_u32(&audio[40..44]) as usize, samples * 4);
+    }
+}
@@ -180,6 +180,9 @@ pub struct View<T, VT: view_type::ViewType> {
     _phantom: marker::PhantomData<VT>,
 }
 
+// Each end of the ringbuffer is meant to live on its own thread
+unsafe impl<T: Send, VT: view_type::ViewType> Send for View<T, VT> {}
+
 impl <T, VT: view_type::ViewType> View<T, VT> {
     fn check_connected(&self) -> Result<(), Error> {
         if unsafe{&*self.buf}.ref_cnt.load(Ordering::Acquire) > 1 {
@@ -315,6 +318,20 @@ impl<'a, T: 'a> Iterator for ReadIter<'a, T> {
 pub struct WriteIter<'a, T: 'a> {
     iter: PtrIter<'a, T>,
 }
+
+impl<'a, T: 'a> WriteIter<'a, T> {
+    /// Like `Writer::push`, but without synchronizing. Returns item
+    /// if there is no space.
+    pub fn push(&mut self, item: T) -> Result<(), T> {
+        match self.iter.next() {
+            Some(v) => {
+                unsafe{ptr::write(v, item)};
+                Ok(())
+            },
+            None => Err(item),
+        }
+    }
+}
     
 impl<'a, T: 'a> iter::Extend<T> for WriteIter<'a, T> {
     fn extend<I>(&mut self, input: I)
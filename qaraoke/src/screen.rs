@@ -0,0 +1,259 @@
+//! Video output without OpenGL. Codecs draw frames on the CPU with
+//! `VideoCodec::render_software`, and a `Screen` puts them in front of
+//! the audience, scaled up to fill as much of it as it can without
+//! distorting them, or draws them on the terminal.
+
+use std::error::Error;
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use cdg_renderer::Rectangle;
+use cdg_renderer::terminal::{Mode, TerminalRenderer};
+
+/// Where the software path draws when OpenGL is unavailable
+pub const DEFAULT_FRAMEBUFFER: &'static str = "/dev/fb0";
+
+/// Frames are presented no faster than this, which stands in for the
+/// vsync that paces the OpenGL path
+const FRAME_RATE: u32 = 30;
+
+pub trait Screen {
+    /// Show a frame of `width` by `height` RGBA pixels, row by row.
+    /// `changed` is the region, in pixels, that differs from the last
+    /// frame presented, as returned by `render_software`. This blocks
+    /// until it's time for the next frame.
+    fn present(&mut self, rgba: &[u8], width: u32, height: u32, changed: Option<Rectangle<u16>>) -> io::Result<()>;
+}
+
+/// Wait until `next_frame`, and then set it to the time of the frame
+/// after
+fn pace(next_frame: &mut Instant) {
+    let now = Instant::now();
+    if *next_frame > now {
+        thread::sleep(*next_frame - now);
+    } else {
+        *next_frame = now;
+    }
+    *next_frame += Duration::new(0, 1_000_000_000 / FRAME_RATE);
+}
+
+/// The largest rectangle with the aspect ratio of `width` by `height`
+/// that fits in `screen_width` by `screen_height`, centred, as
+/// (left, top, width, height)
+pub fn letterbox(width: u32, height: u32, screen_width: u32, screen_height: u32) -> (u32, u32, u32, u32) {
+    if width == 0 || height == 0 {
+        return (0, 0, 0, 0);
+    }
+    let (w, h) = if screen_width as u64 * height as u64 <= screen_height as u64 * width as u64 {
+        (screen_width, (screen_width as u64 * height as u64 / width as u64) as u32)
+    } else {
+        ((screen_height as u64 * width as u64 / height as u64) as u32, screen_height)
+    };
+    ((screen_width - w) / 2, (screen_height - h) / 2, w, h)
+}
+
+/// How a framebuffer lays out its pixels
+#[derive(Copy,Clone,Debug,PartialEq)]
+enum PixelFormat {
+    /// 32 bits per pixel: blue, green, red and an unused byte
+    Xrgb8888,
+    /// 16 bits per pixel, little endian
+    Rgb565,
+}
+
+impl PixelFormat {
+    fn bytes(&self) -> usize {
+        match *self {
+            PixelFormat::Xrgb8888 => 4,
+            PixelFormat::Rgb565 => 2,
+        }
+    }
+
+    fn write(&self, rgba: &[u8], out: &mut [u8]) {
+        match *self {
+            PixelFormat::Xrgb8888 => {
+                out[0] = rgba[2];
+                out[1] = rgba[1];
+                out[2] = rgba[0];
+                out[3] = 0xff;
+            },
+            PixelFormat::Rgb565 => {
+                let pixel = (rgba[0] as u16 >> 3) << 11 | (rgba[1] as u16 >> 2) << 5 | rgba[2] as u16 >> 3;
+                out[0] = pixel as u8;
+                out[1] = (pixel >> 8) as u8;
+            },
+        }
+    }
+}
+
+/// Scale `rgba` up to the screen in `out`, nearest neighbour, with
+/// black bars around it
+fn scale(rgba: &[u8], width: u32, height: u32,
+         out: &mut [u8], screen_width: u32, screen_height: u32, stride: usize, format: PixelFormat) {
+    let (left, top, w, h) = letterbox(width, height, screen_width, screen_height);
+    let bytes = format.bytes();
+    for y in 0..screen_height {
+        let row = &mut out[y as usize * stride..][..screen_width as usize * bytes];
+        if y < top || y >= top + h {
+            for b in row.iter_mut() {
+                *b = 0;
+            }
+            continue;
+        }
+        let src_y = ((y - top) as u64 * height as u64 / h as u64) as usize;
+        let src_row = &rgba[src_y * width as usize * 4..];
+        for (x, px) in row.chunks_mut(bytes).enumerate() {
+            let x = x as u32;
+            if x < left || x >= left + w {
+                for b in px.iter_mut() {
+                    *b = 0;
+                }
+            } else {
+                let src_x = ((x - left) as u64 * width as u64 / w as u64) as usize;
+                format.write(&src_row[src_x * 4..], px);
+            }
+        }
+    }
+}
+
+/// A Linux framebuffer device, such as /dev/fb0. Its geometry comes
+/// from sysfs; 32-bit pixels are taken to be XRGB, as they are on
+/// every driver worth mentioning.
+pub struct Framebuffer {
+    device: fs::File,
+    width: u32,
+    height: u32,
+    stride: usize,
+    format: PixelFormat,
+    /// Scratch space for the scaled frame
+    pixels: Vec<u8>,
+    next_frame: Instant,
+}
+
+fn read_sysfs(name: &str, attribute: &str) -> io::Result<String> {
+    let mut text = String::new();
+    try!(try!(fs::File::open(Path::new("/sys/class/graphics").join(name).join(attribute))).read_to_string(&mut text));
+    Ok(text.trim().to_owned())
+}
+
+impl Framebuffer {
+    pub fn open(path: &Path) -> Result<Framebuffer, Box<Error>> {
+        let device = try!(fs::OpenOptions::new().write(true).open(path));
+        let name = match try!(fs::canonicalize(path)).file_name() {
+            Some(name) => name.to_string_lossy().into_owned(),
+            None => return Err(From::from("Not a framebuffer device")),
+        };
+        let size = try!(read_sysfs(&name, "virtual_size"));
+        let mut size = size.split(',').map(|n| n.parse::<u32>());
+        let (width, height) = match (size.next(), size.next()) {
+            (Some(Ok(width)), Some(Ok(height))) => (width, height),
+            _ => return Err(From::from("Unable to read the framebuffer size")),
+        };
+        let format = match &*try!(read_sysfs(&name, "bits_per_pixel")) {
+            "32" => PixelFormat::Xrgb8888,
+            "16" => PixelFormat::Rgb565,
+            bits => return Err(From::from(format!("Unsupported framebuffer depth of {} bits", bits))),
+        };
+        let stride = match try!(read_sysfs(&name, "stride")).parse::<usize>() {
+            Ok(stride) => stride,
+            Err(_) => width as usize * format.bytes(),
+        };
+        Ok(Framebuffer{
+            device: device,
+            width: width,
+            height: height,
+            stride: stride,
+            format: format,
+            pixels: vec![0; stride * height as usize],
+            next_frame: Instant::now(),
+        })
+    }
+}
+
+impl Screen for Framebuffer {
+    fn present(&mut self, rgba: &[u8], width: u32, height: u32, changed: Option<Rectangle<u16>>) -> io::Result<()> {
+        pace(&mut self.next_frame);
+        // Unchanged frames aren't drawn again
+        if changed.is_none() {
+            return Ok(());
+        }
+        scale(rgba, width, height, &mut self.pixels, self.width, self.height, self.stride, self.format);
+        try!(self.device.seek(SeekFrom::Start(0)));
+        self.device.write_all(&self.pixels)
+    }
+}
+
+/// Half blocks or sixels on standard output, for headless machines
+/// and for watching over ssh. Only the part of each frame that has
+/// changed is drawn again.
+pub struct Terminal {
+    renderer: TerminalRenderer,
+    next_frame: Instant,
+}
+
+impl Terminal {
+    pub fn new(mode: Mode) -> Terminal {
+        let mut renderer = TerminalRenderer::new(mode);
+        // At full size, half blocks need a terminal 300 columns wide
+        if mode == Mode::HalfBlock {
+            renderer.set_scale(2);
+        }
+        Terminal{
+            renderer: renderer,
+            next_frame: Instant::now(),
+        }
+    }
+}
+
+impl Screen for Terminal {
+    fn present(&mut self, rgba: &[u8], width: u32, height: u32, changed: Option<Rectangle<u16>>) -> io::Result<()> {
+        pace(&mut self.next_frame);
+        let region = match changed {
+            Some(region) => region,
+            None => return Ok(()),
+        };
+        let stdout = io::stdout();
+        let mut out = stdout.lock();
+        self.renderer.draw(rgba, width as usize, height as usize, region, &mut out)
+    }
+}
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        self.renderer.finish(&mut io::stdout()).ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn letterboxing() {
+        assert_eq!(letterbox(300, 216, 600, 432), (0, 0, 600, 432));
+        // Bars at the sides
+        assert_eq!(letterbox(300, 216, 1920, 1080), (210, 0, 1500, 1080));
+        // Bars at the top and bottom
+        assert_eq!(letterbox(300, 216, 300, 400), (0, 92, 300, 216));
+    }
+
+    #[test]
+    fn scaling() {
+        let rgba = [255, 0, 0, 255, 0, 0, 255, 255];
+        let mut out = vec![1; 4 * 4 * 4];
+        scale(&rgba, 2, 1, &mut out, 4, 4, 16, PixelFormat::Xrgb8888);
+        let red = [0, 0, 255, 255];
+        let blue = [255, 0, 0, 255];
+        assert!(out[..16].iter().all(|&b| b == 0));
+        assert_eq!(&out[16..32], &[red, red, blue, blue].concat()[..]);
+        assert_eq!(&out[32..48], &out[16..32]);
+        assert!(out[48..].iter().all(|&b| b == 0));
+
+        let mut out = vec![0; 2 * 2];
+        scale(&rgba, 2, 1, &mut out, 2, 1, 4, PixelFormat::Rgb565);
+        assert_eq!(out, vec![0x00, 0xf8, 0x1f, 0x00]);
+    }
+}
@@ -0,0 +1,170 @@
+//! Reader and writer for 48kHz WAV files
+
+use std::io::{self, Read, Write, Seek, SeekFrom};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use types::{self, SAMPLE_RATE};
+
+const CHANNELS: u16 = 2;
+const BYTES_PER_FRAME: u32 = CHANNELS as u32 * 2;
+
+/// Convert a sample to 16 bits, clipping it if need be
+pub fn quantize(sample: f32) -> i16 {
+    (sample.max(-1.).min(1.) * 32767.).round() as i16
+}
+
+/// Writes 16-bit PCM. The sizes in the header are only filled in by
+/// `finish`; until then, they claim the file is empty.
+pub struct WavWriter<W: Write + Seek> {
+    writer: W,
+    frames: u32,
+}
+
+impl <W: Write + Seek> WavWriter<W> {
+    pub fn new(mut writer: W) -> io::Result<Self> {
+        try!(writer.write_all(b"RIFF"));
+        try!(writer.write_u32::<LittleEndian>(36));
+        try!(writer.write_all(b"WAVEfmt "));
+        try!(writer.write_u32::<LittleEndian>(16));
+        try!(writer.write_u16::<LittleEndian>(1)); // PCM
+        try!(writer.write_u16::<LittleEndian>(CHANNELS));
+        try!(writer.write_u32::<LittleEndian>(SAMPLE_RATE));
+        try!(writer.write_u32::<LittleEndian>(SAMPLE_RATE * BYTES_PER_FRAME));
+        try!(writer.write_u16::<LittleEndian>(BYTES_PER_FRAME as u16));
+        try!(writer.write_u16::<LittleEndian>(16));
+        try!(writer.write_all(b"data"));
+        try!(writer.write_u32::<LittleEndian>(0));
+        Ok(WavWriter{
+            writer: writer,
+            frames: 0,
+        })
+    }
+
+    pub fn write_samples(&mut self, samples: &[types::Sample]) -> io::Result<()> {
+        for frame in samples {
+            for &sample in frame {
+                try!(self.writer.write_i16::<LittleEndian>(quantize(sample)));
+            }
+        }
+        self.frames += samples.len() as u32;
+        Ok(())
+    }
+
+    /// Fill in the header and return the underlying writer
+    pub fn finish(mut self) -> io::Result<W> {
+        let data_size = self.frames * BYTES_PER_FRAME;
+        try!(self.writer.seek(SeekFrom::Start(4)));
+        try!(self.writer.write_u32::<LittleEndian>(36 + data_size));
+        try!(self.writer.seek(SeekFrom::Start(40)));
+        try!(self.writer.write_u32::<LittleEndian>(data_size));
+        try!(self.writer.seek(SeekFrom::End(0)));
+        try!(self.writer.flush());
+        Ok(self.writer)
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Reads 16-bit PCM, mono or stereo. Mono files come out on the left
+/// channel only.
+pub struct WavReader<R: Read> {
+    reader: R,
+    channels: u16,
+    /// Bytes left in the data chunk
+    remaining: u32,
+}
+
+impl <R: Read> WavReader<R> {
+    pub fn new(mut reader: R) -> io::Result<Self> {
+        let mut tag = [0; 4];
+        try!(reader.read_exact(&mut tag));
+        if &tag != b"RIFF" {
+            return Err(invalid("Not a WAV file"));
+        }
+        try!(reader.read_u32::<LittleEndian>());
+        try!(reader.read_exact(&mut tag));
+        if &tag != b"WAVE" {
+            return Err(invalid("Not a WAV file"));
+        }
+
+        let mut channels = None;
+        loop {
+            try!(reader.read_exact(&mut tag));
+            let size = try!(reader.read_u32::<LittleEndian>());
+            match &tag {
+                b"fmt " => {
+                    let format = try!(reader.read_u16::<LittleEndian>());
+                    let nchannels = try!(reader.read_u16::<LittleEndian>());
+                    let rate = try!(reader.read_u32::<LittleEndian>());
+                    try!(reader.read_u32::<LittleEndian>());
+                    try!(reader.read_u16::<LittleEndian>());
+                    let bits = try!(reader.read_u16::<LittleEndian>());
+                    if format != 1 || bits != 16 || rate != SAMPLE_RATE || nchannels < 1 || nchannels > 2 {
+                        return Err(invalid("Only 48kHz 16-bit PCM WAV files, in mono or stereo, are supported"));
+                    }
+                    channels = Some(nchannels);
+                    try!(io::copy(&mut (&mut reader).take((size as u64).saturating_sub(16)), &mut io::sink()));
+                },
+                b"data" => match channels {
+                    Some(channels) => return Ok(WavReader{
+                        reader: reader,
+                        channels: channels,
+                        remaining: size,
+                    }),
+                    None => return Err(invalid("WAV data before format")),
+                },
+                _ => {
+                    // Chunks are padded to an even length
+                    try!(io::copy(&mut (&mut reader).take(size as u64 + size as u64 % 2), &mut io::sink()));
+                },
+            }
+        }
+    }
+
+    /// Fill `samples` from the file, and return how many were read.
+    /// This is only short at the end of the file.
+    pub fn read_samples(&mut self, samples: &mut [types::Sample]) -> io::Result<usize> {
+        let frame_size = self.channels as u32 * 2;
+        let mut read = 0;
+        for frame in samples.iter_mut() {
+            if self.remaining < frame_size {
+                break;
+            }
+            let left = try!(self.reader.read_i16::<LittleEndian>()) as f32 / 32768.;
+            let right = if self.channels == 2 {
+                try!(self.reader.read_i16::<LittleEndian>()) as f32 / 32768.
+            } else {
+                0.
+            };
+            *frame = [left, right];
+            self.remaining -= frame_size;
+            read += 1;
+        }
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn round_trip() {
+        let samples = [[0.5, -0.5], [0.25, 0.], [-1., 1.]];
+        let mut writer = WavWriter::new(Cursor::new(Vec::new())).unwrap();
+        writer.write_samples(&samples).unwrap();
+        let file = writer.finish().unwrap().into_inner();
+
+        let mut reader = WavReader::new(&file[..]).unwrap();
+        let mut read = [[0.; 2]; 4];
+        assert_eq!(reader.read_samples(&mut read).unwrap(), 3);
+        for (a, b) in read.iter().zip(&samples) {
+            assert!((a[0] - b[0]).abs() < 1e-4 && (a[1] - b[1]).abs() < 1e-4);
+        }
+        assert_eq!(reader.read_samples(&mut read).unwrap(), 0);
+        assert!(WavReader::new(&b"RIFX"[..]).is_err());
+    }
+}
@@ -83,6 +83,13 @@ impl<I: SoxrFormat, O: SoxrFormat> SoxrBuilder<I,O> {
         }
     }
 
+    /// For variable-rate resampling, the largest ratio of input rate
+    /// to output rate that will be passed to `change_rate`
+    pub fn with_max_ratio(mut self, ratio: f64) -> Self {
+        self.rate = ratio;
+        self
+    }
+
     pub fn with_scale(mut self, scale: f64) -> Self {
         self.io_spec.scale = scale;
         self